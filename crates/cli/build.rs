@@ -0,0 +1,61 @@
+use std::{env, fs, path::Path, process::Command};
+
+// Records which rnix-parser the tester is built against, and which commit the tester itself is built from, since
+// results cached by `compare --incremental` are only good for those
+fn main() {
+    let root = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("../..");
+    let lock = root.join("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock.display());
+    println!(
+        "cargo:rerun-if-changed={}",
+        root.join(".git/HEAD").display()
+    );
+    println!(
+        "cargo:rerun-if-changed={}",
+        root.join(".git/index").display()
+    );
+
+    if let Some(version) = fs::read_to_string(&lock)
+        .ok()
+        .and_then(|lock| rnix_version(&lock))
+    {
+        println!("cargo:rustc-env=RNIX_VERSION={version}");
+    }
+
+    if let Some(commit) = git_commit(&root) {
+        println!("cargo:rustc-env=TESTER_COMMIT={commit}");
+    }
+}
+
+/// The commit checked out in the repo at `root`, if it's a git repo
+fn git_commit(root: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The version of the rnix package in `lock`, along with its source, which for a git dependency has the commit
+fn rnix_version(lock: &str) -> Option<String> {
+    let package = lock
+        .split("[[package]]")
+        .find(|package| package.contains("\nname = \"rnix\"\n"))?;
+    let field = |name: &str| {
+        package.lines().find_map(|line| {
+            line.strip_prefix(name)?
+                .strip_prefix(" = ")
+                .map(|value| value.trim_matches('"'))
+        })
+    };
+    Some(format!(
+        "{} {}",
+        field("version")?,
+        field("source").unwrap_or("")
+    ))
+}
@@ -0,0 +1,137 @@
+//! The parsers which can be compared. Each turns Nix into the same normalized AST, so that any two of them can be
+//! compared with each other.
+
+use std::{error::Error, sync::Arc};
+
+use normal_ast::NormalNixExpr;
+use rnix_normalize::{normalize_nix_expr, NixVersionProfile, Passes, PathContext};
+
+use crate::{deser_json, get_ref_impl_json, parse_rnix, read_source, CompareError, NixSource};
+
+/// A parser, along with whatever it takes to bring its output into the reference impl's shape
+pub trait ParserBackend: Send + Sync {
+    /// What the backend is called in results, e.g. `rnix-parser`
+    fn name(&self) -> &str;
+
+    /// Parse `source`, resolving paths in it against `paths`. Rejecting the source is an error, as is anything else
+    /// going wrong along the way, which should then be one of the errors of a [`crate::Phase`].
+    fn parse(
+        &self,
+        source: &NixSource,
+        paths: &PathContext,
+    ) -> Result<NormalNixExpr, Box<dyn Error>>;
+}
+
+impl<T: ParserBackend + ?Sized> ParserBackend for Arc<T> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn parse(
+        &self,
+        source: &NixSource,
+        paths: &PathContext,
+    ) -> Result<NormalNixExpr, Box<dyn Error>> {
+        (**self).parse(source, paths)
+    }
+}
+
+/// Parses `source` into the reference impl's JSON, resolving paths in it against the given context
+pub type ParseFn =
+    Box<dyn Fn(&NixSource, &PathContext) -> Result<String, Box<dyn Error>> + Send + Sync>;
+
+/// The reference impl, through ref-impl-parser
+pub struct ReferenceImplBackend;
+
+impl ParserBackend for ReferenceImplBackend {
+    fn name(&self) -> &str {
+        "reference impl"
+    }
+
+    fn parse(
+        &self,
+        source: &NixSource,
+        paths: &PathContext,
+    ) -> Result<NormalNixExpr, Box<dyn Error>> {
+        Ok(read_json(self.name(), &get_ref_impl_json(source, paths)?)?)
+    }
+}
+
+/// rnix-parser, normalized with the given passes for the given version of Nix
+pub struct RNixBackend {
+    name: String,
+    passes: Passes,
+    profile: NixVersionProfile,
+}
+
+impl RNixBackend {
+    pub fn new(passes: Passes, profile: NixVersionProfile) -> Self {
+        RNixBackend {
+            name: "rnix-parser".to_string(),
+            passes,
+            profile,
+        }
+    }
+
+    /// Call the backend something else, e.g. to tell apart two which are normalized differently
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+}
+
+impl Default for RNixBackend {
+    fn default() -> Self {
+        RNixBackend::new(Passes::default(), NixVersionProfile::default())
+    }
+}
+
+impl ParserBackend for RNixBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn parse(
+        &self,
+        source: &NixSource,
+        paths: &PathContext,
+    ) -> Result<NormalNixExpr, Box<dyn Error>> {
+        let (input, file) = read_source(source, paths)?;
+        Ok(normalize_nix_expr(
+            parse_rnix(&input)?,
+            &input,
+            file.as_deref(),
+            paths,
+            &self.passes,
+            self.profile,
+        )?)
+    }
+}
+
+/// Anything else which produces the reference impl's JSON, e.g. the reference impl built against another version of Nix
+pub struct CustomBackend {
+    pub name: String,
+    pub parse: ParseFn,
+}
+
+impl ParserBackend for CustomBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn parse(
+        &self,
+        source: &NixSource,
+        paths: &PathContext,
+    ) -> Result<NormalNixExpr, Box<dyn Error>> {
+        Ok(read_json(&self.name, &(self.parse)(source, paths)?)?)
+    }
+}
+
+/// Read back the JSON that `backend` produced
+fn read_json(backend: &str, json: &str) -> Result<NormalNixExpr, CompareError> {
+    deser_json(json).map_err(|error| CompareError {
+        backend: backend.to_string(),
+        error,
+    })
+}
@@ -0,0 +1,142 @@
+//! Results of earlier runs of `compare`, for `compare --incremental`
+
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use cli::NixSource;
+use rnix_normalize::{NixVersionProfile, Passes, PathContext, NORMALIZATION_VERSION};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{AppError, Outcome};
+
+/// Everything besides a file itself which its result depends on. Cached results are only used by a run with the same
+/// versions.
+#[derive(PartialEq, Eq, Deserialize, Serialize)]
+pub struct Versions {
+    /// The tester's own version, and the commit it was built from if it was built from a git checkout
+    tester: String,
+    /// rnix-parser's version, and the commit it was built from
+    rnix: String,
+    /// The version of Nix that the reference impl is built against
+    nix: String,
+    /// What `--nix-version` rnix-parser's AST is normalized for
+    nix_version_profile: String,
+    /// See [`NORMALIZATION_VERSION`]
+    normalization: u32,
+    /// The rest of what `compare` was told which could change a result, i.e., the passes, the path context, the
+    /// backends, and whether errors are compared. An external backend is only known by its command, so changing the
+    /// program behind the same command doesn't start the cache over.
+    options: String,
+}
+
+impl Versions {
+    pub fn new(
+        passes: &Passes,
+        profile: NixVersionProfile,
+        paths: &PathContext,
+        backends: &[&str],
+        compare_errors: bool,
+    ) -> Result<Self, AppError> {
+        let rnix = option_env!("RNIX_VERSION").ok_or_else(|| {
+            AppError::UsageError(
+                "Can't tell which rnix-parser this was built against, so can't cache results"
+                    .to_string(),
+            )
+        })?;
+
+        let tester = match option_env!("TESTER_COMMIT") {
+            Some(commit) => format!("{} {commit}", env!("CARGO_PKG_VERSION")),
+            None => env!("CARGO_PKG_VERSION").to_string(),
+        };
+
+        Ok(Versions {
+            tester,
+            rnix: rnix.to_string(),
+            nix: ref_impl_parser::nix_version(),
+            nix_version_profile: profile.to_string(),
+            normalization: NORMALIZATION_VERSION,
+            options: format!("{passes:?} {paths:?} {backends:?} {compare_errors}"),
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Cache {
+    versions: Versions,
+    /// Keyed by [`Cache::key`], with an outcome for each pair of backends
+    results: HashMap<String, Vec<Outcome>>,
+}
+
+impl Cache {
+    /// Where the cache is kept if `--incremental` isn't given a file
+    pub fn default_file() -> Result<PathBuf, AppError> {
+        let cache_dir = match env::var("XDG_CACHE_HOME") {
+            Ok(cache_dir) => PathBuf::from(cache_dir),
+            Err(_) => PathBuf::from(env::var("HOME").map_err(|_| {
+                AppError::UsageError(
+                    "Neither XDG_CACHE_HOME nor HOME is set. Pass a file to --incremental instead."
+                        .to_string(),
+                )
+            })?)
+            .join(".cache"),
+        };
+        Ok(cache_dir.join("rnix-parser-tester").join("compare.json"))
+    }
+
+    /// The results in `file`, if they were cached with the same `versions`. Otherwise, none of them can be used, and
+    /// the cache starts out empty.
+    pub fn load(file: &Path, versions: Versions) -> io::Result<Self> {
+        let cache = match fs::read(file) {
+            Ok(cache) => serde_json::from_slice::<Cache>(&cache).ok(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+
+        Ok(match cache {
+            Some(cache) if cache.versions == versions => cache,
+            _ => Cache {
+                versions,
+                results: HashMap::new(),
+            },
+        })
+    }
+
+    pub fn save(&self, file: &Path) -> io::Result<()> {
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(file, serde_json::to_vec(self)?)
+    }
+
+    /// What `source` is cached under: a hash of its content, and of where it is, since relative paths in it depend on
+    /// that. `None` if it can't be read, in which case comparing it will report the error.
+    pub fn key(source: &NixSource, paths: &PathContext) -> Option<String> {
+        let mut hasher = Sha256::new();
+        match source {
+            NixSource::File(file) => {
+                let file = paths.resolve_file(file).ok()?;
+                hasher.update(fs::read(&file).ok()?);
+                hasher.update([0]);
+                hasher.update(file);
+            }
+            NixSource::String(source) => {
+                hasher.update(source);
+                hasher.update([0]);
+                hasher.update(&paths.cwd);
+            }
+        }
+        Some(format!("{:x}", hasher.finalize()))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Vec<Outcome>> {
+        self.results.get(key)
+    }
+
+    pub fn insert(&mut self, key: String, outcomes: Vec<Outcome>) {
+        self.results.insert(key, outcomes);
+    }
+}
@@ -0,0 +1,273 @@
+//! Telling why the two sides' ASTs differ, so that the mismatches across a whole corpus can be grouped by their likely
+//! cause rather than looked at one by one.
+
+use std::fmt;
+
+use rnix_normalize::Pass;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The likely cause of a difference between the two sides' ASTs, going by the kinds of nodes which differ
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum MismatchKind {
+    /// A string is split into fragments differently, e.g. a single `String` on one side and a concatenation on the other
+    StringFragments,
+    /// A string has the same shape, but different contents, e.g. from escapes or the indentation of an indented string
+    StringContent,
+    /// A path was resolved to a different absolute path, or `<...>` into something else
+    PathCanonicalization,
+    /// A float has a different value, or a number is a float on one side only
+    FloatFormatting,
+    /// An attr set defines different attrs, e.g. from definitions of the same attr being merged differently
+    AttrMerge,
+    /// A call takes a different number of arguments, e.g. one side has a call of a call where the other has one call
+    CallFlattening,
+    /// An operator became something other than the call to a builtin on the other side
+    OperatorDesugaring,
+    /// A variable with the same name refers to a different binder
+    VarResolution,
+    /// A `__curPos` is at a different position
+    Position,
+    Other,
+}
+
+impl MismatchKind {
+    pub const ALL: [MismatchKind; 10] = [
+        MismatchKind::StringFragments,
+        MismatchKind::StringContent,
+        MismatchKind::PathCanonicalization,
+        MismatchKind::FloatFormatting,
+        MismatchKind::AttrMerge,
+        MismatchKind::CallFlattening,
+        MismatchKind::OperatorDesugaring,
+        MismatchKind::VarResolution,
+        MismatchKind::Position,
+        MismatchKind::Other,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MismatchKind::StringFragments => "string fragment split",
+            MismatchKind::StringContent => "string content",
+            MismatchKind::PathCanonicalization => "path canonicalization",
+            MismatchKind::FloatFormatting => "float formatting",
+            MismatchKind::AttrMerge => "attr merge",
+            MismatchKind::CallFlattening => "call flattening",
+            MismatchKind::OperatorDesugaring => "operator desugaring",
+            MismatchKind::VarResolution => "variable resolution",
+            MismatchKind::Position => "position",
+            MismatchKind::Other => "other",
+        }
+    }
+
+    /// The normalization pass which produces what differs, if it's up to one. The rest comes from lowering
+    /// rnix-parser's AST, or from rnix-parser itself.
+    pub fn pass(self) -> Option<Pass> {
+        match self {
+            MismatchKind::PathCanonicalization => Some(Pass::ResolvePaths),
+            MismatchKind::AttrMerge => Some(Pass::MergeAttrs),
+            MismatchKind::CallFlattening => Some(Pass::FlattenCalls),
+            MismatchKind::OperatorDesugaring => Some(Pass::DesugarOperators),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MismatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// One place where the two sides' ASTs differ
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Difference {
+    pub kind: MismatchKind,
+    /// Where the ASTs differ, as a JSON pointer into both sides
+    pub path: String,
+    /// What's there on the reference side, e.g. `Call`, or the type of the JSON value if it isn't a node
+    pub reference_node: String,
+    /// What's there on the candidate side
+    pub candidate_node: String,
+}
+
+impl Difference {
+    /// A short description of the difference, by its kind, the nodes which differ and the pass involved, e.g.
+    /// `call flattening (Call vs. Call, flatten-calls)`. `compare` groups differences by it, since the path alone
+    /// differs between most of them.
+    pub fn signature(&self) -> String {
+        match self.kind.pass() {
+            Some(pass) => format!(
+                "{} ({} vs. {}, {pass})",
+                self.kind, self.reference_node, self.candidate_node
+            ),
+            None => format!(
+                "{} ({} vs. {})",
+                self.kind, self.reference_node, self.candidate_node
+            ),
+        }
+    }
+}
+
+/// Find every place where `reference` and `candidate` differ, i.e., the outermost nodes which differ in a way that
+/// can be classified, or which differ in their shape. Both should be what's compared, e.g. a [`crate::Comparison`]'s
+/// JSON.
+pub fn classify(reference: &Value, candidate: &Value) -> Vec<Difference> {
+    let mut differences = vec![];
+    walk(reference, candidate, &mut String::new(), &mut differences);
+    differences
+}
+
+fn walk(lhs: &Value, rhs: &Value, path: &mut String, differences: &mut Vec<Difference>) {
+    if lhs == rhs {
+        return;
+    }
+
+    let difference = |kind, path: &str| Difference {
+        kind,
+        path: path.to_string(),
+        reference_node: describe(lhs),
+        candidate_node: describe(rhs),
+    };
+
+    if let (Some((lhs_kind, lhs_node)), Some((rhs_kind, rhs_node))) = (node(lhs), node(rhs)) {
+        let kind = if lhs_kind == rhs_kind {
+            same_kind(lhs_kind, lhs_node, rhs_node)
+        } else {
+            Some(different_kinds((lhs_kind, lhs_node), (rhs_kind, rhs_node)))
+        };
+        if let Some(kind) = kind {
+            differences.push(difference(kind, path));
+            return;
+        }
+    }
+
+    match (lhs, rhs) {
+        (Value::Object(lhs), Value::Object(rhs))
+            if lhs.len() == rhs.len() && lhs.keys().all(|key| rhs.contains_key(key)) =>
+        {
+            for (key, value) in lhs {
+                let len = path.len();
+                path.push('/');
+                path.push_str(key);
+                walk(value, &rhs[key], path, differences);
+                path.truncate(len);
+            }
+        }
+        (Value::Array(lhs), Value::Array(rhs)) if lhs.len() == rhs.len() => {
+            for (i, (lhs, rhs)) in lhs.iter().zip(rhs).enumerate() {
+                let len = path.len();
+                path.push('/');
+                path.push_str(&i.to_string());
+                walk(lhs, rhs, path, differences);
+                path.truncate(len);
+            }
+        }
+        _ => differences.push(difference(MismatchKind::Other, path)),
+    }
+}
+
+/// The kind of `value` and what's in it, if it's a node of the AST, i.e. an object with a single field named after
+/// the kind, like `{"Int": 1}`
+fn node(value: &Value) -> Option<(&str, &Value)> {
+    match value {
+        Value::Object(fields) if fields.len() == 1 => {
+            let (kind, node) = fields.iter().next()?;
+            kind.starts_with(|c: char| c.is_ascii_uppercase())
+                .then_some((kind.as_str(), node))
+        }
+        _ => None,
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match node(value) {
+        Some((kind, _)) => kind.to_string(),
+        None => match value {
+            Value::Null => "null",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        }
+        .to_string(),
+    }
+}
+
+/// Whether `reference` and `candidate` differ, but only in the values of numeric literals, i.e. every difference is
+/// between two `Int`s or two `Float`s. An `Int` on one side and a `Float` on the other differ in more than their values.
+pub fn only_values_differ(reference: &Value, candidate: &Value) -> bool {
+    let differences = classify(reference, candidate);
+    !differences.is_empty()
+        && differences.iter().all(|difference| {
+            difference.reference_node == difference.candidate_node
+                && matches!(difference.reference_node.as_str(), "Int" | "Float")
+        })
+}
+
+/// How two different nodes of the same kind differ, if it can be told from them as a whole. Otherwise, it's down to
+/// what's in them.
+fn same_kind(kind: &str, lhs: &Value, rhs: &Value) -> Option<MismatchKind> {
+    let len = |node: &Value, field| node.get(field).and_then(Value::as_array).map(Vec::len);
+    match kind {
+        "Float" => Some(MismatchKind::FloatFormatting),
+        "Path" => Some(MismatchKind::PathCanonicalization),
+        "String" => Some(MismatchKind::StringContent),
+        "Pos" => Some(MismatchKind::Position),
+        "Int" => Some(MismatchKind::Other),
+        "Var" if lhs.get("name") == rhs.get("name") => Some(MismatchKind::VarResolution),
+        "OpConcatStrings" if len(lhs, "es") != len(rhs, "es") => {
+            Some(MismatchKind::StringFragments)
+        }
+        "Call" if len(lhs, "args") != len(rhs, "args") => Some(MismatchKind::CallFlattening),
+        "Attrs"
+            if attr_names(lhs) != attr_names(rhs)
+                || len(lhs, "dynamic_attrs") != len(rhs, "dynamic_attrs") =>
+        {
+            Some(MismatchKind::AttrMerge)
+        }
+        _ => None,
+    }
+}
+
+fn attr_names(attrs: &Value) -> Vec<&Value> {
+    attrs
+        .get("attrs")
+        .and_then(Value::as_array)
+        .map(|attrs| attrs.iter().filter_map(|attr| attr.get("name")).collect())
+        .unwrap_or_default()
+}
+
+fn different_kinds(lhs: (&str, &Value), rhs: (&str, &Value)) -> MismatchKind {
+    let is = |kind| lhs.0 == kind || rhs.0 == kind;
+    let either = |a, b| (lhs.0 == a && rhs.0 == b) || (lhs.0 == b && rhs.0 == a);
+
+    if either("String", "OpConcatStrings") {
+        MismatchKind::StringFragments
+    } else if either("Int", "Float") {
+        MismatchKind::FloatFormatting
+    } else if either("Attrs", "UnmergedAttrs") {
+        MismatchKind::AttrMerge
+    } else if either("Path", "Call") {
+        MismatchKind::PathCanonicalization
+    } else if is("Operator") {
+        MismatchKind::OperatorDesugaring
+    } else if is("Call") {
+        let call = if lhs.0 == "Call" { lhs.1 } else { rhs.1 };
+        match call.get("fun").and_then(node) {
+            Some(("Call", _)) => MismatchKind::CallFlattening,
+            Some(("Var", var))
+                if var
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .is_some_and(|name| name.starts_with("__")) =>
+            {
+                MismatchKind::OperatorDesugaring
+            }
+            _ => MismatchKind::Other,
+        }
+    } else {
+        MismatchKind::Other
+    }
+}
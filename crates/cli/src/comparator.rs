@@ -0,0 +1,391 @@
+//! A configurable version of [`crate::check_parses_eq`], for embedding the tester in other programs.
+
+use std::{
+    error::Error,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use assert_json_diff::{assert_json_matches_no_panic, Config};
+use normal_ast::NormalNixExpr;
+use rnix_normalize::{merge_string_fragments, PathContext};
+use serde_json::Value;
+
+use crate::{
+    catch_panic, classify::only_values_differ, compare_errors, CheckResult, NixSource, Panic,
+    ParserBackend, Phase, RNixBackend, ReferenceImplBackend, SerializeError,
+};
+
+pub use assert_json_diff::CompareMode;
+
+/// How floats are compared. The two sides can print the same float differently, e.g. with fewer digits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FloatPolicy {
+    /// Floats must be exactly the same
+    Exact,
+    /// Floats are equal if they're the same when rounded to this many significant digits
+    SignificantDigits(u32),
+}
+
+/// How long each step of a [`Comparator::compare`] took
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timings {
+    /// Parsing with the reference backend, and bringing its AST into the shape that's compared
+    pub reference: Duration,
+    /// Parsing with the candidate backend, and bringing its AST into the shape that's compared
+    pub candidate: Duration,
+    /// Comparing both sides, including the diff if they differ
+    pub compare: Duration,
+}
+
+impl Timings {
+    pub fn total(&self) -> Duration {
+        self.reference + self.candidate + self.compare
+    }
+}
+
+/// What [`Comparator::compare`] found for one source
+pub struct Comparison {
+    pub result: CheckResult,
+    /// What was compared from each side, i.e., after ignored paths were removed and floats rounded. Only set for the
+    /// sides which accepted the source.
+    pub reference_json: Option<Value>,
+    pub candidate_json: Option<Value>,
+    pub timings: Timings,
+}
+
+/// What [`Comparator::compare_pairwise`] found for one pair of backends
+pub struct PairComparison {
+    /// The backend which stands for the reference in the comparison, as an index into
+    /// [`Comparator::backend_names`]
+    pub reference: usize,
+    /// The backend which stands for the candidate, which always comes after the reference
+    pub candidate: usize,
+    pub comparison: Comparison,
+}
+
+/// Compares what backends parse the same source into. By default, that's the reference impl and rnix-parser with
+/// every normalization pass, compared strictly, which is what [`crate::check_parses_eq`] does.
+///
+/// The first backend is the reference, which is assumed to be right, and the second is the candidate, which is the one
+/// being tested. In the [`CheckResult`], `ReferenceImpl` and `RNix` stand for these two. Any further backends are only
+/// compared by [`Comparator::compare_pairwise`].
+pub struct Comparator {
+    paths: PathContext,
+    mode: CompareMode,
+    floats: FloatPolicy,
+    ignored: Vec<Vec<String>>,
+    merge_string_fragments: bool,
+    compare_errors: bool,
+    backends: Vec<Box<dyn ParserBackend>>,
+}
+
+impl Comparator {
+    pub fn new(paths: PathContext) -> Self {
+        Comparator {
+            paths,
+            mode: CompareMode::Strict,
+            floats: FloatPolicy::Exact,
+            ignored: vec![],
+            merge_string_fragments: true,
+            compare_errors: false,
+            backends: vec![
+                Box::new(ReferenceImplBackend),
+                Box::new(RNixBackend::default()),
+            ],
+        }
+    }
+
+    /// Where paths in the parsed source are relative to
+    pub fn paths(mut self, paths: PathContext) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    /// With [`CompareMode::Inclusive`], the candidate's JSON only has to be contained in the reference's, e.g. so
+    /// that a candidate which leaves out some fields can still be compared.
+    pub fn compare_mode(mut self, mode: CompareMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn float_policy(mut self, floats: FloatPolicy) -> Self {
+        self.floats = floats;
+        self
+    }
+
+    /// Leave out everything at `path` on both sides. `path` is made of `/`-separated object keys and array indices, like
+    /// a JSON pointer, except that `*` stands for any one key or index and `**` for any number of them. E.g.,
+    /// `**/Var/displ` ignores where every variable was found in its scope. Can be given several times.
+    pub fn ignore_path(mut self, path: &str) -> Self {
+        self.ignored.push(
+            path.trim_start_matches('/')
+                .split('/')
+                .map(str::to_string)
+                .collect(),
+        );
+        self
+    }
+
+    /// Whether every side goes through the merge-string-fragments pass (see
+    /// [`rnix_normalize::Pass::MergeStringFragments`]) before comparing, which it should whenever rnix-parser's side
+    /// does. Otherwise, only backends which run the pass themselves have their string fragments merged.
+    pub fn merge_string_fragments(mut self, merge: bool) -> Self {
+        self.merge_string_fragments = merge;
+        self
+    }
+
+    /// When both backends reject a source, also tell whether they did so for the same reason (see
+    /// [`crate::compare_errors`]). That takes classifying both errors, and possibly reading the source again to
+    /// locate them, so it's off by default.
+    pub fn compare_errors(mut self, compare_errors: bool) -> Self {
+        self.compare_errors = compare_errors;
+        self
+    }
+
+    /// The backend whose output is assumed to be right
+    pub fn reference(mut self, backend: impl ParserBackend + 'static) -> Self {
+        self.backends[0] = Box::new(backend);
+        self
+    }
+
+    /// The backend being tested, e.g. rnix-parser normalized for another version of Nix
+    pub fn candidate(mut self, backend: impl ParserBackend + 'static) -> Self {
+        self.backends[1] = Box::new(backend);
+        self
+    }
+
+    /// Compare all of `backends` instead, the first of which is the reference, and the second the candidate
+    ///
+    /// # Panics
+    ///
+    /// If there are fewer than two backends
+    pub fn backends(mut self, backends: Vec<Box<dyn ParserBackend>>) -> Self {
+        assert!(backends.len() >= 2, "a comparison takes two backends");
+        self.backends = backends;
+        self
+    }
+
+    pub fn backend_names(&self) -> Vec<&str> {
+        self.backends.iter().map(|backend| backend.name()).collect()
+    }
+
+    /// Every pair of backends, as indices into [`Comparator::backend_names`], in the order that
+    /// [`Comparator::compare_pairwise`] compares them in. The first pair is the reference and the candidate.
+    pub fn pairs(&self) -> Vec<(usize, usize)> {
+        (0..self.backends.len())
+            .flat_map(|reference| {
+                (reference + 1..self.backends.len()).map(move |candidate| (reference, candidate))
+            })
+            .collect()
+    }
+
+    /// Compare the reference with the candidate
+    pub fn compare(&self, source: &NixSource) -> Comparison {
+        // Always run both sides, so that an expression which the candidate wrongly accepts doesn't hide behind a
+        // reference error
+        let reference = self.parse(&*self.backends[0], source);
+        let candidate = self.parse(&*self.backends[1], source);
+        self.judge(source, &reference, &candidate)
+    }
+
+    /// Compare each backend with each one after it. Each backend only parses `source` once.
+    pub fn compare_pairwise(&self, source: &NixSource) -> Vec<PairComparison> {
+        let sides: Vec<Side> = self
+            .backends
+            .iter()
+            .map(|backend| self.parse(&**backend, source))
+            .collect();
+
+        self.pairs()
+            .into_iter()
+            .map(|(reference, candidate)| PairComparison {
+                reference,
+                candidate,
+                comparison: self.judge(source, &sides[reference], &sides[candidate]),
+            })
+            .collect()
+    }
+
+    fn parse(&self, backend: &dyn ParserBackend, source: &NixSource) -> Side {
+        let start = Instant::now();
+        let parsed = match catch_panic(|| backend.parse(source, &self.paths)) {
+            Ok(Ok(expr)) => match self.prepare(expr) {
+                Ok(json) => Parsed::Accepted(json),
+                Err(err) => Parsed::Rejected(Rc::new(err)),
+            },
+            Ok(Err(err)) => Parsed::Rejected(Rc::from(err)),
+            Err(panic) => Parsed::Panicked(panic),
+        };
+        Side {
+            parsed,
+            time: start.elapsed(),
+        }
+    }
+
+    /// Serialize the AST from a backend and bring it into the shape that's compared
+    fn prepare(&self, expr: NormalNixExpr) -> Result<Value, SerializeError> {
+        let expr = if self.merge_string_fragments {
+            merge_string_fragments(expr)
+        } else {
+            expr
+        };
+        let mut json = serde_json::to_value(expr).map_err(SerializeError)?;
+        for path in &self.ignored {
+            remove_path(&mut json, path);
+        }
+        if let FloatPolicy::SignificantDigits(digits) = self.floats {
+            round_floats(&mut json, digits);
+        }
+        Ok(json)
+    }
+
+    fn judge(&self, source: &NixSource, reference: &Side, candidate: &Side) -> Comparison {
+        let start = Instant::now();
+        let failed = |phase, error: &Rc<dyn Error>| {
+            let error = error.clone();
+            (CheckResult::Failed { phase, error }, None, None)
+        };
+        let (result, reference_json, candidate_json) = match (&reference.parsed, &candidate.parsed)
+        {
+            // Whatever the reference made of the source doesn't matter then
+            (_, Parsed::Panicked(panic)) => (CheckResult::RNixPanicked(panic.clone()), None, None),
+            // The reference is assumed to be right, so its panic says nothing about the candidate
+            (Parsed::Panicked(panic), _) => {
+                let error = Rc::new(panic.clone());
+                (
+                    CheckResult::Failed {
+                        phase: Phase::ReferenceParse,
+                        error,
+                    },
+                    None,
+                    None,
+                )
+            }
+            // Neither parser is to blame for these, so they say nothing about the other side
+            (_, Parsed::Rejected(error)) if failure_phase(&**error).is_some() => {
+                failed(failure_phase(&**error).unwrap(), error)
+            }
+            (Parsed::Rejected(error), _) if failure_phase(&**error).is_some() => {
+                failed(failure_phase(&**error).unwrap(), error)
+            }
+            (Parsed::Accepted(lhs), Parsed::Accepted(rhs)) => {
+                let result = match assert_json_matches_no_panic(lhs, rhs, Config::new(self.mode)) {
+                    Ok(()) => CheckResult::Equal,
+                    Err(err) if only_values_differ(lhs, rhs) => CheckResult::ValuesDiffer(err),
+                    Err(err) => CheckResult::NotEqual(err),
+                };
+                (result, Some(lhs.clone()), Some(rhs.clone()))
+            }
+            (Parsed::Rejected(reference_impl_error), Parsed::Rejected(rnix_error)) => {
+                let result = CheckResult::BothReject {
+                    errors: self.compare_errors.then(|| {
+                        compare_errors(source, &self.paths, &**reference_impl_error, &**rnix_error)
+                    }),
+                    reference_impl_error: reference_impl_error.clone(),
+                    rnix_error: rnix_error.clone(),
+                };
+                (result, None, None)
+            }
+            (Parsed::Rejected(err), Parsed::Accepted(_)) => (
+                CheckResult::OnlyReferenceImplRejects(err.clone()),
+                None,
+                None,
+            ),
+            (Parsed::Accepted(_), Parsed::Rejected(err)) => {
+                (CheckResult::OnlyRNixRejects(err.clone()), None, None)
+            }
+        };
+
+        Comparison {
+            result,
+            reference_json,
+            candidate_json,
+            timings: Timings {
+                reference: reference.time,
+                candidate: candidate.time,
+                compare: start.elapsed(),
+            },
+        }
+    }
+}
+
+/// What one backend made of a source, and how long that took
+struct Side {
+    parsed: Parsed,
+    time: Duration,
+}
+
+enum Parsed {
+    /// The AST, in the shape that's compared
+    Accepted(Value),
+    /// Shared by the comparisons of every pair which the backend is in
+    Rejected(Rc<dyn Error>),
+    Panicked(Panic),
+}
+
+/// The phase that `err` came from, if it isn't one where a parser rejects the source. Errors from custom backends count
+/// as rejections.
+fn failure_phase(err: &(dyn Error + 'static)) -> Option<Phase> {
+    Phase::of(err).filter(|phase| !phase.is_rejection())
+}
+
+/// Remove whatever matches `path` from `json`. See [`Comparator::ignore_path`].
+fn remove_path(json: &mut Value, path: &[String]) {
+    let (segment, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    if segment == "**" {
+        // `**` matching nothing, then matching at least one level
+        remove_path(json, rest);
+        match json {
+            Value::Object(fields) => fields.values_mut().for_each(|v| remove_path(v, path)),
+            Value::Array(items) => items.iter_mut().for_each(|v| remove_path(v, path)),
+            _ => {}
+        }
+        return;
+    }
+
+    let matches = |key: &str| segment == "*" || segment == key;
+    match json {
+        Value::Object(fields) if rest.is_empty() => fields.retain(|key, _| !matches(key)),
+        Value::Object(fields) => fields
+            .iter_mut()
+            .filter(|(key, _)| matches(key))
+            .for_each(|(_, v)| remove_path(v, rest)),
+        Value::Array(items) if rest.is_empty() => {
+            let mut i = 0;
+            items.retain(|_| {
+                i += 1;
+                !matches(&(i - 1).to_string())
+            })
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| matches(&i.to_string()))
+            .for_each(|(_, v)| remove_path(v, rest)),
+        _ => {}
+    }
+}
+
+/// Round every `Float` in `json` to `digits` significant digits
+fn round_floats(json: &mut Value, digits: u32) {
+    match json {
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                match value.as_f64() {
+                    Some(float) if key == "Float" => {
+                        let rounded = format!("{float:.*e}", digits.saturating_sub(1) as usize);
+                        *value = Value::from(rounded.parse::<f64>().unwrap_or(float));
+                    }
+                    _ => round_floats(value, digits),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| round_floats(v, digits)),
+        _ => {}
+    }
+}
@@ -0,0 +1,71 @@
+//! Running work on many files across several threads, for `compare --jobs`
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+/// Run `work` on each of `inputs` on `jobs` threads. `on_finished` sees each result as soon as it's done, e.g. for
+/// progress counters, and `on_result` then gets them in the order of `inputs`, no matter which order they finished in.
+/// Both are called on the current thread. If `on_result` fails, the remaining inputs are skipped.
+///
+/// Each thread is registered with the reference impl's garbage collector for as long as it runs. A
+/// [`ref_impl_parser::Parser`] can't be sent between threads, so `work` has to create its own.
+pub fn for_each_in_parallel<I, T, E>(
+    inputs: &[I],
+    jobs: usize,
+    work: impl Fn(&I) -> T + Sync,
+    mut on_finished: impl FnMut(&T),
+    mut on_result: impl FnMut(usize, T) -> Result<(), E>,
+) -> Result<(), E>
+where
+    I: Sync,
+    T: Send,
+{
+    ref_impl_parser::allow_parser_threads();
+
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            let (sender, next, work) = (sender.clone(), &next, &work);
+            scope.spawn(move || {
+                let _registration = ref_impl_parser::ThreadRegistration::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let input = match inputs.get(i) {
+                        Some(input) => input,
+                        None => break,
+                    };
+                    // The receiver only hangs up when on_result failed, so there's no point in going on
+                    if sender.send((i, work(input))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        // Results which are done, but still waiting for the ones before them
+        let mut pending = BTreeMap::new();
+        let mut in_order = 0;
+        for (i, result) in receiver {
+            on_finished(&result);
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&in_order) {
+                if let Err(err) = on_result(in_order, result) {
+                    // Stop handing out inputs, so the threads finish up with what they're on
+                    next.store(inputs.len(), Ordering::Relaxed);
+                    return Err(err);
+                }
+                in_order += 1;
+            }
+        }
+        Ok(())
+    })
+}
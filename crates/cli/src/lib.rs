@@ -1,85 +1,487 @@
-use assert_json_diff::{assert_json_matches_no_panic, CompareMode, Config};
-use rnix_ast::ast::RNixExpr;
-use rnix_normalize::normalize_nix_expr;
-use serde::Deserialize;
-use serde_json::Value;
-use std::{env, error::Error, fs, path::PathBuf};
+mod backend;
+mod classify;
+mod comparator;
+mod minimize;
+mod panic;
+mod phase;
+mod process;
+
+use ref_impl_parser::ReferenceImplError;
+use rnix::parser::ParseError;
+use rnix_ast::{ast::RNixExpr, convert::ToAstError};
+use rnix_normalize::{
+    normalize_nix_expr_staged, normalize_nix_expr_traced, LineIndex, NixVersionProfile,
+    NormalizeError, Pass, Passes, PathContext,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    cell::OnceCell,
+    error::Error,
+    fmt::{self, Write},
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+pub use backend::{CustomBackend, ParseFn, ParserBackend, RNixBackend, ReferenceImplBackend};
+pub use classify::{classify, Difference, MismatchKind};
+pub use comparator::{Comparator, CompareMode, Comparison, FloatPolicy, PairComparison, Timings};
+pub use minimize::{minimize, MinimizeError, MismatchSignature};
+pub use panic::{catch_panic, Panic};
+pub use phase::{CompareError, ConvertError, Phase, RNixParseError, ReadError, SerializeError};
+pub use process::{ExternalParseError, ProcessBackend, ProcessError, ProcessFailure, ProcessMode};
 
 pub enum NixSource {
     String(String),
     File(PathBuf),
 }
 
-pub fn get_ref_impl_json(source: &NixSource) -> Result<String, Box<dyn Error>> {
-    let parser = ref_impl_parser::Parser::new();
+pub fn get_ref_impl_json(
+    source: &NixSource,
+    paths: &PathContext,
+) -> Result<String, Box<dyn Error>> {
+    let parser = ref_impl_parser::Parser::new(&paths.home);
     match source {
-        NixSource::String(input) => parser.parse_from_str(input),
-        NixSource::File(path) => parser.parse_from_file(path),
+        NixSource::String(input) => parser.parse_from_str(input, &paths.cwd),
+        NixSource::File(path) => {
+            parser.parse_from_file(paths.resolve_file(path).map_err(ReadError::Resolve)?)
+        }
     }
 }
 
-pub fn get_rnix_json(source: &NixSource) -> Result<String, Box<dyn Error>> {
-    let home_path = env::var("HOME")?;
-    let ast = match source {
-        NixSource::String(input) => normalize_nix_expr(
-            RNixExpr::try_from(rnix::Root::parse(input))?,
-            env::current_dir()?.into_os_string().into_string().unwrap(),
-            home_path,
-        ),
-        NixSource::File(path) => {
-            let input = fs::read_to_string(path)?;
-            normalize_nix_expr(
-                RNixExpr::try_from(rnix::Root::parse(input.as_str()))?,
-                path.parent()
-                    .unwrap()
-                    .to_path_buf()
-                    .into_os_string()
-                    .into_string()
-                    .unwrap(),
-                home_path,
-            )
-        }
+/// Serialize the rnix-parser AST as-is, i.e., before normalization. Useful for telling whether a mismatch comes from
+/// rnix-parser itself or from the normalization rules.
+pub fn get_rnix_ast_json(
+    source: &NixSource,
+    paths: &PathContext,
+) -> Result<String, Box<dyn Error>> {
+    let (input, _) = read_source(source, paths)?;
+    let ast = parse_rnix(&input)?;
+
+    let json = serde_json::to_string(&ast).map_err(SerializeError)?;
+
+    Ok(json)
+}
+
+/// Render the rowan CST produced by rnix-parser as an indented tree, one node or token per line. If `mark_ignored` is
+/// set, nodes which [`rnix_ast::convert`] never looked at are marked as such.
+pub fn get_rnix_cst(
+    source: &NixSource,
+    paths: &PathContext,
+    mark_ignored: bool,
+) -> Result<String, Box<dyn Error>> {
+    let (input, _) = read_source(source, paths)?;
+
+    let parse = rnix::Root::parse(&input);
+    let visited = if mark_ignored {
+        // The conversion failing doesn't matter here. Whatever it got through is still recorded.
+        Some(rnix_ast::convert::convert_recording_visited(parse.clone()).1)
+    } else {
+        None
     };
 
-    let json = serde_json::to_string(&ast)?;
+    let mut out = String::new();
+    let mut depth = 0;
+    for event in parse.syntax().preorder_with_tokens() {
+        match event {
+            rnix::WalkEvent::Enter(element) => {
+                write!(
+                    out,
+                    "{:indent$}{:?}@{:?}",
+                    "",
+                    element.kind(),
+                    element.text_range(),
+                    indent = depth * 2
+                )?;
+                match &element {
+                    rnix::NodeOrToken::Node(node) => {
+                        if let Some(visited) = &visited {
+                            if !visited.contains(&(node.kind(), node.text_range())) {
+                                write!(out, " (ignored)")?;
+                            }
+                        }
+                        depth += 1;
+                    }
+                    rnix::NodeOrToken::Token(token) => write!(out, " {:?}", token.text())?,
+                }
+                writeln!(out)?;
+            }
+            rnix::WalkEvent::Leave(rnix::NodeOrToken::Node(_)) => depth -= 1,
+            rnix::WalkEvent::Leave(rnix::NodeOrToken::Token(_)) => {}
+        }
+    }
+
+    Ok(out)
+}
+
+pub fn get_rnix_json(
+    source: &NixSource,
+    paths: &PathContext,
+    passes: &Passes,
+    profile: NixVersionProfile,
+) -> Result<String, Box<dyn Error>> {
+    let ast = RNixBackend::new(passes.clone(), profile).parse(source, paths)?;
+
+    let json = serde_json::to_string(&ast).map_err(SerializeError)?;
 
     Ok(json)
 }
 
+/// Render which lowering rule produced each node of the rnix-parser AST, before any pass, and from where in the
+/// source.
+pub fn get_rnix_trace(
+    source: &NixSource,
+    paths: &PathContext,
+    passes: &Passes,
+    profile: NixVersionProfile,
+) -> Result<String, Box<dyn Error>> {
+    let (input, file) = read_source(source, paths)?;
+    let (_, trace) = normalize_nix_expr_traced(
+        parse_rnix(&input)?,
+        &input,
+        file.as_deref(),
+        paths,
+        passes,
+        profile,
+    )?;
+
+    Ok(trace.to_string())
+}
+
+/// Serialize the rnix-parser AST after lowering and after each normalization pass, handing each to `on_stage` along
+/// with the name of the stage as soon as it's done. If a pass fails, the stages before it have still been handed over.
+pub fn get_rnix_stages(
+    source: &NixSource,
+    paths: &PathContext,
+    passes: &Passes,
+    profile: NixVersionProfile,
+    on_stage: &mut dyn FnMut(&str, String),
+) -> Result<(), Box<dyn Error>> {
+    let (input, file) = read_source(source, paths)?;
+    normalize_nix_expr_staged(
+        parse_rnix(&input)?,
+        &input,
+        file.as_deref(),
+        paths,
+        passes,
+        profile,
+        &mut |stage, expr| {
+            on_stage(
+                stage,
+                serde_json::to_string(expr).expect("A NormalNixExpr always serializes"),
+            )
+        },
+    )?;
+
+    Ok(())
+}
+
+/// The contents of `source`, along with the name it should be parsed as, if it's a file
+fn read_source(
+    source: &NixSource,
+    paths: &PathContext,
+) -> Result<(String, Option<String>), ReadError> {
+    match source {
+        NixSource::String(input) => Ok((input.clone(), None)),
+        NixSource::File(path) => {
+            let file = paths.resolve_file(path).map_err(ReadError::Resolve)?;
+            Ok((read_file(file.as_ref())?, Some(file)))
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, ReadError> {
+    fs::read_to_string(path).map_err(|err| ReadError::Io(path.to_path_buf(), err))
+}
+
+/// Parse `input` with rnix-parser, and convert it to rnix-ast's AST. rnix-parser rejecting it is told apart from the
+/// conversion failing, which is the tester's fault.
+fn parse_rnix(input: &str) -> Result<RNixExpr, Box<dyn Error>> {
+    RNixExpr::try_from(rnix::Root::parse(input)).map_err(|err| -> Box<dyn Error> {
+        match err {
+            ToAstError::ParseError(err) => Box::new(RNixParseError(err)),
+            err => Box::new(ConvertError(err)),
+        }
+    })
+}
+
+/// The result of comparing two parsers. Errors are shared, since with several backends, each of them is compared with
+/// every other (see [`Comparator::compare_pairwise`]).
 pub enum CheckResult {
+    /// Both parsers accepted the expression, and their normalized ASTs are the same
     Equal,
+    /// Both parsers accepted the expression, but their normalized ASTs differ
     NotEqual(String),
-    ReferenceImplError(Box<dyn Error>),
-    RNixError(Box<dyn Error>),
+    /// Both parsers accepted the expression, and their normalized ASTs only differ in the values of numeric literals,
+    /// e.g. from a float being parsed to a different number
+    ValuesDiffer(String),
+    /// Both parsers rejected the expression. `errors` says whether they did so for the same reason, if the comparator
+    /// compared them (see [`Comparator::compare_errors`]).
+    BothReject {
+        reference_impl_error: Rc<dyn Error>,
+        rnix_error: Rc<dyn Error>,
+        errors: Option<ErrorComparison>,
+    },
+    OnlyReferenceImplRejects(Rc<dyn Error>),
+    OnlyRNixRejects(Rc<dyn Error>),
+    /// rnix-parser, or the tester while converting or normalizing its AST, panicked. Whatever the reference impl made
+    /// of the expression doesn't matter then.
+    RNixPanicked(Panic),
+    /// A phase which isn't either parser's failed, e.g. the file couldn't be read, or rnix-parser's AST couldn't be
+    /// converted. See [`Phase::is_rejection`]. The reference panicking counts as this too, in
+    /// [`Phase::ReferenceParse`].
+    Failed {
+        phase: Phase,
+        error: Rc<dyn Error>,
+    },
+}
+
+impl CheckResult {
+    /// The phase whose error decided the result, if it was decided by an error. When both parsers rejected the
+    /// expression, that's rnix-parser's phase.
+    pub fn phase(&self) -> Option<Phase> {
+        match self {
+            CheckResult::Equal
+            | CheckResult::NotEqual(_)
+            | CheckResult::ValuesDiffer(_)
+            | CheckResult::RNixPanicked(_) => None,
+            CheckResult::BothReject {
+                rnix_error: err, ..
+            }
+            | CheckResult::OnlyRNixRejects(err) => {
+                Some(Phase::of(&**err).unwrap_or(Phase::RNixParse))
+            }
+            CheckResult::OnlyReferenceImplRejects(_) => Some(Phase::ReferenceParse),
+            CheckResult::Failed { phase, .. } => Some(*phase),
+        }
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckResult::Equal => write!(f, "equal"),
+            CheckResult::NotEqual(err) => write!(f, "not equal: {err}"),
+            CheckResult::ValuesDiffer(err) => write!(f, "values differ: {err}"),
+            CheckResult::BothReject {
+                reference_impl_error,
+                rnix_error,
+                errors,
+            } => {
+                write!(f, "both reject")?;
+                if let Some(errors) = errors {
+                    write!(f, ", with {errors}")?;
+                }
+                write!(f, ": {reference_impl_error}, {rnix_error}")
+            }
+            CheckResult::OnlyReferenceImplRejects(err) => {
+                write!(f, "only the reference impl rejects: {err}")
+            }
+            CheckResult::OnlyRNixRejects(err) => write!(f, "only rnix-parser rejects: {err}"),
+            CheckResult::RNixPanicked(panic) => write!(f, "rnix-parser {panic}"),
+            CheckResult::Failed { phase, error } => write!(f, "{phase} failed: {error}"),
+        }
+    }
 }
 
-pub fn check_parses_eq(source: NixSource) -> CheckResult {
-    let ref_impl_json = match get_ref_impl_json(&source) {
-        Ok(s) => s,
-        Err(err) => return CheckResult::ReferenceImplError(err),
+/// Compare the reference impl's AST for `source` with rnix-parser's after the given `passes`, normalized for `profile`.
+/// With any pass switched off, the two are only equal if the expression happens to have none of that pass' sugar. When
+/// both reject it, their errors are compared too. See [`Comparator`] for more options.
+pub fn check_parses_eq(
+    source: NixSource,
+    paths: &PathContext,
+    passes: &Passes,
+    profile: NixVersionProfile,
+) -> CheckResult {
+    Comparator::new(paths.clone())
+        .candidate(RNixBackend::new(passes.clone(), profile))
+        .merge_string_fragments(passes.is_enabled(Pass::MergeStringFragments))
+        .compare_errors(true)
+        .compare(&source)
+        .result
+}
+
+/// Broadly, why a parser rejected an expression. The two parsers word their errors differently, so this is what gets
+/// compared when both reject.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ErrorCategory {
+    Syntax,
+    InvalidInteger,
+    InvalidFloat,
+    DuplicateAttr,
+    DuplicateFormal,
+    DynamicAttrInLet,
+    DynamicAttrInInherit,
+    PathTrailingSlash,
+    UndefinedVariable,
+    Other,
+}
+
+/// A 1-based line and column, with columns counted in bytes like the reference impl does.
+pub type Location = (u32, u32);
+
+#[derive(Clone, Deserialize, Serialize)]
+pub enum ErrorComparison {
+    Same,
+    DifferentCategory {
+        reference_impl: ErrorCategory,
+        rnix: ErrorCategory,
+    },
+    /// Same category, but reported at different places. Locations are only compared when both errors have one.
+    DifferentLocation {
+        reference_impl: Location,
+        rnix: Location,
+    },
+}
+
+impl fmt::Display for ErrorComparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorComparison::Same => write!(f, "the same error"),
+            ErrorComparison::DifferentCategory {
+                reference_impl,
+                rnix,
+            } => write!(
+                f,
+                "different errors (reference impl: {reference_impl:?}, rnix-parser: {rnix:?})"
+            ),
+            ErrorComparison::DifferentLocation {
+                reference_impl: (ref_line, ref_column),
+                rnix: (rnix_line, rnix_column),
+            } => write!(
+                f,
+                "errors at different locations (reference impl: {ref_line}:{ref_column}, rnix-parser: {rnix_line}:{rnix_column})"
+            ),
+        }
+    }
+}
+
+/// Compare the errors from [`get_ref_impl_json`] and [`get_rnix_json`] for the same source, whose files are found in
+/// `paths`. Either can be an error from either parser, since any two backends can be compared.
+pub fn compare_errors(
+    source: &NixSource,
+    paths: &PathContext,
+    reference_impl_error: &(dyn Error + 'static),
+    rnix_error: &(dyn Error + 'static),
+) -> ErrorComparison {
+    let (ref_category, ref_position) = classify_error(reference_impl_error);
+    let (rnix_category, rnix_position) = classify_error(rnix_error);
+
+    if ref_category != rnix_category {
+        return ErrorComparison::DifferentCategory {
+            reference_impl: ref_category,
+            rnix: rnix_category,
+        };
+    }
+
+    // Only read when an error is at an offset
+    let input = OnceCell::new();
+    let locate = |position| match position? {
+        ErrorPosition::LineColumn(location) => Some(location),
+        ErrorPosition::Offset(offset) => {
+            let input = input
+                .get_or_init(|| read_source(source, paths).ok().map(|(input, _)| input))
+                .as_ref()?;
+            Some(LineIndex::new(input).line_col(offset))
+        }
     };
 
-    let rnix_json = match get_rnix_json(&source) {
-        Ok(s) => s,
-        Err(err) => return CheckResult::RNixError(err),
+    match (locate(ref_position), locate(rnix_position)) {
+        (Some(reference_impl), Some(rnix)) if reference_impl != rnix => {
+            ErrorComparison::DifferentLocation {
+                reference_impl,
+                rnix,
+            }
+        }
+        _ => ErrorComparison::Same,
+    }
+}
+
+/// Where an error is, as the parser which reported it has it
+enum ErrorPosition {
+    LineColumn(Location),
+    Offset(usize),
+}
+
+fn classify_error(err: &(dyn Error + 'static)) -> (ErrorCategory, Option<ErrorPosition>) {
+    if err.is::<ReferenceImplError>() {
+        let (category, location) = classify_reference_impl_error(err);
+        (category, location.map(ErrorPosition::LineColumn))
+    } else if let Some(err) = err.downcast_ref::<ExternalParseError>() {
+        (
+            err.category.unwrap_or(ErrorCategory::Other),
+            err.location().map(ErrorPosition::LineColumn),
+        )
+    } else {
+        let (category, offset) = classify_rnix_error(err);
+        (category, offset.map(ErrorPosition::Offset))
+    }
+}
+
+fn classify_reference_impl_error(err: &(dyn Error + 'static)) -> (ErrorCategory, Option<Location>) {
+    let err = match err.downcast_ref::<ReferenceImplError>() {
+        Some(err) => err,
+        None => return (ErrorCategory::Other, None),
+    };
+
+    let message = err.message.as_str();
+    let category = if message.starts_with("syntax error") {
+        ErrorCategory::Syntax
+    } else if message.starts_with("invalid integer") {
+        ErrorCategory::InvalidInteger
+    } else if message.starts_with("invalid float") {
+        ErrorCategory::InvalidFloat
+    } else if message.starts_with("attribute") && message.contains("already defined") {
+        ErrorCategory::DuplicateAttr
+    } else if message.starts_with("duplicate formal function argument") {
+        ErrorCategory::DuplicateFormal
+    } else if message.starts_with("dynamic attributes not allowed in let") {
+        ErrorCategory::DynamicAttrInLet
+    } else if message.starts_with("dynamic attributes not allowed in inherit") {
+        ErrorCategory::DynamicAttrInInherit
+    } else if message.contains("has a trailing slash") {
+        ErrorCategory::PathTrailingSlash
+    } else if message.starts_with("undefined variable") {
+        ErrorCategory::UndefinedVariable
+    } else {
+        ErrorCategory::Other
     };
 
-    let lhs = deser_json(ref_impl_json).unwrap();
-    let rhs = deser_json(rnix_json).unwrap();
+    (category, err.line.zip(err.column))
+}
 
-    let config = Config::new(CompareMode::Strict);
+/// Classify an error from [`get_rnix_json`], along with the byte offset it's at. Syntax errors aren't located, since
+/// the two parsers generally notice them at different tokens.
+fn classify_rnix_error(err: &(dyn Error + 'static)) -> (ErrorCategory, Option<usize>) {
+    if let Some(err) = err.downcast_ref::<NormalizeError>() {
+        let category = match err {
+            NormalizeError::IntegerOutOfRange { .. } => ErrorCategory::InvalidInteger,
+            NormalizeError::FloatOutOfRange { .. } => ErrorCategory::InvalidFloat,
+            NormalizeError::DuplicateAttr { .. } => ErrorCategory::DuplicateAttr,
+            NormalizeError::DuplicateFormal { .. } => ErrorCategory::DuplicateFormal,
+            NormalizeError::DynamicAttrInLet { .. } => ErrorCategory::DynamicAttrInLet,
+            NormalizeError::DynamicAttrInInherit { .. } => ErrorCategory::DynamicAttrInInherit,
+            NormalizeError::PathTrailingSlash { .. } => ErrorCategory::PathTrailingSlash,
+            NormalizeError::UndefinedVariable { .. } => ErrorCategory::UndefinedVariable,
+        };
+        return (category, Some(err.span().start));
+    }
 
-    match assert_json_matches_no_panic(&lhs, &rhs, config) {
-        Ok(()) => CheckResult::Equal,
-        Err(err) => CheckResult::NotEqual(err),
+    match err.downcast_ref::<RNixParseError>() {
+        // rnix-parser catches some duplicate formals itself
+        Some(RNixParseError(ParseError::DuplicatedArgs(range, _))) => {
+            (ErrorCategory::DuplicateFormal, Some(range.start().into()))
+        }
+        Some(_) => (ErrorCategory::Syntax, None),
+        None => (ErrorCategory::Other, None),
     }
 }
 
-fn deser_json(json: String) -> Result<Value, serde_json::Error> {
-    let mut deserializer = serde_json::Deserializer::from_str(&json);
+fn deser_json<T: DeserializeOwned>(json: &str) -> Result<T, serde_json::Error> {
+    let mut deserializer = serde_json::Deserializer::from_str(json);
     deserializer.disable_recursion_limit();
     let deserializer = serde_stacker::Deserializer::new(&mut deserializer);
-    Value::deserialize(deserializer)
+    T::deserialize(deserializer)
 }
 
 #[derive(Debug)]
@@ -95,15 +497,35 @@ impl std::error::Error for JsonMismatch {}
 
 #[cfg(test)]
 mod integration_tests {
-    use crate::{check_parses_eq, CheckResult, NixSource};
+    use crate::{
+        check_parses_eq, classify, classify::only_values_differ, compare_errors, get_rnix_json,
+        get_rnix_stages, get_rnix_trace, minimize, CheckResult, Comparator, CustomBackend,
+        ErrorCategory, ErrorComparison, ExternalParseError, FloatPolicy, MinimizeError,
+        MismatchKind, NixSource, ParserBackend, Phase, ProcessBackend, ProcessError,
+        ProcessFailure, ProcessMode, RNixBackend,
+    };
     use indoc::indoc;
+    use ref_impl_parser::ReferenceImplError;
+    use rnix_ast::ast::Span;
+    use rnix_normalize::{
+        merge_string_fragments, NixVersionProfile, NormalizeError, Pass, Passes, PathContext,
+        SymlinkPolicy,
+    };
+    use std::time::Duration;
 
     fn assert_parses_eq(nix_expr: &str) {
-        match check_parses_eq(NixSource::String(nix_expr.to_string())) {
+        assert_parses_eq_in(nix_expr, &PathContext::from_env().unwrap())
+    }
+
+    fn assert_parses_eq_in(nix_expr: &str, paths: &PathContext) {
+        match check_parses_eq(
+            NixSource::String(nix_expr.to_string()),
+            paths,
+            &Passes::default(),
+            NixVersionProfile::default(),
+        ) {
             CheckResult::Equal => {}
-            CheckResult::NotEqual(err) => panic!("{err}"),
-            CheckResult::ReferenceImplError(err) => panic!("{err}"),
-            CheckResult::RNixError(err) => panic!("{err}"),
+            result => panic!("{result}"),
         }
     }
 
@@ -120,146 +542,742 @@ mod integration_tests {
         };
     }
 
-    // Many expressions are nested in a lambda to introduce dummy identifiers.
-    // This is necessary because the reference nix parser couples parsing and
-    // evaluation and will complain about undeclared identifiers at the
-    // parsing phase. As long as lambdas parse equally, then this shouldn't
-    // affect the outcome of the test.
-    gen_tests! {
-        int: "1",
-        int_leading_zeros: "001",
-        float: "3.14",
-        float_no_whole_part: ".14",
-        float_e: "2.5e01",
-        float_e_no_whole_part: ".5e01",
-        string: r#" "hello world" "#,
-        string_interpolated: r#" "hello ${"world"} ${123}" "#,
-        string_escaped_quote: r#" "hello \"world\"" "#,
-        string_multiline: indoc!{r#"
-            ''
-            Hello world
-            ''
-        "#},
-        // FIXME: I don't think this test is working correctly. Shouldn't it fail because of https://github.com/nix-community/rnix-parser/issues/71 ?
-        string_multiline_indented: indoc!{r#"
-            ''
-                foo
-                bar
-            ''
-        "#},
-        // https://github.com/nix-community/rnix-parser/issues/69
-        string_multiline_nested_quotes: r#" ''"foo"'' "#,
-        // https://github.com/kamadorueda/alejandra/issues/194
-        string_escaped_interpol: indoc!{r#"
-            ''
-                foo ''\${ bar
-            ''
-        "#},
-        path_relative: "foo/bar",
-        path_relative_prefixed: "./foo/bar",
-        path_relative_parent: "./foo/..",
-        path_relative_cur: "./.",
-        path_absolute: "/foo/bar",
-        path_absolute_parent: "/foo/bar/..",
-        path_absolute_cur: "/foo/bar/.",
-        path_home: "~/foo/bar",
-        path_home_parent: "~/foo/bar/..",
-        path_home_cur: "~/foo/bar/.",
-        path_store: "<foo/bar>",
-        path_store_parent: "<foo/bar/..>",
-        path_store_cur: "<foo/bar/.>",
-        path_interpolated: r#"./${"foo"}"#,
-        select: "x: x.y",
-        select_nested: "x: x.y.z",
-        select_with_default: "x: x.y.z or 37",
-        select_string: r#" {}."foo" "#,
-        select_string_interp: r#" {}."${"foo"}" "#,
-        select_dynamic: "x: {}.${x}",
-        select_dynamic_constant_string: r#" {}.${"foo"} "#,
-        has_attr: "x: x ? y",
-        has_attr_compound: "x: x ? y.z",
-        has_attr_string: r#" {} ? "foo" "#,
-        has_attr_string_interpol: r#" {} ? "${"foo"}" "#,
-        has_attr_dynamic: "x: {} ? ${x}",
-        has_attr_dynamic_constant_string: r#" {} ? ${"foo"} "#,
-        has_attr_select_first_part_not_var: r#" {} ? ${"foo"}.y "#,
-        attrs: "{ x = 5; }",
-        attrs_multiple: "{ x = 5; y = 3.14; }",
-        attrs_nested: "{ x = { y = { z = 5; }; }; }",
-        attrs_compound_key: "{ x.y.z = 5; }",
-        attrs_rec: "rec { x = 5; y = x; }",
-        attrs_dynamic: "x: { ${x} = 5; }",
-        attrs_dynamic_constant_string: r#"{ ${"foo"} = "bar"; }"#,
-        attrs_dynamic_plain_compound: "x: { ${x}.y = 5; }",
-        attrs_dynamic_dynamic_compound: "x: { ${x}.${x} = 5; }",
-        attrs_string_key: r#"{ "hello" = "world"; }"#,
-        attrs_string_key_interpol: r#"x: { "${x}.y" = 5; }"#,
-        attrs_overlapping: r#"{ x.y = "foo"; x.z = "bar"; }"#,
-        attrs_inherit: "x: { inherit x; }",
-        attrs_inherit_from: "x: { inherit (x) y z; }",
-        list: r#"[1 "2" (x: 3) 4.5]"#,
-        list_empty: "[]",
-        lambda: "x: x",
-        lambda_underscore_arg: "_:null",
-        lambda_nested: "x: y: x",
-        lambda_formals: "{ x }: x",
-        lambda_formals_default: "{ x ? null } : x",
-        lambda_formals_ellipsis: "{ x, ... }: x",
-        lambda_formals_at_left: "inp@{ x }: x",
-        lambda_formals_at_right: "{ x }@inp: x",
-        call: "f: f 0",
-        call_multiple_args: "f: f 0 1 2",
-        call_multiple_args_grouped: "f: (f 0) 1",
-        call_nested: "f: g: f 0 (g 0 1) 2",
-        let: "let x = 5; in x",
-        let_multiple: "let x = 5; y = 3.14; in x",
-        let_compound_key: "let x.y.z = 5; in x",
-        let_legacy: "let { x = 5; body = x; }",
-        with: "x: with x; y",
-        if: "if true then 0 else 1",
-        assert: "assert true; 0",
-        not: "!true",
-        eq: "0 == 1",
-        neq: "0 != 1",
-        and: "false && true",
-        and_assoc: "false && true && false",
-        or: "false || true",
-        or_assoc: "false || true || false",
-        impl: "false -> true",
-        impl_assoc: "false -> true -> false",
-        update: "{ x = 0; } // { x = 1; }",
-        update_assoc: "{ x = 0; } // { x = 1; } // { x = 2; }",
-        concat_lists: "[0] ++ [1]",
-        concat_lists_assoc: "[0] ++ [1] ++ [2]",
-        concat_strings: r#" "hello" + "world" "#,
-        concat_strings_assoc: r#" "hello" + "world" + "foo" "#,
-        plus: "0 + 1",
-        plus_assoc: "0 + 1 + 2",
-        minus: "0 - 1",
-        minus_assoc: "0 - 1 - 2",
-        times: "0 * 1",
-        times_assoc: "0 * 1 * 2",
-        divide: "0 / 1",
-        divide_assoc: "0 / 1 / 2",
-        less: "0 < 1",
-        less_eq: "0 <= 1",
-        greater: "0 > 1",
-        greater_eq: "0 >= 1",
-        negate: "-5",
-        math_prec: "(0 + 1 + -2 - 3) * -(4 / 5)",
-        import: "import ./foo.nix",
-        // Disabled for now
-        // or_special_handling: "[1 or 2]",
-
-        // This is a kind of sanity check relating to how the reference impl sorts attr set keys.
-        // In particular, it maintains a global set of symbols, and attributes are sorted by when
-        // their corresponding symbols were created. However, there are a bunch of built-in symbols
-        // which are created earlier than all the others: https://github.com/NixOS/nix/blob/7e23039b7f491f8517309e0c20653d6d80c37dd7/src/libexpr/eval.cc#L426-L462
-        // So, without doing anything, `outputs` would appear _before_ `description` in the below set.
-        // However, we don't actually care about attribute order in Nix, so to make things easier, we
-        // sort lexicographically on key name in both the ref impl and rnix normalization phases.
-        // So, this test verifies that both are sorting correctly despite the ref impl's default behavior.
-        attr_set_key_sorting: r#"{ description = "foo"; outputs = "bar"; a = "a"; }"#,
-        cur_pos: "__curPos",
+    test_cases::with_test_cases!(gen_tests);
+
+    #[test]
+    fn test_merge_string_fragments() {
+        let expr = serde_json::from_value(serde_json::json!({"OpConcatStrings": {"force_string": true, "es": [
+            {"String": "foo "},
+            {"String": "$"},
+            {"Var": {"name": "x", "from_with": false, "level": 0, "displ": 0}},
+            {"OpConcatStrings": {"force_string": true, "es": [{"String": "a"}, {"String": "b"}]}},
+            {"OpConcatStrings": {"force_string": false, "es": [{"String": "a"}, {"String": "b"}]}},
+        ]}}))
+        .unwrap();
+        let merged = merge_string_fragments(expr);
+
+        assert_eq!(
+            serde_json::to_value(merged).unwrap(),
+            serde_json::json!({"OpConcatStrings": {"force_string": true, "es": [
+                {"String": "foo $"},
+                {"Var": {"name": "x", "from_with": false, "level": 0, "displ": 0}},
+                {"String": "ab"},
+                {"OpConcatStrings": {"force_string": false, "es": [{"String": "a"}, {"String": "b"}]}},
+            ]}})
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let trace = get_rnix_trace(
+            &NixSource::String("(a: a - 1)".to_string()),
+            &PathContext::from_env().unwrap(),
+            &Passes::default(),
+            NixVersionProfile::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            trace,
+            indoc! {"
+                Paren <- root: discarded at 0..10
+                  Paren <- paren at 0..10
+                    Lambda <- lambda at 1..9
+                      Operator <- binop: `-` at 4..9
+                        Var <- ident at 4..5
+                        Int <- literal at 8..9
+            "}
+        );
+    }
+
+    #[test]
+    fn test_passes() {
+        let paths = PathContext::from_env().unwrap();
+
+        // Each of these has sugar which the given pass removes
+        for (pass, nix_expr) in [
+            (Pass::DesugarOperators, "1 - 2"),
+            (Pass::ResolvePaths, "<nixpkgs>"),
+            (Pass::FlattenCalls, "(a: b: a) 1 2"),
+            (Pass::ExpandAttrpaths, "{ x.y = 1; }"),
+            (Pass::MergeAttrs, "{ x = 1; }"),
+            (Pass::MergeStringFragments, r#"''foo \$ ${"b"}''"#),
+            (Pass::StripParens, "(1)"),
+        ] {
+            let mut stages = vec![];
+            get_rnix_stages(
+                &NixSource::String(nix_expr.to_string()),
+                &paths,
+                &Passes::default(),
+                NixVersionProfile::default(),
+                &mut |stage, json| stages.push((stage.to_string(), json)),
+            )
+            .unwrap();
+
+            let names: Vec<&str> = stages.iter().map(|(stage, _)| stage.as_str()).collect();
+            assert_eq!(
+                names,
+                [
+                    "lower",
+                    "desugar-operators",
+                    "resolve-paths",
+                    "flatten-calls",
+                    "expand-attrpaths",
+                    "merge-attrs",
+                    "merge-string-fragments",
+                    "strip-parens"
+                ]
+            );
+            let i = names.iter().position(|name| *name == pass.name()).unwrap();
+            assert_ne!(stages[i - 1].1, stages[i].1, "{pass} left {nix_expr} alone");
+
+            if pass.can_be_disabled() {
+                let mut passes = Passes::default();
+                passes.disable(pass);
+                assert!(
+                    matches!(
+                        check_parses_eq(
+                            NixSource::String(nix_expr.to_string()),
+                            &paths,
+                            &passes,
+                            NixVersionProfile::default()
+                        ),
+                        CheckResult::NotEqual(_)
+                    ),
+                    "{nix_expr} is the same without {pass}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_nix_version_profiles() {
+        let paths = PathContext::from_env().unwrap();
+        let rnix_json = |nix_expr: &str, passes: &Passes, profile| {
+            get_rnix_json(
+                &NixSource::String(nix_expr.to_string()),
+                &paths,
+                passes,
+                profile,
+            )
+            .unwrap()
+        };
+        let mut unflattened = Passes::default();
+        unflattened.disable(Pass::FlattenCalls);
+
+        // Nix 2.3 calls builtins with one operand at a time, and never flattens calls
+        for (nix_expr, curried) in [
+            ("a: b: a - b", "a: b: __sub a b"),
+            ("a: -a", "a: __sub 0 a"),
+            ("a: b: a >= b", "a: b: !(__lessThan a b)"),
+            ("f: (f 1) 2 3", "f: f 1 2 3"),
+            ("<foo>", r#"__findFile __nixPath "foo""#),
+        ] {
+            assert_eq!(
+                rnix_json(nix_expr, &Passes::default(), NixVersionProfile::Nix2_3),
+                rnix_json(curried, &unflattened, NixVersionProfile::Nix2_10),
+            );
+            assert_ne!(
+                rnix_json(nix_expr, &Passes::default(), NixVersionProfile::Nix2_3),
+                rnix_json(nix_expr, &Passes::default(), NixVersionProfile::Nix2_10),
+                "{nix_expr} is the same for every version"
+            );
+        }
+
+        // Builtins which Nix 2.3 doesn't have yet are free variables there
+        for nix_expr in ["__floor", "fetchTree"] {
+            let source = NixSource::String(nix_expr.to_string());
+            let rnix = |profile| get_rnix_json(&source, &paths, &Passes::default(), profile);
+            assert!(rnix(NixVersionProfile::Nix2_3).is_err());
+            assert!(rnix(NixVersionProfile::Nix2_10).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_comparator() {
+        let source = NixSource::String("[ 0.3 ]".to_string());
+        // Stands in for a reference impl which prints floats less precisely
+        let comparator = || {
+            Comparator::new(PathContext::from_env().unwrap()).reference(CustomBackend {
+                name: "imprecise".to_string(),
+                parse: Box::new(|_, _| {
+                    Ok(r#"{"List": [{"Float": 0.30000000000000004}]}"#.to_string())
+                }),
+            })
+        };
+
+        let comparison = comparator().compare(&source);
+        assert!(matches!(comparison.result, CheckResult::ValuesDiffer(_)));
+        assert_eq!(
+            comparison.candidate_json,
+            Some(serde_json::json!({"List": [{"Float": 0.3}]}))
+        );
+        assert!(comparison.timings.total() >= comparison.timings.candidate);
+
+        assert!(matches!(
+            comparator()
+                .float_policy(FloatPolicy::SignificantDigits(15))
+                .compare(&source)
+                .result,
+            CheckResult::Equal
+        ));
+        assert!(matches!(
+            comparator().ignore_path("**/Float").compare(&source).result,
+            CheckResult::Equal
+        ));
+        assert!(matches!(
+            comparator().ignore_path("/List/0").compare(&source).result,
+            CheckResult::Equal
+        ));
+        assert!(matches!(
+            comparator().ignore_path("/List/1").compare(&source).result,
+            CheckResult::ValuesDiffer(_)
+        ));
+    }
+
+    #[test]
+    fn test_compare_pairwise() {
+        let comparator = Comparator::new(PathContext::from_env().unwrap())
+            .backends(vec![
+                Box::new(RNixBackend::default()),
+                Box::new(
+                    RNixBackend::new(Passes::default(), NixVersionProfile::Nix2_3)
+                        .named("rnix-parser@2.3"),
+                ),
+                Box::new(RNixBackend::default().named("rnix-parser again")),
+            ])
+            .compare_errors(true);
+        assert_eq!(
+            comparator.backend_names(),
+            ["rnix-parser", "rnix-parser@2.3", "rnix-parser again"]
+        );
+        assert_eq!(comparator.pairs(), [(0, 1), (0, 2), (1, 2)]);
+
+        // Nix 2.3 calls `__sub` with one operand at a time
+        let pairs = comparator.compare_pairwise(&NixSource::String("a: b: a - b".to_string()));
+        assert_eq!(
+            pairs
+                .iter()
+                .map(|pair| (pair.reference, pair.candidate))
+                .collect::<Vec<_>>(),
+            comparator.pairs()
+        );
+        assert!(matches!(
+            pairs[0].comparison.result,
+            CheckResult::NotEqual(_)
+        ));
+        assert!(matches!(pairs[1].comparison.result, CheckResult::Equal));
+        assert!(matches!(
+            pairs[2].comparison.result,
+            CheckResult::NotEqual(_)
+        ));
+
+        for pair in comparator.compare_pairwise(&NixSource::String("{ a = 1; a = 2; }".to_string()))
+        {
+            assert!(matches!(
+                pair.comparison.result,
+                CheckResult::BothReject {
+                    errors: Some(ErrorComparison::Same),
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_process_backend() {
+        let paths = PathContext::from_env().unwrap();
+        let sh = |script: &str, mode| {
+            ProcessBackend::new("sh", vec!["-c".to_string(), script.to_string()], mode)
+                .timeout(Duration::from_millis(500))
+        };
+        let source = NixSource::String("1".to_string());
+
+        // Compared like any other backend, and a long-lived one answers every request
+        for candidate in [
+            sh(
+                r#"cat > /dev/null; echo '{"Int": 1}'"#,
+                ProcessMode::PerFile,
+            ),
+            sh(
+                r#"while read request; do echo '{"Int": 1}'; done"#,
+                ProcessMode::JsonLines,
+            ),
+        ] {
+            let comparator = Comparator::new(paths.clone()).candidate(candidate);
+            for _ in 0..2 {
+                assert!(matches!(
+                    comparator.compare(&source).result,
+                    CheckResult::Equal
+                ));
+            }
+        }
+
+        let rejecting = || {
+            sh(
+                r#"cat > /dev/null; echo '{"error": {"message": "nope", "category": "Syntax", "line": 1, "column": 2}}'"#,
+                ProcessMode::PerFile,
+            )
+        };
+        let err = rejecting().parse(&source, &paths).unwrap_err();
+        let err = err.downcast_ref::<ExternalParseError>().unwrap();
+        assert_eq!(err.category, Some(ErrorCategory::Syntax));
+        assert_eq!(err.location(), Some((1, 2)));
+
+        // A rejection, but not rnix-parser's
+        let comparison = Comparator::new(paths.clone())
+            .candidate(rejecting())
+            .compare(&source);
+        assert!(matches!(comparison.result, CheckResult::OnlyRNixRejects(_)));
+        assert_eq!(comparison.result.phase(), Some(Phase::ExternalParse));
+
+        let failure = |script, mode| match sh(script, mode)
+            .parse(&source, &paths)
+            .unwrap_err()
+            .downcast::<ProcessError>()
+        {
+            Ok(err) => err.failure,
+            Err(err) => panic!("not a process error: {err}"),
+        };
+        assert!(matches!(
+            failure("echo 'not json'", ProcessMode::PerFile),
+            ProcessFailure::Malformed { .. }
+        ));
+        assert!(matches!(
+            failure(r#"echo '{"Nonsense": 1}'"#, ProcessMode::JsonLines),
+            ProcessFailure::Malformed { .. }
+        ));
+        assert!(matches!(
+            failure("echo oops >&2; exit 3", ProcessMode::PerFile),
+            ProcessFailure::Exited { stderr, .. } if stderr == "oops\n"
+        ));
+        assert!(matches!(
+            failure("read request; echo oops >&2; exit 4", ProcessMode::JsonLines),
+            ProcessFailure::Exited { stderr, .. } if stderr == "oops\n"
+        ));
+        for script in ["sleep 5", "exec > /dev/null; sleep 5"] {
+            assert!(matches!(
+                failure(script, ProcessMode::PerFile),
+                ProcessFailure::TimedOut(_)
+            ));
+        }
+
+        let comparator =
+            Comparator::new(paths.clone()).candidate(sh("echo 'not json'", ProcessMode::PerFile));
+        assert_eq!(
+            comparator.compare(&source).result.phase(),
+            Some(Phase::Process)
+        );
+    }
+
+    #[test]
+    fn test_rnix_panic_is_caught() {
+        let comparator =
+            Comparator::new(PathContext::from_env().unwrap()).candidate(CustomBackend {
+                name: "panicky".to_string(),
+                parse: Box::new(|_, _| panic!("not implemented yet")),
+            });
+
+        match comparator
+            .compare(&NixSource::String("1".to_string()))
+            .result
+        {
+            CheckResult::RNixPanicked(panic) => {
+                assert!(panic.message.starts_with("not implemented yet at "));
+                assert!(!panic.backtrace.is_empty());
+            }
+            _ => panic!("the panic wasn't caught"),
+        }
+    }
+
+    #[test]
+    fn test_phases() {
+        let paths = PathContext::from_env().unwrap();
+        let phase = |comparator: Comparator, source| comparator.compare(&source).result.phase();
+
+        assert_eq!(
+            phase(
+                Comparator::new(paths.clone()),
+                NixSource::File("does/not/exist.nix".into())
+            ),
+            Some(Phase::Read)
+        );
+        assert_eq!(
+            phase(
+                Comparator::new(paths.clone()),
+                NixSource::String("1 +".to_string())
+            ),
+            Some(Phase::RNixParse)
+        );
+        assert_eq!(
+            phase(
+                Comparator::new(paths.clone()),
+                NixSource::String("{ a = 1; a = 2; }".to_string())
+            ),
+            Some(Phase::Normalize)
+        );
+        assert_eq!(
+            phase(
+                Comparator::new(paths).candidate(CustomBackend {
+                    name: "truncated".to_string(),
+                    parse: Box::new(|_, _| Ok(r#"{"Int": "#.to_string())),
+                }),
+                NixSource::String("1".to_string())
+            ),
+            Some(Phase::Compare)
+        );
+    }
+
+    #[test]
+    fn test_classify() {
+        let causes = |reference, candidate| {
+            classify(&reference, &candidate)
+                .into_iter()
+                .map(|difference| (difference.kind, difference.path))
+                .collect::<Vec<_>>()
+        };
+        let var = |name, level| serde_json::json!({"Var": {"name": name, "level": level}});
+
+        assert_eq!(
+            causes(
+                serde_json::json!({"List": [{"Float": 0.1}, {"String": "ab"}]}),
+                serde_json::json!({"List": [
+                    {"Float": 0.2},
+                    {"OpConcatStrings": {"force_string": true, "es": [{"String": "a"}, var("b", 0)]}}
+                ]})
+            ),
+            [
+                (MismatchKind::FloatFormatting, "/List/0".to_string()),
+                (MismatchKind::StringFragments, "/List/1".to_string())
+            ]
+        );
+        assert_eq!(
+            causes(
+                serde_json::json!({"Call": {"fun": var("f", 0), "args": [{"Int": 1}, {"Int": 2}]}}),
+                serde_json::json!({"Call": {
+                    "fun": {"Call": {"fun": var("f", 0), "args": [{"Int": 1}]}},
+                    "args": [{"Int": 2}]
+                }})
+            ),
+            [(MismatchKind::CallFlattening, "".to_string())]
+        );
+        assert_eq!(
+            causes(
+                serde_json::json!({"Lambda": {"arg": "x", "body": var("x", 0)}}),
+                serde_json::json!({"Lambda": {"arg": "x", "body": var("x", 1)}})
+            ),
+            [(MismatchKind::VarResolution, "/Lambda/body".to_string())]
+        );
+
+        let list = |a, b| serde_json::json!({"List": [a, b]});
+        assert!(only_values_differ(
+            &list(
+                serde_json::json!({"Int": 1}),
+                serde_json::json!({"Float": 0.5})
+            ),
+            &list(
+                serde_json::json!({"Int": 2}),
+                serde_json::json!({"Float": 0.0})
+            ),
+        ));
+        assert!(!only_values_differ(
+            &list(
+                serde_json::json!({"Int": 1}),
+                serde_json::json!({"Float": 0.5})
+            ),
+            &list(
+                serde_json::json!({"Float": 1.0}),
+                serde_json::json!({"Float": 0.5})
+            ),
+        ));
+        assert!(!only_values_differ(
+            &list(
+                serde_json::json!({"Int": 1}),
+                serde_json::json!({"String": "a"})
+            ),
+            &list(
+                serde_json::json!({"Int": 2}),
+                serde_json::json!({"String": "b"})
+            ),
+        ));
+    }
+
+    #[test]
+    fn test_minimize() {
+        // Stands in for a reference impl which gets 42 wrong
+        let comparator =
+            Comparator::new(PathContext::from_env().unwrap()).reference(CustomBackend {
+                name: "off by one".to_string(),
+                parse: Box::new(|source, paths| {
+                    get_rnix_json(
+                        source,
+                        paths,
+                        &Passes::default(),
+                        NixVersionProfile::default(),
+                    )
+                    .map(|json| json.replace(r#""Int":42"#, r#""Int":43"#))
+                }),
+            });
+
+        let source = indoc! {"
+            let
+              a = [ 1 2 { x = 42; y = 3; } ];
+              # Not needed either
+              b = a;
+            in b
+        "};
+        let minimized = minimize(source, &comparator, &mut |_| {}).unwrap();
+        assert_eq!(minimized.trim(), "42");
+
+        assert!(matches!(
+            minimize("[ 1 2 3 ]", &comparator, &mut |_| {}),
+            Err(MinimizeError::NoMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_paths_in_context() {
+        // Neither parser should care about where the tests happen to run. The home directory can't be changed, since the
+        // reference impl only takes the one its first parser was created with.
+        let paths = PathContext {
+            cwd: "/some/where/else".to_string(),
+            symlinks: SymlinkPolicy::Keep,
+            search_path: vec![],
+            ..PathContext::from_env().unwrap()
+        };
+
+        for nix_expr in [
+            "./foo",
+            "../../../../foo",
+            r#"./${"foo"}"#,
+            "~/foo",
+            "<foo>",
+            "/foo/../bar",
+        ] {
+            assert_parses_eq_in(nix_expr, &paths);
+        }
+    }
+
+    #[test]
+    fn test_compare_errors_in_file() {
+        // Locating rnix-parser's error takes reading the file again, which is relative to the context's cwd
+        let dir = std::env::temp_dir().join(format!("compare-errors-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("errors.nix"), "{\n  x = 1;\n  x = 2;\n}").unwrap();
+        let paths = PathContext {
+            cwd: dir.to_str().unwrap().to_string(),
+            ..PathContext::from_env().unwrap()
+        };
+        let rnix_error = NormalizeError::DuplicateAttr {
+            path: "x".to_string(),
+            span: Span { start: 13, end: 14 },
+            previous: Span { start: 4, end: 5 },
+        };
+        let reference_impl_error = |column| ReferenceImplError {
+            message: "attribute 'x' already defined at (string):2:3".to_string(),
+            line: Some(3),
+            column: Some(column),
+        };
+        let source = NixSource::File("errors.nix".into());
+
+        assert!(matches!(
+            compare_errors(&source, &paths, &reference_impl_error(3), &rnix_error),
+            ErrorComparison::Same
+        ));
+        assert!(matches!(
+            compare_errors(&source, &paths, &reference_impl_error(5), &rnix_error),
+            ErrorComparison::DifferentLocation {
+                reference_impl: (3, 5),
+                rnix: (3, 3)
+            }
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Expressions which rnix-parser accepts, but which the reference impl rejects while parsing.
+    #[test]
+    fn test_static_errors() {
+        let exprs = [
+            "{ x = 1; x = 2; }",
+            "{ x = 1; x.y = 2; }",
+            "{ x.y = 1; x = 2; }",
+            "{ x.y = 1; x.y = 2; }",
+            "x: { inherit x; x = 1; }",
+            "x: { inherit x; x.y = 1; }",
+            "x: { inherit x x; }",
+            "x: { x = 1; inherit (x) x; }",
+            // Attr set literals are only merged one level deep
+            "{ x = { y = { a = 1; }; }; x = { y = { b = 2; }; }; }",
+            "{ a, a }: a",
+            "{ a, b, a }: a",
+            "{ a }@a: a",
+            "x: let ${x} = 1; in 1",
+            "x: { inherit ${x}; }",
+            "x: { inherit \"${x}\"; }",
+            "x: { inherit (x) ${x}; }",
+            "x",
+            "{ a = b; }",
+            "rec { a = b; }",
+            "let a = b; in a",
+            "let inherit a; in a",
+            "rec { inherit a; }",
+            "{ a }: b",
+            "{ a ? b }: a",
+            "a: { inherit a; }.b",
+            "a: __curPos a b",
+            // Only `__curPos` as an expression is special. Inheriting it refers to a variable.
+            "{ inherit __curPos; }",
+        ];
+
+        let failures: Vec<String> = exprs
+            .into_iter()
+            .filter_map(|expr| {
+                match check_parses_eq(
+                    NixSource::String(expr.to_string()),
+                    &PathContext::from_env().unwrap(),
+                    &Passes::default(),
+                    NixVersionProfile::default(),
+                ) {
+                    CheckResult::BothReject {
+                        errors: Some(ErrorComparison::Same),
+                        ..
+                    } => None,
+                    result => Some(format!("{expr}: {result}")),
+                }
+            })
+            .collect();
+
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    /// Numeric literals that the reference impl lexes as a single int or float token, along with some near misses
+    /// which lex as something else in both parsers.
+    fn numeric_literals() -> Vec<String> {
+        let ints = [
+            "0".to_string(),
+            "00".to_string(),
+            "007".to_string(),
+            i64::MAX.to_string(),
+            (i64::MAX as u64 + 1).to_string(),
+            format!("-{}", i64::MAX as u64 + 1),
+            u64::MAX.to_string(),
+            "99999999999999999999999999999".to_string(),
+        ];
+
+        let wholes = ["", "0", "1", "10", "123"];
+        let fractions = [".", ".0", ".5", ".05"];
+        let exponents = [
+            "", "e0", "E5", "e+5", "e-0", "e308", "e309", "e-324", "e999", "e-999", "e0001",
+        ];
+        let floats = wholes
+            .iter()
+            .flat_map(|whole| fractions.iter().map(move |fraction| (whole, fraction)))
+            .flat_map(|(whole, fraction)| {
+                exponents
+                    .iter()
+                    .map(move |exponent| (whole, fraction, exponent))
+            })
+            // Nix floats either have a non-zero whole part, or a fractional part with at least one digit
+            .filter(|(whole, fraction, _)| {
+                !((whole.is_empty() || **whole == "0") && **fraction == ".")
+            })
+            .map(|(whole, fraction, exponent)| format!("{whole}{fraction}{exponent}"));
+
+        let near_misses = ["00.5", "01.5", "0.5.5"].map(String::from);
+
+        ints.into_iter().chain(floats).chain(near_misses).collect()
+    }
+
+    #[test]
+    fn test_numeric_literal_sweep() {
+        let failures: Vec<String> = numeric_literals()
+            .into_iter()
+            .filter_map(|literal| {
+                match check_parses_eq(
+                    NixSource::String(literal.clone()),
+                    &PathContext::from_env().unwrap(),
+                    &Passes::default(),
+                    NixVersionProfile::default(),
+                ) {
+                    CheckResult::Equal
+                    | CheckResult::BothReject {
+                        errors: Some(ErrorComparison::Same),
+                        ..
+                    } => None,
+                    result => Some(format!("{literal}: {result}")),
+                }
+            })
+            .collect();
+
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
+
+#[cfg(test)]
+mod generator_tests {
+    use crate::{check_parses_eq, CheckResult, NixSource};
+    use proptest::prelude::*;
+    use rnix_ast::{
+        ast::RNixExpr,
+        generator::{arb_or_expr, GeneratedExpr},
+    };
+    use rnix_normalize::{NixVersionProfile, Passes, PathContext};
+    use serde_json::Value;
+
+    /// `expr` as JSON, without any of its spans. Generated trees don't have any, so this is what's compared with them.
+    fn without_spans(expr: &RNixExpr) -> Value {
+        fn strip(value: &mut Value) {
+            match value {
+                Value::Object(fields) => {
+                    fields.remove("span");
+                    fields.values_mut().for_each(strip);
+                }
+                Value::Array(items) => items.iter_mut().for_each(strip),
+                _ => {}
+            }
+        }
+        let mut json = serde_json::to_value(expr).unwrap();
+        strip(&mut json);
+        json
+    }
+
+    /// Fail unless both parsers agree on the closed source of `generated`
+    fn assert_generated_parses_eq(generated: &GeneratedExpr) -> Result<(), TestCaseError> {
+        match check_parses_eq(
+            NixSource::String(generated.closed_source()),
+            &PathContext::from_env().unwrap(),
+            &Passes::default(),
+            NixVersionProfile::default(),
+        ) {
+            CheckResult::Equal => Ok(()),
+            result => Err(TestCaseError::fail(result.to_string())),
+        }
+    }
+
+    proptest! {
+        // Sanity check for the generator itself: rnix should parse the printed source back into the generated tree.
+        #[test]
+        fn test_generated_source_round_trips(generated: GeneratedExpr) {
+            let parsed = rnix_ast::parse(&generated.source)
+                .map_err(|err| TestCaseError::fail(err.to_string()))?;
+            prop_assert_eq!(without_spans(&parsed), without_spans(&generated.expr));
+        }
+
+        #[test]
+        fn test_generated_parses_eq(generated: GeneratedExpr) {
+            assert_generated_parses_eq(&generated)?;
+        }
+
+        #[test]
+        fn test_generated_or_source_round_trips(generated in arb_or_expr().prop_map(GeneratedExpr::from)) {
+            let parsed = rnix_ast::parse(&generated.source)
+                .map_err(|err| TestCaseError::fail(err.to_string()))?;
+            prop_assert_eq!(without_spans(&parsed), without_spans(&generated.expr));
+        }
+
+        #[test]
+        fn test_generated_or_parses_eq(generated in arb_or_expr().prop_map(GeneratedExpr::from)) {
+            assert_generated_parses_eq(&generated)?;
+        }
     }
 }
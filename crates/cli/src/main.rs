@@ -1,20 +1,36 @@
+mod cache;
+mod jobs;
+
 use std::{
-    collections::HashSet,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashSet},
     env,
     error::Error,
-    fs::File,
+    fmt,
+    fs::{self, File},
     io::{self, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 
-use clap::{clap_derive::ArgEnum, Parser, Subcommand};
+use clap::{clap_derive::ArgEnum, Args as ClapArgs, Parser, Subcommand};
 use globwalk::GlobWalkerBuilder;
 
 use cli::{
-    check_parses_eq, get_ref_impl_json, get_rnix_json, CheckResult, NixSource,
+    classify, get_ref_impl_json, get_rnix_ast_json, get_rnix_cst, get_rnix_json, get_rnix_stages,
+    get_rnix_trace, minimize, CheckResult, Comparator, Comparison, Difference, ErrorComparison,
+    MismatchKind, NixSource, Panic, ParserBackend, Phase, ProcessBackend, ProcessMode, RNixBackend,
+    ReferenceImplBackend,
+};
+use rnix_normalize::{
+    NixVersionProfile, Pass, Passes, PathContext, SearchPathEntry, SymlinkPolicy,
 };
 use serde::{Deserialize, Serialize};
 
+use cache::{Cache, Versions};
+
 /// Utility program to test/use various aspects of rnix-parser-tester
 #[derive(Parser)]
 #[clap()]
@@ -39,6 +55,34 @@ enum Commands {
         /// Which parser to use when parsing (can specify multiple!)
         #[clap(short, long, value_parser)]
         parser: Vec<ParserImpl>,
+
+        /// Also print the normalized AST from this backend (can specify multiple!), and with more than one, whether
+        /// each pair of them agrees. See `compare --backend`
+        #[clap(short, long, value_parser)]
+        backend: Vec<BackendArg>,
+
+        /// How many seconds an exec: or jsonl: backend gets to answer before it's killed
+        #[clap(long, value_parser, default_value_t = 10.0)]
+        backend_timeout: f64,
+
+        /// When dumping the rnix-parser CST, mark nodes that were ignored when converting to rnix-ast
+        #[clap(long, value_parser)]
+        mark_ignored: bool,
+
+        /// Also print which lowering rule produced each node of the rnix-parser AST before the normalization passes,
+        /// and from where in the source
+        #[clap(long, value_parser)]
+        trace: bool,
+
+        /// Also print the rnix-parser AST after lowering it, and after each normalization pass
+        #[clap(long, value_parser)]
+        stages: bool,
+
+        #[clap(flatten)]
+        paths: PathArgs,
+
+        #[clap(flatten)]
+        normalize: NormalizeArgs,
     },
     /// Report differences in serialization between the reference Nix parser and rnix-parser
     Compare {
@@ -53,6 +97,56 @@ enum Commands {
         /// Save a machine-readable summary of the comparison results to the given file
         #[clap(long, value_parser)]
         save_summary: Option<PathBuf>,
+
+        /// When both parsers reject a file, also check that they report the same kind of error at the same place
+        #[clap(long, value_parser)]
+        compare_errors: bool,
+
+        /// Compare this many files at once. Results are still printed in order, with counters of the results so far
+        /// kept up to date on stderr
+        #[clap(short, long, value_parser, default_value_t = 1)]
+        jobs: usize,
+
+        /// Reuse the results of earlier runs for files which haven't changed since, as long as the parsers and the
+        /// normalization haven't either, and keep the new results for later runs. Results are kept in the given file
+        /// [default: $XDG_CACHE_HOME/rnix-parser-tester/compare.json]
+        #[clap(long, value_parser, value_name = "CACHE_FILE")]
+        incremental: Option<Option<PathBuf>>,
+
+        /// A parser to compare (can specify multiple!): reference, rnix, rnix@<NIX_VERSION> for rnix-parser
+        /// normalized for another version of Nix, exec:<COMMAND> for a command run once per file, or jsonl:<COMMAND>
+        /// for a long-lived command which takes a request per line. With more than two, each is compared with each of
+        /// the others [default: reference and rnix]
+        #[clap(short, long, value_parser)]
+        backend: Vec<BackendArg>,
+
+        /// How many seconds an exec: or jsonl: backend gets to answer for each file before it's killed
+        #[clap(long, value_parser, default_value_t = 10.0)]
+        backend_timeout: f64,
+
+        #[clap(flatten)]
+        paths: PathArgs,
+
+        #[clap(flatten)]
+        normalize: NormalizeArgs,
+    },
+    /// Shrink a Nix expression that the parsers disagree on to the smallest one that they still disagree on in the same
+    /// way, and print it
+    Minimize {
+        /// The Nix file to minimize. If not given, will read from stdin. Relative paths in it stay relative to its
+        /// directory
+        #[clap(value_parser)]
+        file: Option<String>,
+
+        /// Print each smaller expression as it's found on stderr, not just its size
+        #[clap(short, long, value_parser)]
+        verbose: bool,
+
+        #[clap(flatten)]
+        paths: PathArgs,
+
+        #[clap(flatten)]
+        normalize: NormalizeArgs,
     },
     /// Perform analysis of summaries generated by the compare subcommand
     Summary {
@@ -64,10 +158,168 @@ enum Commands {
     },
 }
 
+/// Where paths in the parsed expressions are relative to
+#[derive(ClapArgs)]
+struct PathArgs {
+    /// The directory that relative paths are relative to when reading from stdin, and that relative file names are
+    /// relative to. Has to be absolute [default: the current directory]
+    #[clap(long, value_parser)]
+    cwd: Option<String>,
+
+    /// The directory that `~` expands to [default: $HOME]
+    #[clap(long, value_parser)]
+    home: Option<String>,
+
+    /// Resolve symlinks in the names of files before parsing them
+    #[clap(long, value_parser)]
+    resolve_symlinks: bool,
+
+    /// Add an entry (`path` or `prefix=path`) to the front of the search path used to find `<...>` files, like `nix -I`.
+    /// The rest of the search path comes from NIX_PATH
+    #[clap(short = 'I', long = "include", value_parser)]
+    include: Vec<String>,
+}
+
+impl PathArgs {
+    fn into_context(self) -> Result<PathContext, Box<dyn Error>> {
+        let home = match self.home {
+            Some(home) => home,
+            None => env::var("HOME").map_err(|_| {
+                AppError::UsageError("HOME isn't set. Pass --home instead.".to_string())
+            })?,
+        };
+        let cwd = match self.cwd {
+            Some(cwd) if !Path::new(&cwd).is_absolute() => {
+                return Err(
+                    AppError::UsageError(format!("--cwd has to be absolute, not {cwd}")).into(),
+                )
+            }
+            Some(cwd) => cwd,
+            None => PathContext::current_dir()?,
+        };
+
+        let mut search_path: Vec<SearchPathEntry> = self
+            .include
+            .iter()
+            .map(|entry| SearchPathEntry::parse(entry))
+            .collect();
+        search_path.extend(PathContext::search_path_from_env());
+
+        Ok(PathContext {
+            cwd,
+            home,
+            symlinks: if self.resolve_symlinks {
+                SymlinkPolicy::Resolve
+            } else {
+                SymlinkPolicy::Keep
+            },
+            search_path,
+        })
+    }
+}
+
+/// How to normalize the rnix-parser AST
+#[derive(ClapArgs)]
+struct NormalizeArgs {
+    /// Switch off a normalization pass, leaving its sugar in the rnix-parser AST (can specify multiple!). One of
+    /// desugar-operators, resolve-paths, flatten-calls, merge-attrs, merge-string-fragments, or strip-parens
+    #[clap(long = "disable-pass", value_parser)]
+    disable_pass: Vec<Pass>,
+
+    /// Desugar the rnix-parser AST like this version of Nix does. One of 2.3, or 2.10 (which the reference impl is built
+    /// against, and which later versions desugar the same as)
+    #[clap(long, value_parser, default_value_t)]
+    nix_version: NixVersionProfile,
+}
+
+impl NormalizeArgs {
+    fn into_passes(self) -> Result<(Passes, NixVersionProfile), AppError> {
+        let mut passes = Passes::default();
+        for pass in self.disable_pass {
+            if !pass.can_be_disabled() {
+                return Err(AppError::UsageError(format!(
+                    "switching off {pass} changes nothing, since merge-attrs expands attr paths itself (switch off \
+                     merge-attrs to see them expanded, but not merged)"
+                )));
+            }
+            passes.disable(pass);
+        }
+        Ok((passes, self.nix_version))
+    }
+}
+
+/// A backend for `--backend`
+#[derive(Clone)]
+enum BackendArg {
+    Reference,
+    /// rnix-parser, normalized as the other options say, or for another version of Nix
+    RNix(Option<NixVersionProfile>),
+    /// An external command, split on whitespace into the program and its arguments
+    Process(ProcessMode, String),
+}
+
+impl BackendArg {
+    fn to_backend(
+        &self,
+        passes: &Passes,
+        profile: NixVersionProfile,
+        timeout: Duration,
+    ) -> Box<dyn ParserBackend> {
+        match self {
+            BackendArg::Reference => Box::new(ReferenceImplBackend),
+            BackendArg::RNix(None) => Box::new(RNixBackend::new(passes.clone(), profile)),
+            BackendArg::RNix(Some(profile)) => Box::new(
+                RNixBackend::new(passes.clone(), *profile).named(format!("rnix-parser@{profile}")),
+            ),
+            BackendArg::Process(mode, command) => {
+                let mut words = command.split_whitespace().map(str::to_string);
+                let program = words.next().expect("checked when parsing the argument");
+                Box::new(ProcessBackend::new(program, words.collect(), *mode).timeout(timeout))
+            }
+        }
+    }
+}
+
+impl FromStr for BackendArg {
+    type Err = AppError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        for (prefix, mode) in [
+            ("exec:", ProcessMode::PerFile),
+            ("jsonl:", ProcessMode::JsonLines),
+        ] {
+            if let Some(command) = name.strip_prefix(prefix) {
+                if command.trim().is_empty() {
+                    return Err(AppError::UsageError(format!(
+                        "backend '{name}' is missing its command"
+                    )));
+                }
+                return Ok(BackendArg::Process(mode, command.to_string()));
+            }
+        }
+
+        match name.split_once('@') {
+            None if name == "reference" => Ok(BackendArg::Reference),
+            None if name == "rnix" => Ok(BackendArg::RNix(None)),
+            Some(("rnix", version)) => version
+                .parse()
+                .map(|profile| BackendArg::RNix(Some(profile)))
+                .map_err(|err| AppError::UsageError(format!("{err}"))),
+            _ => Err(AppError::UsageError(format!(
+                "unknown backend '{name}' (expected reference, rnix, rnix@<NIX_VERSION>, exec:<COMMAND>, or jsonl:<COMMAND>)"
+            ))),
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
 enum ParserImpl {
     Reference,
     Rnix,
+    /// rnix-parser, but before normalization
+    RnixAst,
+    /// rnix-parser's raw rowan CST
+    RnixCst,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -77,67 +329,260 @@ fn main() -> Result<(), Box<dyn Error>> {
         Commands::Dump {
             file,
             parser,
+            backend,
+            backend_timeout,
             recursive,
+            mark_ignored,
+            trace,
+            stages,
+            paths,
+            normalize,
         } => {
-            for (file, input) in walk(file, recursive)? {
-                dump(file, input, &parser)?;
+            let paths = paths.into_context()?;
+            let (passes, profile) = normalize.into_passes()?;
+            let timeout = timeout(backend_timeout)?;
+
+            // Made once for every file, so that long-lived backends stay alive between them
+            let backends: Vec<Arc<dyn ParserBackend>> = backend
+                .iter()
+                .map(|backend| Arc::from(backend.to_backend(&passes, profile, timeout)))
+                .collect();
+            let comparator = (backends.len() > 1).then(|| {
+                Comparator::new(paths.clone())
+                    .backends(
+                        backends
+                            .iter()
+                            .map(|backend| Box::new(backend.clone()) as Box<dyn ParserBackend>)
+                            .collect(),
+                    )
+                    .merge_string_fragments(passes.is_enabled(Pass::MergeStringFragments))
+            });
+
+            for (file, input) in walk(file, recursive, &paths)? {
+                dump(
+                    file,
+                    input,
+                    &parser,
+                    &backends,
+                    comparator.as_ref(),
+                    mark_ignored,
+                    trace,
+                    stages,
+                    &paths,
+                    &passes,
+                    profile,
+                )?;
             }
         }
         Commands::Compare {
             file,
             recursive,
             save_summary,
+            compare_errors,
+            jobs,
+            incremental,
+            backend,
+            backend_timeout,
+            paths,
+            normalize,
         } => {
-            let mut equal = HashSet::new();
-            let mut not_equal = HashSet::new();
-            let mut reference_impl_error = HashSet::new();
-            let mut rnix_error = HashSet::new();
-
-            for (file, input) in walk(file, recursive)? {
-                print!("{file} ... ");
-                io::stdout().flush()?;
-
-                match check_parses_eq(input) {
-                    CheckResult::Equal => {
-                        println!("\x1b[32mequal\x1b[0m");
-                        if save_summary.is_some() {
-                            equal.insert(file);
-                        }
-                    }
-                    CheckResult::NotEqual(_) => {
-                        println!("\x1b[31mNOT EQUAL\x1b[0m");
+            let paths = paths.into_context()?;
+            let (passes, profile) = normalize.into_passes()?;
+
+            let backends = if backend.is_empty() {
+                vec![BackendArg::Reference, BackendArg::RNix(None)]
+            } else {
+                backend
+            };
+            if backends.len() < 2 {
+                return Err(AppError::UsageError(
+                    "Comparing takes at least two backends".to_string(),
+                )
+                .into());
+            }
+            if backends.len() > 2 && save_summary.is_some() {
+                return Err(AppError::UsageError(
+                    "A summary is of two backends. Can't save one for more.".to_string(),
+                )
+                .into());
+            }
+            let timeout = timeout(backend_timeout)?;
+            let comparator = Comparator::new(paths.clone())
+                .backends(
+                    backends
+                        .iter()
+                        .map(|backend| backend.to_backend(&passes, profile, timeout))
+                        .collect(),
+                )
+                .merge_string_fragments(passes.is_enabled(Pass::MergeStringFragments))
+                .compare_errors(compare_errors);
+            let pairs = pair_names(&comparator);
+
+            let cache = match incremental {
+                Some(cache_file) => {
+                    let cache_file = match cache_file {
+                        Some(cache_file) => cache_file,
+                        None => Cache::default_file()?,
+                    };
+                    let versions = Versions::new(
+                        &passes,
+                        profile,
+                        &paths,
+                        &comparator.backend_names(),
+                        compare_errors,
+                    )?;
+                    let cache = Cache::load(&cache_file, versions)?;
+                    Some((cache_file, cache))
+                }
+                None => None,
+            };
+
+            let check = |input: &NixSource| {
+                let key = cache.as_ref().and_then(|_| Cache::key(input, &paths));
+                let cached = cache
+                    .as_ref()
+                    .zip(key.as_ref())
+                    .and_then(|((_, cache), key)| cache.get(key));
+                match cached {
+                    Some(outcomes) => Checked {
+                        outcomes: outcomes.clone(),
+                        cached: true,
+                        key: None,
+                    },
+                    None => Checked {
+                        outcomes: comparator
+                            .compare_pairwise(input)
+                            .into_iter()
+                            .map(|pair| Outcome::from(pair.comparison))
+                            .collect(),
+                        cached: false,
+                        key,
+                    },
+                }
+            };
+
+            let files: Vec<(String, NixSource)> = walk(file, recursive, &paths)?.collect();
+            let mut summary = Summary::default();
+            // New results, to be cached once they're all in
+            let mut fresh = vec![];
+            let mut causes = Causes::default();
+            // How each pair of backends compared, for when there are several
+            let mut agreement: Vec<Counters> = pairs.iter().map(|_| Counters::default()).collect();
+
+            if jobs > 1 {
+                // Both callbacks redraw the counters
+                let counters = RefCell::new(Counters::default());
+                jobs::for_each_in_parallel(
+                    &files,
+                    jobs,
+                    |(_, input)| check(input),
+                    |checked| {
+                        let mut counters = counters.borrow_mut();
+                        checked
+                            .outcomes
+                            .iter()
+                            .for_each(|outcome| counters.add(outcome));
+                        counters.draw(files.len() * pairs.len());
+                    },
+                    |i, checked| -> io::Result<()> {
+                        let file = &files[i].0;
+                        Counters::clear();
+                        println!("{file} ... {}", checked.describe(compare_errors, &pairs));
+                        counters.borrow().draw(files.len() * pairs.len());
                         if save_summary.is_some() {
-                            not_equal.insert(file);
+                            summary.add(file, &checked.outcomes[0], compare_errors);
                         }
-                    }
-                    CheckResult::ReferenceImplError(_) => {
-                        println!("\x1b[33mreference impl error\x1b[0m");
-                        if save_summary.is_some() {
-                            reference_impl_error.insert(file);
+                        causes.add(file, &checked.outcomes);
+                        for (counters, outcome) in agreement.iter_mut().zip(&checked.outcomes) {
+                            counters.add(outcome);
                         }
+                        fresh.extend(checked.into_fresh());
+                        Ok(())
+                    },
+                )?;
+                Counters::clear();
+            } else {
+                for (file, input) in &files {
+                    print!("{file} ... ");
+                    io::stdout().flush()?;
+
+                    let checked = check(input);
+                    println!("{}", checked.describe(compare_errors, &pairs));
+                    if save_summary.is_some() {
+                        summary.add(file, &checked.outcomes[0], compare_errors);
                     }
-                    CheckResult::RNixError(_) => {
-                        println!("\x1b[33mrnix-parser error\x1b[0m");
-                        if save_summary.is_some() {
-                            rnix_error.insert(file);
-                        }
+                    causes.add(file, &checked.outcomes);
+                    for (counters, outcome) in agreement.iter_mut().zip(&checked.outcomes) {
+                        counters.add(outcome);
                     }
+                    fresh.extend(checked.into_fresh());
+                }
+            }
+
+            if !causes.0.is_empty() {
+                println!();
+                causes.print();
+            }
+
+            if pairs.len() > 1 {
+                println!();
+                println!("== Agreement between each pair of backends ==");
+                for (pair, counters) in pairs.iter().zip(&agreement) {
+                    println!("{pair}: {counters}");
+                }
+            }
+
+            if let Some((cache_file, mut cache)) = cache {
+                for (key, outcomes) in fresh {
+                    cache.insert(key, outcomes);
                 }
+                cache.save(&cache_file)?;
             }
 
             match save_summary {
-                Some(summary_file) => serde_json::to_writer_pretty(
-                    File::create(summary_file)?,
-                    &Summary {
-                        equal,
-                        not_equal,
-                        reference_impl_error,
-                        rnix_error,
-                    },
-                )?,
+                Some(summary_file) => {
+                    serde_json::to_writer_pretty(File::create(summary_file)?, &summary)?
+                }
                 None => {}
             }
         }
+        Commands::Minimize {
+            file,
+            verbose,
+            paths,
+            normalize: normalize_args,
+        } => {
+            let mut paths = paths.into_context()?;
+            let (passes, profile) = normalize_args.into_passes()?;
+
+            // The smaller expressions don't live in the file, so parse them all as strings, but from its directory
+            let source = match file {
+                Some(file) => {
+                    let file = PathBuf::from(paths.resolve_file(&normalize(file, &paths)?)?);
+                    if let Some(dir) = file.parent() {
+                        paths.cwd = dir.display().to_string();
+                    }
+                    fs::read_to_string(file)?
+                }
+                None => read_stdin()?,
+            };
+
+            let comparator = Comparator::new(paths)
+                .merge_string_fragments(passes.is_enabled(Pass::MergeStringFragments))
+                .candidate(RNixBackend::new(passes, profile));
+            let minimized = minimize(&source, &comparator, &mut |smaller| {
+                if verbose {
+                    eprintln!("{smaller}\n");
+                } else {
+                    Counters::clear();
+                    eprint!("{} bytes", smaller.len());
+                }
+            })?;
+            if !verbose {
+                Counters::clear();
+            }
+            println!("{minimized}");
+        }
         Commands::Summary {
             summary_before,
             summary_after,
@@ -145,8 +590,18 @@ fn main() -> Result<(), Box<dyn Error>> {
             let summary_before: Summary = serde_json::from_reader(File::open(summary_before)?)?;
             let summary_after: Summary = serde_json::from_reader(File::open(summary_after)?)?;
 
-            let progressions = summary_before.not_equal.intersection(&summary_after.equal);
-            let regressions = summary_before.equal.intersection(&summary_after.not_equal);
+            // Files whose values differ aren't equal either
+            let not_equal = |summary: &Summary| -> HashSet<String> {
+                summary
+                    .not_equal
+                    .union(&summary.values_differ)
+                    .cloned()
+                    .collect()
+            };
+            let (not_equal_before, not_equal_after) =
+                (not_equal(&summary_before), not_equal(&summary_after));
+            let progressions = not_equal_before.intersection(&summary_after.equal);
+            let regressions = summary_before.equal.intersection(&not_equal_after);
 
             let mut num_progressions = 0;
             let mut num_regressions = 0;
@@ -168,27 +623,9 @@ fn main() -> Result<(), Box<dyn Error>> {
             println!();
 
             println!("== Summary ==");
-            println!("# equal before: {}", summary_before.equal.len());
-            println!("# not equal before: {}", summary_before.not_equal.len());
-            println!(
-                "# reference impl errors before: {}",
-                summary_before.reference_impl_error.len()
-            );
-            println!(
-                "# rnix-parser errors before: {}",
-                summary_before.rnix_error.len()
-            );
+            print_summary_counts(&summary_before, "before");
             println!();
-            println!("# equal after: {}", summary_after.equal.len());
-            println!("# not equal after: {}", summary_after.not_equal.len());
-            println!(
-                "# reference impl errors after: {}",
-                summary_after.reference_impl_error.len()
-            );
-            println!(
-                "# rnix-parser errors after: {}",
-                summary_after.rnix_error.len()
-            );
+            print_summary_counts(&summary_after, "after");
             println!();
             println!("# progressions: {num_progressions}");
             println!("# regressions: {num_regressions}");
@@ -198,12 +635,46 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn print_summary_counts(summary: &Summary, when: &str) {
+    println!("# equal {when}: {}", summary.equal.len());
+    println!("# not equal {when}: {}", summary.not_equal.len());
+    println!("# values differ {when}: {}", summary.values_differ.len());
+    println!("# both reject {when}: {}", summary.both_reject.len());
+    println!(
+        "# both reject, with different errors {when}: {}",
+        summary.both_reject_differently.len()
+    );
+    println!(
+        "# only reference impl rejects {when}: {}",
+        summary.only_reference_impl_rejects.len()
+    );
+    println!(
+        "# only rnix-parser rejects {when}: {}",
+        summary.only_rnix_rejects.len()
+    );
+    println!(
+        "# rnix-parser panicked {when}: {}",
+        summary.rnix_panicked.len()
+    );
+    println!("# failed {when}: {}", summary.failed.len());
+    for phase in Phase::ALL {
+        println!(
+            "#   decided in {phase} {when}: {}",
+            summary.phases.get(&phase).map_or(0, HashSet::len)
+        );
+    }
+}
+
 type WalkIter = Box<dyn Iterator<Item = (String, NixSource)>>;
 
-fn walk(file: Option<String>, recursive: bool) -> Result<WalkIter, Box<dyn Error>> {
+fn walk(
+    file: Option<String>,
+    recursive: bool,
+    paths: &PathContext,
+) -> Result<WalkIter, Box<dyn Error>> {
     match file {
         Some(file) => {
-            let file = normalize(file)?;
+            let file = normalize(file, paths)?;
 
             if recursive && !file.is_dir() {
                 return Err(AppError::UsageError(format!(
@@ -240,46 +711,97 @@ fn walk(file: Option<String>, recursive: bool) -> Result<WalkIter, Box<dyn Error
     }
 }
 
-fn normalize(file: String) -> Result<PathBuf, Box<dyn Error>> {
-    if let Some(file) = file.strip_prefix('<') {
-        if let Some(file) = file.strip_suffix('>') {
-            let mut path = path_to_nixpkgs()?;
-            path.push(file);
-            return Ok(path);
+fn normalize(file: String, paths: &PathContext) -> Result<PathBuf, Box<dyn Error>> {
+    if let Some(name) = file.strip_prefix('<') {
+        if let Some(name) = name.strip_suffix('>') {
+            return Ok(paths
+                .find_file(name)
+                .ok_or_else(|| AppError::NotInSearchPath(name.to_string()))?);
         }
     }
 
     Ok(PathBuf::from(file))
 }
 
-fn path_to_nixpkgs() -> Result<PathBuf, Box<dyn Error>> {
-    let path = env::var("NIX_PATH")?;
-    let nixpkgs = path
-        .split(':')
-        .find(|s| s.starts_with("nixpkgs="))
-        .ok_or(AppError::CantFindNixpkgs)?;
-
-    Ok(PathBuf::from(&nixpkgs["nixpkgs=".len()..]))
-}
-
-fn dump(filename: String, input: NixSource, parser: &[ParserImpl]) -> Result<(), Box<dyn Error>> {
+#[allow(clippy::too_many_arguments)]
+fn dump(
+    filename: String,
+    input: NixSource,
+    parser: &[ParserImpl],
+    backends: &[Arc<dyn ParserBackend>],
+    comparator: Option<&Comparator>,
+    mark_ignored: bool,
+    trace: bool,
+    stages: bool,
+    paths: &PathContext,
+    passes: &Passes,
+    profile: NixVersionProfile,
+) -> Result<(), Box<dyn Error>> {
     println!("{filename} ...");
 
     if parser.contains(&ParserImpl::Reference) {
         println!("==== Reference impl json ====");
-        println!("{}", get_ref_impl_json(&input)?);
+        println!("{}", get_ref_impl_json(&input, paths)?);
         println!();
     }
 
     if parser.contains(&ParserImpl::Rnix) {
         println!("==== rnix-parser json ====");
-        println!("{}", get_rnix_json(&input)?);
+        println!("{}", get_rnix_json(&input, paths, passes, profile)?);
+        println!();
+    }
+
+    for backend in backends {
+        println!("==== {} json ====", backend.name());
+        println!("{}", serde_json::to_string(&backend.parse(&input, paths)?)?);
+        println!();
+    }
+
+    // Set when there's more than one backend
+    if let Some(comparator) = comparator {
+        let names = pair_names(comparator);
+        println!("==== Agreement ====");
+        for (pair, name) in comparator.compare_pairwise(&input).into_iter().zip(names) {
+            println!("{name}: {}", Outcome::from(pair.comparison).describe(true));
+        }
+        println!();
+    }
+
+    if trace {
+        println!("==== rnix-parser lowering trace ====");
+        print!("{}", get_rnix_trace(&input, paths, passes, profile)?);
+        println!();
+    }
+
+    if stages {
+        get_rnix_stages(&input, paths, passes, profile, &mut |stage, json| {
+            println!("==== rnix-parser json after {stage} ====");
+            println!("{json}");
+            println!();
+        })?;
+    }
+
+    if parser.contains(&ParserImpl::RnixAst) {
+        println!("==== rnix-parser AST json (before normalization) ====");
+        println!("{}", get_rnix_ast_json(&input, paths)?);
+        println!();
+    }
+
+    if parser.contains(&ParserImpl::RnixCst) {
+        println!("==== rnix-parser CST ====");
+        print!("{}", get_rnix_cst(&input, paths, mark_ignored)?);
         println!();
     }
 
     Ok(())
 }
 
+/// The `--backend-timeout`, which is in seconds
+fn timeout(seconds: f64) -> Result<Duration, AppError> {
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| AppError::UsageError(format!("{seconds} isn't a valid number of seconds")))
+}
+
 fn read_stdin() -> Result<String, io::Error> {
     let mut input = String::new();
     io::stdin().read_to_string(&mut input)?;
@@ -289,24 +811,321 @@ fn read_stdin() -> Result<String, io::Error> {
 #[derive(Debug)]
 enum AppError {
     UsageError(String),
-    CantFindNixpkgs,
+    NotInSearchPath(String),
 }
 
 impl std::fmt::Display for AppError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             AppError::UsageError(err) => write!(f, "{err}"),
-            AppError::CantFindNixpkgs => write!(f, "Can't find nixpkgs"),
+            AppError::NotInSearchPath(name) => write!(f, "Can't find <{name}> in the search path"),
         }
     }
 }
 
 impl std::error::Error for AppError {}
 
-#[derive(Deserialize, Serialize)]
+/// What comparing a file came to. Unlike [`CheckResult`], this can be sent between threads, and cached.
+#[derive(Clone, Deserialize, Serialize)]
+enum Outcome {
+    Equal,
+    /// Along with where the ASTs differ, and why
+    NotEqual(Vec<Difference>),
+    ValuesDiffer,
+    /// Along with how the errors compare, if they were compared, and the phase in which rnix-parser rejected it
+    BothReject(Option<ErrorComparison>, Phase),
+    OnlyReferenceImplRejects,
+    OnlyRNixRejects(Phase),
+    RNixPanicked(Panic),
+    Failed {
+        phase: Phase,
+        message: String,
+    },
+}
+
+impl From<Comparison> for Outcome {
+    fn from(comparison: Comparison) -> Self {
+        let phase = comparison.result.phase();
+        match comparison.result {
+            CheckResult::Equal => Outcome::Equal,
+            CheckResult::NotEqual(_) => {
+                let differences = match (&comparison.reference_json, &comparison.candidate_json) {
+                    (Some(reference_json), Some(candidate_json)) => {
+                        classify(reference_json, candidate_json)
+                    }
+                    _ => vec![],
+                };
+                Outcome::NotEqual(differences)
+            }
+            CheckResult::ValuesDiffer(_) => Outcome::ValuesDiffer,
+            CheckResult::BothReject { errors, .. } => Outcome::BothReject(errors, phase.unwrap()),
+            CheckResult::OnlyReferenceImplRejects(_) => Outcome::OnlyReferenceImplRejects,
+            CheckResult::OnlyRNixRejects(_) => Outcome::OnlyRNixRejects(phase.unwrap()),
+            CheckResult::RNixPanicked(panic) => Outcome::RNixPanicked(panic),
+            CheckResult::Failed { phase, error } => Outcome::Failed {
+                phase,
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+impl Outcome {
+    /// The phase whose error decided the outcome. See [`CheckResult::phase`].
+    fn phase(&self) -> Option<Phase> {
+        match self {
+            Outcome::Equal
+            | Outcome::NotEqual(_)
+            | Outcome::ValuesDiffer
+            | Outcome::RNixPanicked(_) => None,
+            Outcome::BothReject(_, phase) | Outcome::OnlyRNixRejects(phase) => Some(*phase),
+            Outcome::OnlyReferenceImplRejects => Some(Phase::ReferenceParse),
+            Outcome::Failed { phase, .. } => Some(*phase),
+        }
+    }
+
+    /// The outcome as printed by `compare`, in color
+    fn describe(&self, compare_errors: bool) -> String {
+        match self {
+            Outcome::Equal => "\x1b[32mequal\x1b[0m".to_string(),
+            Outcome::NotEqual(differences) => format!(
+                "\x1b[31mNOT EQUAL\x1b[0m ({})",
+                differences
+                    .iter()
+                    .map(|difference| difference.kind)
+                    .collect::<BTreeSet<MismatchKind>>()
+                    .iter()
+                    .map(|cause| cause.name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Outcome::ValuesDiffer => "\x1b[31mVALUES DIFFER\x1b[0m".to_string(),
+            Outcome::BothReject(Some(errors), _)
+                if compare_errors && !matches!(errors, ErrorComparison::Same) =>
+            {
+                format!("\x1b[33mboth reject, with {errors}\x1b[0m")
+            }
+            Outcome::BothReject(_, _) => "\x1b[32mboth reject\x1b[0m".to_string(),
+            Outcome::OnlyReferenceImplRejects => {
+                "\x1b[31mONLY REFERENCE IMPL REJECTS\x1b[0m".to_string()
+            }
+            Outcome::OnlyRNixRejects(phase) => {
+                format!("\x1b[31mONLY RNIX-PARSER REJECTS\x1b[0m (in {phase})")
+            }
+            Outcome::RNixPanicked(Panic { message, backtrace }) => {
+                format!("\x1b[31mRNIX-PARSER PANICKED\x1b[0m: {message}\n{backtrace}")
+            }
+            Outcome::Failed { phase, message } => {
+                format!("\x1b[31mFAILED\x1b[0m (in {phase}): {message}")
+            }
+        }
+    }
+}
+
+/// A file's outcomes, one for each pair of backends, which are either new or from the cache of
+/// `compare --incremental`
+struct Checked {
+    outcomes: Vec<Outcome>,
+    cached: bool,
+    /// What to cache new outcomes under. Only set with `--incremental`.
+    key: Option<String>,
+}
+
+impl Checked {
+    /// The outcomes as printed by `compare`. With several pairs of backends, each goes on a line of its own, after its
+    /// name in `pairs`.
+    fn describe(&self, compare_errors: bool, pairs: &[String]) -> String {
+        let outcomes = match &self.outcomes[..] {
+            [outcome] => outcome.describe(compare_errors),
+            outcomes => outcomes
+                .iter()
+                .zip(pairs)
+                .map(|(outcome, pair)| format!("\n  {pair}: {}", outcome.describe(compare_errors)))
+                .collect(),
+        };
+        if self.cached {
+            format!("{outcomes} (cached)")
+        } else {
+            outcomes
+        }
+    }
+
+    /// The new outcomes, with their key, if they should be cached. Failures aren't, since they're down to the
+    /// environment (e.g. an unreadable file, or an external backend timing out) rather than to the file's content.
+    fn into_fresh(self) -> Option<(String, Vec<Outcome>)> {
+        if self
+            .outcomes
+            .iter()
+            .any(|outcome| matches!(outcome, Outcome::Failed { .. }))
+        {
+            return None;
+        }
+        Some((self.key?, self.outcomes))
+    }
+}
+
+/// The name of each pair of the comparator's backends, e.g. `reference impl vs. rnix-parser`
+fn pair_names(comparator: &Comparator) -> Vec<String> {
+    let names = comparator.backend_names();
+    comparator
+        .pairs()
+        .into_iter()
+        .map(|(reference, candidate)| format!("{} vs. {}", names[reference], names[candidate]))
+        .collect()
+}
+
+/// How many of the files which weren't equal had each kind of difference, by [`Difference::signature`], and some of
+/// them as examples
+#[derive(Default)]
+struct Causes(BTreeMap<String, (usize, Vec<String>)>);
+
+impl Causes {
+    const EXAMPLES: usize = 3;
+
+    /// Count the differences in `file`'s outcomes, which are for each pair of backends. A signature counts once per
+    /// file.
+    fn add(&mut self, file: &str, outcomes: &[Outcome]) {
+        let causes: BTreeSet<String> = outcomes
+            .iter()
+            .flat_map(|outcome| match outcome {
+                Outcome::NotEqual(differences) => differences.as_slice(),
+                _ => [].as_slice(),
+            })
+            .map(Difference::signature)
+            .collect();
+        for cause in causes {
+            let (count, examples) = self.0.entry(cause).or_default();
+            *count += 1;
+            if examples.len() < Self::EXAMPLES {
+                examples.push(file.to_string());
+            }
+        }
+    }
+
+    fn print(&self) {
+        println!("== Likely causes of the files which aren't equal ==");
+        println!(
+            "{:<56} {:>6}  examples",
+            "cause (reference vs. candidate node, pass involved)", "files"
+        );
+        for (cause, (count, examples)) in &self.0 {
+            println!("{cause:<56} {count:>6}  {}", examples.join(", "));
+        }
+    }
+}
+
+/// How many files have had each outcome, e.g. so far, for `compare --jobs`
+#[derive(Default)]
+struct Counters {
+    equal: usize,
+    not_equal: usize,
+    values_differ: usize,
+    both_reject: usize,
+    only_reference_impl_rejects: usize,
+    only_rnix_rejects: usize,
+    rnix_panicked: usize,
+    failed: usize,
+}
+
+impl Counters {
+    fn add(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Equal => self.equal += 1,
+            Outcome::NotEqual(_) => self.not_equal += 1,
+            Outcome::ValuesDiffer => self.values_differ += 1,
+            Outcome::BothReject(..) => self.both_reject += 1,
+            Outcome::OnlyReferenceImplRejects => self.only_reference_impl_rejects += 1,
+            Outcome::OnlyRNixRejects(_) => self.only_rnix_rejects += 1,
+            Outcome::RNixPanicked(_) => self.rnix_panicked += 1,
+            Outcome::Failed { .. } => self.failed += 1,
+        }
+    }
+
+    /// Overwrite the current line of stderr with the counters
+    fn draw(&self, total: usize) {
+        let done = self.equal
+            + self.not_equal
+            + self.values_differ
+            + self.both_reject
+            + self.only_reference_impl_rejects
+            + self.only_rnix_rejects
+            + self.rnix_panicked
+            + self.failed;
+        eprint!("\r\x1b[K[{done}/{total}] {self}");
+    }
+
+    /// Erase the counters, so that something else can be printed in their place
+    fn clear() {
+        eprint!("\r\x1b[K");
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "equal: {}, not equal: {}, values differ: {}, both reject: {}, only reference impl rejects: {}, only rnix-parser rejects: {}, rnix-parser panicked: {}, failed: {}",
+            self.equal,
+            self.not_equal,
+            self.values_differ,
+            self.both_reject,
+            self.only_reference_impl_rejects,
+            self.only_rnix_rejects,
+            self.rnix_panicked,
+            self.failed
+        )
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
 struct Summary {
     equal: HashSet<String>,
     not_equal: HashSet<String>,
-    reference_impl_error: HashSet<String>,
-    rnix_error: HashSet<String>,
+    // Summaries saved before these existed don't have them
+    #[serde(default)]
+    values_differ: HashSet<String>,
+    #[serde(default)]
+    both_reject: HashSet<String>,
+    /// The subset of `both_reject` where the errors differ. Only tracked with `--compare-errors`.
+    #[serde(default)]
+    both_reject_differently: HashSet<String>,
+    // Older summaries didn't run rnix-parser when the reference impl failed, so the closest thing they have to "only
+    // the reference impl rejects" is "the reference impl rejects"
+    #[serde(alias = "reference_impl_error")]
+    only_reference_impl_rejects: HashSet<String>,
+    #[serde(alias = "rnix_error")]
+    only_rnix_rejects: HashSet<String>,
+    #[serde(default)]
+    rnix_panicked: HashSet<String>,
+    /// Files where a phase which isn't either parser's failed
+    #[serde(default)]
+    failed: HashSet<String>,
+    /// Every file whose result was decided by an error, by the phase it came from. For files which both parsers
+    /// reject, that's rnix-parser's phase.
+    #[serde(default)]
+    phases: BTreeMap<Phase, HashSet<String>>,
+}
+
+impl Summary {
+    fn add(&mut self, file: &str, outcome: &Outcome, compare_errors: bool) {
+        let file = file.to_string();
+        if let Some(phase) = outcome.phase() {
+            self.phases.entry(phase).or_default().insert(file.clone());
+        }
+        match outcome {
+            Outcome::Equal => self.equal.insert(file),
+            Outcome::NotEqual(_) => self.not_equal.insert(file),
+            Outcome::ValuesDiffer => self.values_differ.insert(file),
+            Outcome::BothReject(errors, _) => {
+                if compare_errors && !matches!(errors, None | Some(ErrorComparison::Same)) {
+                    self.both_reject_differently.insert(file.clone());
+                }
+                self.both_reject.insert(file)
+            }
+            Outcome::OnlyReferenceImplRejects => self.only_reference_impl_rejects.insert(file),
+            Outcome::OnlyRNixRejects(_) => self.only_rnix_rejects.insert(file),
+            Outcome::RNixPanicked(_) => self.rnix_panicked.insert(file),
+            Outcome::Failed { .. } => self.failed.insert(file),
+        };
+    }
 }
@@ -0,0 +1,256 @@
+//! Shrinking a source with a mismatch down to a snippet which still has it, by delta debugging over rnix-parser's CST.
+
+use std::{collections::BTreeSet, fmt, ops::Range};
+
+use rnix::{
+    ast::{AstNode, Expr},
+    SyntaxKind, SyntaxNode, TextRange,
+};
+use serde_json::Value;
+
+use crate::{classify_rnix_error, CheckResult, Comparator, Comparison, ErrorCategory, NixSource};
+
+/// What a mismatch looks like, for telling whether a smaller source still has the same one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MismatchSignature {
+    /// Both sides accepted the source, but their ASTs differ at these keys (the last object key of each path where
+    /// they differ)
+    NotEqual(BTreeSet<String>),
+    /// rnix-parser rejected the source with this kind of error
+    OnlyRNixRejects(ErrorCategory),
+}
+
+impl MismatchSignature {
+    /// The signature of `comparison`, if it's a mismatch that the reference impl accepts
+    pub fn of(comparison: &Comparison) -> Option<Self> {
+        match (
+            &comparison.result,
+            &comparison.reference_json,
+            &comparison.candidate_json,
+        ) {
+            (
+                CheckResult::NotEqual(_) | CheckResult::ValuesDiffer(_),
+                Some(reference_json),
+                Some(candidate_json),
+            ) => {
+                let mut keys = BTreeSet::new();
+                differing_keys(reference_json, candidate_json, "(root)", &mut keys);
+                Some(MismatchSignature::NotEqual(keys))
+            }
+            (CheckResult::OnlyRNixRejects(err), _, _) => Some(MismatchSignature::OnlyRNixRejects(
+                classify_rnix_error(&**err).0,
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MismatchSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MismatchSignature::NotEqual(keys) => write!(
+                f,
+                "not equal at {}",
+                keys.iter().cloned().collect::<Vec<_>>().join(", ")
+            ),
+            MismatchSignature::OnlyRNixRejects(category) => {
+                write!(f, "only rnix-parser rejects, with {category:?}")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MinimizeError {
+    /// The source has no mismatch to begin with, or only one where the reference impl rejects it
+    NoMismatch,
+}
+
+impl fmt::Display for MinimizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MinimizeError::NoMismatch => write!(
+                f,
+                "nothing to minimize: the reference impl has to accept the source, and the parsers have to disagree"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MinimizeError {}
+
+/// Shrink `source` for as long as it keeps the same [`MismatchSignature`] under `comparator`, and the reference impl
+/// keeps accepting it. Each step removes, inlines or simplifies part of rnix-parser's CST, trying the changes which
+/// remove the most first. `on_progress` is handed every smaller source that's kept.
+pub fn minimize(
+    source: &str,
+    comparator: &Comparator,
+    on_progress: &mut dyn FnMut(&str),
+) -> Result<String, MinimizeError> {
+    let signature = |source: &str| {
+        MismatchSignature::of(&comparator.compare(&NixSource::String(source.to_string())))
+    };
+    let wanted = signature(source).ok_or(MinimizeError::NoMismatch)?;
+
+    let mut source = source.to_string();
+    'shrink: loop {
+        for edit in edits(&source) {
+            let smaller = edit.apply(&source);
+            if signature(&smaller).as_ref() == Some(&wanted) {
+                on_progress(&smaller);
+                source = smaller;
+                continue 'shrink;
+            }
+        }
+        return Ok(source);
+    }
+}
+
+/// Replace each of `ranges` (which are sorted and don't overlap) with `replacement`
+struct Edit {
+    ranges: Vec<Range<usize>>,
+    replacement: &'static str,
+}
+
+impl Edit {
+    fn removed(&self) -> usize {
+        self.ranges.iter().map(|range| range.len()).sum::<usize>()
+            - self.replacement.len() * self.ranges.len()
+    }
+
+    fn apply(&self, source: &str) -> String {
+        let mut out = String::with_capacity(source.len());
+        let mut end = 0;
+        for range in &self.ranges {
+            out.push_str(&source[end..range.start]);
+            out.push_str(self.replacement);
+            end = range.end;
+        }
+        out.push_str(&source[end..]);
+        out
+    }
+}
+
+/// Every edit of `source` which makes it smaller, biggest first
+fn edits(source: &str) -> Vec<Edit> {
+    let root = rnix::Root::parse(source).syntax();
+    let mut edits = vec![];
+
+    for node in root.descendants() {
+        if Expr::can_cast(node.kind()) && node.kind() != SyntaxKind::NODE_ROOT {
+            // Inline one of the expressions in it
+            for inner in nearest_exprs(&node) {
+                edits.push(Edit {
+                    ranges: vec![
+                        range(node.text_range()).start..range(inner.text_range()).start,
+                        range(inner.text_range()).end..range(node.text_range()).end,
+                    ],
+                    replacement: "",
+                });
+            }
+            // Simplify it to the smallest thing that's still an expression
+            edits.push(Edit {
+                ranges: vec![range(node.text_range())],
+                replacement: "null",
+            });
+        }
+
+        // Remove list items or bindings, in ever smaller chunks like ddmin does
+        let removable: Vec<Range<usize>> = match node.kind() {
+            SyntaxKind::NODE_LIST => node
+                .children()
+                .filter(|child| Expr::can_cast(child.kind()))
+                .map(|child| range(child.text_range()))
+                .collect(),
+            SyntaxKind::NODE_ATTR_SET | SyntaxKind::NODE_LET_IN | SyntaxKind::NODE_LEGACY_LET => {
+                node.children()
+                    .filter(|child| {
+                        matches!(
+                            child.kind(),
+                            SyntaxKind::NODE_ATTRPATH_VALUE | SyntaxKind::NODE_INHERIT
+                        )
+                    })
+                    .map(|child| range(child.text_range()))
+                    .collect()
+            }
+            _ => vec![],
+        };
+        let mut chunk_size = removable.len();
+        while chunk_size > 0 {
+            for chunk in removable.chunks(chunk_size) {
+                edits.push(Edit {
+                    ranges: chunk.to_vec(),
+                    replacement: "",
+                });
+            }
+            chunk_size /= 2;
+        }
+    }
+
+    for token in root
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+    {
+        if token.kind() == SyntaxKind::TOKEN_COMMENT {
+            edits.push(Edit {
+                ranges: vec![range(token.text_range())],
+                replacement: "",
+            });
+        }
+    }
+
+    // Every edit has to make progress, so that minimizing is sure to end
+    edits.retain(|edit| {
+        edit.ranges.iter().map(Range::len).sum::<usize>()
+            > edit.replacement.len() * edit.ranges.len()
+    });
+    edits.sort_by_key(|edit| std::cmp::Reverse(edit.removed()));
+    edits
+}
+
+/// The expressions in `node` which aren't inside of another expression in it, e.g. for a `let`, the value of each
+/// binding and the body
+fn nearest_exprs(node: &SyntaxNode) -> Vec<SyntaxNode> {
+    node.children()
+        .flat_map(|child| {
+            if Expr::can_cast(child.kind()) {
+                vec![child]
+            } else {
+                nearest_exprs(&child)
+            }
+        })
+        .collect()
+}
+
+fn range(range: TextRange) -> Range<usize> {
+    range.start().into()..range.end().into()
+}
+
+/// Collect the last key on the way to every place where `lhs` and `rhs` differ
+fn differing_keys(lhs: &Value, rhs: &Value, key: &str, keys: &mut BTreeSet<String>) {
+    match (lhs, rhs) {
+        (Value::Object(lhs), Value::Object(rhs)) => {
+            for (field, value) in lhs {
+                match rhs.get(field) {
+                    Some(other) => differing_keys(value, other, field, keys),
+                    None => {
+                        keys.insert(field.clone());
+                    }
+                }
+            }
+            for field in rhs.keys().filter(|field| !lhs.contains_key(*field)) {
+                keys.insert(field.clone());
+            }
+        }
+        (Value::Array(lhs), Value::Array(rhs)) if lhs.len() == rhs.len() => {
+            for (lhs, rhs) in lhs.iter().zip(rhs) {
+                differing_keys(lhs, rhs, key, keys);
+            }
+        }
+        (lhs, rhs) => {
+            if lhs != rhs {
+                keys.insert(key.to_string());
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Catching panics on the rnix-parser side of a comparison, so that one file which hits a bug in rnix-parser or in the
+//! tester doesn't end a whole run.
+
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::RefCell,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::Once,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A panic which [`catch_panic`] caught
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Panic {
+    /// What was panicked with, and where
+    pub message: String,
+    /// Where the panic came from. Captured no matter what `RUST_BACKTRACE` says.
+    pub backtrace: String,
+}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "panicked: {}", self.message)
+    }
+}
+
+impl std::error::Error for Panic {}
+
+thread_local! {
+    /// Set while [`catch_panic`] runs on this thread, and then filled in by the panic hook if anything panics
+    static CAUGHT: RefCell<Option<Option<Panic>>> = const { RefCell::new(None) };
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+/// Run `f`, turning a panic in it into a [`Panic`]. The panic isn't printed like it usually would be, since it's up to
+/// the caller to report it. A stack overflow still aborts, since it can't be caught.
+pub fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, Panic> {
+    INSTALL_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let caught = CAUGHT.with(|caught| match &mut *caught.borrow_mut() {
+                Some(slot) => {
+                    let message = payload_message(info.payload());
+                    *slot = Some(Panic {
+                        message: match info.location() {
+                            Some(location) => format!("{message} at {location}"),
+                            None => message,
+                        },
+                        backtrace: Backtrace::force_capture().to_string(),
+                    });
+                    true
+                }
+                None => false,
+            });
+            if !caught {
+                default_hook(info);
+            }
+        }));
+    });
+
+    // Restored afterwards, in case this is nested in another catch_panic
+    let outer = CAUGHT.with(|caught| caught.replace(Some(None)));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    let caught = CAUGHT.with(|caught| caught.replace(outer)).flatten();
+
+    result.map_err(|payload| {
+        caught.unwrap_or_else(|| Panic {
+            message: payload_message(&*payload),
+            backtrace: String::new(),
+        })
+    })
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Box<dyn Any>".to_string(),
+        },
+    }
+}
@@ -0,0 +1,180 @@
+//! The steps of comparing a source, and the errors each of them can fail with. Only some of them failing means that a
+//! parser rejected the source. The rest failing means that something is wrong with the tester, or with the input.
+
+use std::{error::Error, fmt, io, path::PathBuf};
+
+use ref_impl_parser::{BridgeError, ReferenceImplError};
+use rnix::parser::ParseError;
+use rnix_ast::convert::ToAstError;
+use rnix_normalize::{NormalizeError, PathContextError};
+use serde::{Deserialize, Serialize};
+
+use crate::{ExternalParseError, ProcessError};
+
+/// A step of comparing a source, in the order they run in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    /// Finding and reading the source. Fails with a [`ReadError`].
+    Read,
+    /// Parsing with rnix-parser. Fails with an [`RNixParseError`].
+    #[serde(rename = "rnix-parse")]
+    RNixParse,
+    /// Converting rnix-parser's CST into rnix-ast's AST. Fails with a [`ConvertError`].
+    Convert,
+    /// Lowering and normalizing rnix-parser's AST. Fails with a [`NormalizeError`].
+    Normalize,
+    /// Serializing rnix-parser's normalized AST. Fails with a [`SerializeError`].
+    Serialize,
+    /// Parsing with the reference impl. Fails with a [`ReferenceImplError`].
+    ReferenceParse,
+    /// Reading the reference impl's answer through ref-impl-parser. Fails with a [`BridgeError`].
+    Bridge,
+    /// Running an external backend's command, and reading its answer. Fails with a [`ProcessError`].
+    Process,
+    /// An external backend rejecting the source, as its answer says. Fails with an [`ExternalParseError`].
+    ExternalParse,
+    /// Reading back and comparing both sides' JSON. Fails with a [`CompareError`].
+    Compare,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 10] = [
+        Phase::Read,
+        Phase::RNixParse,
+        Phase::Convert,
+        Phase::Normalize,
+        Phase::Serialize,
+        Phase::ReferenceParse,
+        Phase::Bridge,
+        Phase::Process,
+        Phase::ExternalParse,
+        Phase::Compare,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Read => "read",
+            Phase::RNixParse => "rnix-parse",
+            Phase::Convert => "convert",
+            Phase::Normalize => "normalize",
+            Phase::Serialize => "serialize",
+            Phase::ReferenceParse => "reference-parse",
+            Phase::Bridge => "bridge",
+            Phase::Process => "process",
+            Phase::ExternalParse => "external-parse",
+            Phase::Compare => "compare",
+        }
+    }
+
+    /// Whether failing in this phase means that a parser rejected the source. Failing in any other phase isn't up to
+    /// either parser, e.g. a conversion failure is a bug in the tester.
+    pub fn is_rejection(self) -> bool {
+        matches!(
+            self,
+            Phase::RNixParse | Phase::Normalize | Phase::ReferenceParse | Phase::ExternalParse
+        )
+    }
+
+    /// The phase that `err` is the error of, if it's one of theirs
+    pub fn of(err: &(dyn Error + 'static)) -> Option<Phase> {
+        if err.is::<ReadError>() {
+            Some(Phase::Read)
+        } else if err.is::<RNixParseError>() {
+            Some(Phase::RNixParse)
+        } else if err.is::<ConvertError>() {
+            Some(Phase::Convert)
+        } else if err.is::<NormalizeError>() {
+            Some(Phase::Normalize)
+        } else if err.is::<SerializeError>() {
+            Some(Phase::Serialize)
+        } else if err.is::<ReferenceImplError>() {
+            Some(Phase::ReferenceParse)
+        } else if err.is::<BridgeError>() {
+            Some(Phase::Bridge)
+        } else if err.is::<ProcessError>() {
+            Some(Phase::Process)
+        } else if err.is::<ExternalParseError>() {
+            Some(Phase::ExternalParse)
+        } else if err.is::<CompareError>() {
+            Some(Phase::Compare)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// The source's name couldn't be resolved against the path context
+    Resolve(PathContextError),
+    Io(PathBuf, io::Error),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Resolve(err) => write!(f, "{err}"),
+            ReadError::Io(path, err) => write!(f, "{}: {err}", path.display()),
+        }
+    }
+}
+
+impl Error for ReadError {}
+
+/// rnix-parser rejected the source
+#[derive(Debug)]
+pub struct RNixParseError(pub ParseError);
+
+impl fmt::Display for RNixParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for RNixParseError {}
+
+/// rnix-parser accepted the source, but its CST couldn't be converted
+#[derive(Debug)]
+pub struct ConvertError(pub ToAstError);
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for ConvertError {}
+
+#[derive(Debug)]
+pub struct SerializeError(pub serde_json::Error);
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for SerializeError {}
+
+/// One side's JSON couldn't be read back in to be compared
+#[derive(Debug)]
+pub struct CompareError {
+    /// The name of the backend whose JSON it was
+    pub backend: String,
+    pub error: serde_json::Error,
+}
+
+impl fmt::Display for CompareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid JSON from {}: {}", self.backend, self.error)
+    }
+}
+
+impl Error for CompareError {}
@@ -0,0 +1,431 @@
+//! Parsers which aren't Rust libraries, run as external commands which speak normal-ast JSON.
+//!
+//! A command is sent a request for each source: a JSON object with the `source` itself, the `base_path` that relative
+//! paths in it are relative to, and the `file` it was read from, if any. It answers with the normalized AST as JSON, in
+//! the same shape as the reference impl's, or with an error object, like
+//! `{"error": {"message": "syntax error", "category": "Syntax", "line": 1, "column": 4}}`, where everything but the
+//! message is optional.
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    process::{Child, ChildStdin, Command, ExitStatus, Stdio},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use normal_ast::NormalNixExpr;
+use rnix_normalize::PathContext;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    deser_json, read_source, ErrorCategory, Location, NixSource, ParserBackend, SerializeError,
+};
+
+/// How a [`ProcessBackend`]'s command is run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessMode {
+    /// Once for each source, which gets the request on stdin and answers on stdout before exiting
+    PerFile,
+    /// As a long-lived process, which gets one request per line on stdin and answers each with a line on stdout
+    JsonLines,
+}
+
+/// A parser run as an external command. See the [module docs](self) for what it's sent and has to answer with.
+pub struct ProcessBackend {
+    name: String,
+    program: String,
+    args: Vec<String>,
+    mode: ProcessMode,
+    timeout: Duration,
+    /// Long-lived processes which aren't busy with a request. There can be several, so that sources can be parsed on
+    /// several threads at once.
+    idle: Mutex<Vec<Server>>,
+}
+
+/// A long-lived process, along with its output, line by line
+struct Server {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<io::Result<String>>,
+    stderr_lines: Receiver<io::Result<String>>,
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    source: &'a str,
+    base_path: &'a str,
+    file: Option<&'a str>,
+}
+
+impl ProcessBackend {
+    pub fn new(program: impl Into<String>, args: Vec<String>, mode: ProcessMode) -> Self {
+        let program = program.into();
+        ProcessBackend {
+            name: std::iter::once(&program)
+                .chain(&args)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(" "),
+            program,
+            args,
+            mode,
+            timeout: Duration::from_secs(10),
+            idle: Mutex::new(vec![]),
+        }
+    }
+
+    /// Call the backend something other than its command
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// How long the command gets to answer each request. After that, it's killed. Defaults to 10 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        command
+    }
+
+    fn run_once(&self, request: String) -> Result<String, ProcessFailure> {
+        let mut child = self
+            .command()
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(ProcessFailure::Spawn)?;
+
+        // Written and read on threads of their own, so that a command which doesn't read all of its input, or which
+        // fills up one pipe while the other is being read, can't block
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        thread::spawn(move || {
+            // Whatever the command does without the rest of its input shows up in its output
+            let _ = stdin.write_all(request.as_bytes());
+        });
+        let stdout = read_to_end(child.stdout.take().expect("stdout is piped"));
+        let stderr = read_to_end(child.stderr.take().expect("stderr is piped"));
+
+        // Closing stdout doesn't mean that it's done, so the timeout covers exiting too
+        let deadline = Instant::now() + self.timeout;
+        let output = match stdout.recv_timeout(self.timeout) {
+            Ok(output) => output.map_err(ProcessFailure::Io)?,
+            Err(_) => {
+                kill(&mut child);
+                return Err(ProcessFailure::TimedOut(self.timeout));
+            }
+        };
+        let status = match wait_until(&mut child, deadline).map_err(ProcessFailure::Io)? {
+            Some(status) => status,
+            None => {
+                kill(&mut child);
+                return Err(ProcessFailure::TimedOut(self.timeout));
+            }
+        };
+        if !status.success() {
+            return Err(ProcessFailure::Exited {
+                status,
+                stderr: stderr
+                    .recv_timeout(STDERR_GRACE)
+                    .ok()
+                    .and_then(Result::ok)
+                    .unwrap_or_default(),
+            });
+        }
+        Ok(output)
+    }
+
+    fn run_on_server(&self, request: String) -> Result<String, ProcessFailure> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut server = match idle {
+            Some(server) => server,
+            None => self.spawn_server()?,
+        };
+
+        match self.ask(&mut server, &request) {
+            Ok(line) => {
+                self.idle.lock().unwrap().push(server);
+                Ok(line)
+            }
+            // It can't be trusted with another request after this
+            Err(failure) => {
+                kill(&mut server.child);
+                Err(failure)
+            }
+        }
+    }
+
+    fn spawn_server(&self) -> Result<Server, ProcessFailure> {
+        let mut child = self
+            .command()
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(ProcessFailure::Spawn)?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let lines = read_lines(child.stdout.take().expect("stdout is piped"));
+        let stderr_lines = read_lines(child.stderr.take().expect("stderr is piped"));
+
+        Ok(Server {
+            child,
+            stdin,
+            lines,
+            stderr_lines,
+        })
+    }
+
+    fn ask(&self, server: &mut Server, request: &str) -> Result<String, ProcessFailure> {
+        let deadline = Instant::now() + self.timeout;
+        // Only what it writes to stderr while answering this request is kept
+        while server.stderr_lines.try_recv().is_ok() {}
+        let exited = |server: &Server, status| ProcessFailure::Exited {
+            status,
+            stderr: stderr_until_closed(&server.stderr_lines),
+        };
+
+        if let Err(err) = writeln!(server.stdin, "{request}").and_then(|()| server.stdin.flush()) {
+            return Err(match server.child.try_wait() {
+                Ok(Some(status)) => exited(server, status),
+                _ => ProcessFailure::Io(err),
+            });
+        }
+
+        match server.lines.recv_timeout(self.timeout) {
+            Ok(line) => line.map_err(ProcessFailure::Io),
+            Err(RecvTimeoutError::Timeout) => Err(ProcessFailure::TimedOut(self.timeout)),
+            Err(RecvTimeoutError::Disconnected) => {
+                // Its stdout was closed, so it's exiting, if it hasn't already
+                match wait_until(&mut server.child, deadline).map_err(ProcessFailure::Io)? {
+                    Some(status) => Err(exited(server, status)),
+                    None => Err(ProcessFailure::TimedOut(self.timeout)),
+                }
+            }
+        }
+    }
+
+    fn read_response(&self, output: &str) -> Result<NormalNixExpr, Box<dyn Error>> {
+        let malformed = |error| ProcessError {
+            backend: self.name.clone(),
+            failure: ProcessFailure::Malformed {
+                output: excerpt(output),
+                error,
+            },
+        };
+
+        let response: Value = deser_json(output).map_err(malformed)?;
+        if let Some(error) = response.get("error") {
+            return Err(Box::new(
+                ExternalParseError::deserialize(error).map_err(malformed)?,
+            ));
+        }
+        Ok(
+            NormalNixExpr::deserialize(serde_stacker::Deserializer::new(response))
+                .map_err(malformed)?,
+        )
+    }
+}
+
+impl ParserBackend for ProcessBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn parse(
+        &self,
+        source: &NixSource,
+        paths: &PathContext,
+    ) -> Result<NormalNixExpr, Box<dyn Error>> {
+        let (input, file) = read_source(source, paths)?;
+        let base_path = paths.base_path(file.as_deref());
+        let request = serde_json::to_string(&Request {
+            source: &input,
+            base_path: &base_path,
+            file: file.as_deref(),
+        })
+        .map_err(SerializeError)?;
+
+        let output = match self.mode {
+            ProcessMode::PerFile => self.run_once(request),
+            ProcessMode::JsonLines => self.run_on_server(request),
+        }
+        .map_err(|failure| ProcessError {
+            backend: self.name.clone(),
+            failure,
+        })?;
+
+        self.read_response(&output)
+    }
+}
+
+impl Drop for ProcessBackend {
+    fn drop(&mut self) {
+        for server in self.idle.get_mut().unwrap().iter_mut() {
+            kill(&mut server.child);
+        }
+    }
+}
+
+/// Read all of `output` on another thread
+fn read_to_end(mut output: impl Read + Send + 'static) -> Receiver<io::Result<String>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = String::new();
+        let _ = sender.send(output.read_to_string(&mut buf).map(|_| buf));
+    });
+    receiver
+}
+
+/// Read `output` line by line on another thread
+fn read_lines(output: impl Read + Send + 'static) -> Receiver<io::Result<String>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// How long to wait for the rest of a command's stderr once it has exited. Anything it started which is still running
+/// may hold on to it.
+const STDERR_GRACE: Duration = Duration::from_secs(1);
+
+/// The lines from [`read_lines`] until its output is closed, each ending in a newline
+fn stderr_until_closed(lines: &Receiver<io::Result<String>>) -> String {
+    let deadline = Instant::now() + STDERR_GRACE;
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+    let mut stderr = String::new();
+    while let Ok(Ok(line)) = lines.recv_timeout(remaining()) {
+        stderr.push_str(&line);
+        stderr.push('\n');
+    }
+    stderr
+}
+
+/// Wait for `child` to exit, but not past `deadline`. Returns `None` if it's still running then.
+fn wait_until(child: &mut Child, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn kill(child: &mut Child) {
+    // It may have exited already, which is just as good
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// The start of `output`, which is all that's needed to tell what's wrong with it
+fn excerpt(output: &str) -> String {
+    const MAX_LEN: usize = 200;
+    match output.char_indices().nth(MAX_LEN) {
+        Some((end, _)) => format!("{}...", &output[..end]),
+        None => output.to_string(),
+    }
+}
+
+/// An external command rejected the source, as it said in its error object
+#[derive(Debug, Deserialize)]
+pub struct ExternalParseError {
+    pub message: String,
+    /// Which kind of error it was, for comparing it with another backend's (see [`crate::compare_errors`])
+    #[serde(default)]
+    pub category: Option<ErrorCategory>,
+    #[serde(default)]
+    pub line: Option<u32>,
+    #[serde(default)]
+    pub column: Option<u32>,
+}
+
+impl ExternalParseError {
+    pub fn location(&self) -> Option<Location> {
+        self.line.zip(self.column)
+    }
+}
+
+impl fmt::Display for ExternalParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location() {
+            Some((line, column)) => write!(f, "{} at {line}:{column}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for ExternalParseError {}
+
+/// An external command couldn't be asked to parse the source, or didn't answer properly
+#[derive(Debug)]
+pub struct ProcessError {
+    /// The name of the backend whose command it was
+    pub backend: String,
+    pub failure: ProcessFailure,
+}
+
+#[derive(Debug)]
+pub enum ProcessFailure {
+    /// The command couldn't be started
+    Spawn(io::Error),
+    /// The command couldn't be talked to
+    Io(io::Error),
+    TimedOut(Duration),
+    /// The command exited without answering, or with an error status. For [`ProcessMode::JsonLines`], `stderr` is only
+    /// what it wrote while answering the request.
+    Exited {
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// The answer was neither an AST nor an error object. `output` is the start of it.
+    Malformed {
+        output: String,
+        error: serde_json::Error,
+    },
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.backend)?;
+        match &self.failure {
+            ProcessFailure::Spawn(err) => write!(f, "couldn't start the command: {err}"),
+            ProcessFailure::Io(err) => write!(f, "couldn't talk to the command: {err}"),
+            ProcessFailure::TimedOut(timeout) => {
+                write!(f, "no answer within {}s", timeout.as_secs_f64())
+            }
+            ProcessFailure::Exited { status, stderr } if stderr.trim().is_empty() => {
+                write!(f, "the command exited ({status})")
+            }
+            ProcessFailure::Exited { status, stderr } => {
+                write!(
+                    f,
+                    "the command exited ({status}): {}",
+                    excerpt(stderr.trim())
+                )
+            }
+            ProcessFailure::Malformed { output, error } => {
+                write!(f, "malformed output ({error}): {output:?}")
+            }
+        }
+    }
+}
+
+impl Error for ProcessError {}
@@ -1,14 +1,16 @@
-use std::collections::HashMap;
+mod sexpr;
 
-use serde::Serialize;
+use std::{collections::HashMap, ops::Range};
 
-#[derive(Clone, Serialize, Debug)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum NormalNixExpr {
     Int(i64),
     Float(f64),
     String(String),
     Path(String),
-    Var(String),
+    Var(Var),
     Select {
         subject: Box<NormalNixExpr>,
         or_default: Option<Box<NormalNixExpr>>,
@@ -62,33 +64,151 @@ pub enum NormalNixExpr {
         force_string: bool,
         es: Vec<NormalNixExpr>,
     },
+    /// `__curPos`. `file` is `None` for expressions that weren't parsed from a file.
+    Pos {
+        file: Option<String>,
+        line: u32,
+        column: u32,
+    },
+
+    // The rest is sugar which the reference impl never produces. Each is only left in place when the normalization
+    // pass which removes it is switched off.
+    /// Parentheses. Removed by the strip-parens pass.
+    Paren(Box<NormalNixExpr>),
+    /// An operator which the reference impl turns into a call to a builtin, e.g. `-` into a call to `__sub`. Unary `-`
+    /// has a single operand. Removed by the desugar-operators pass.
+    Operator {
+        op: String,
+        operands: Vec<NormalNixExpr>,
+    },
+    /// The definitions of an attr set or a `let` as written, i.e., before attr paths like `x.y = 1` are expanded and
+    /// definitions of the same attr are merged. Removed by the merge-attrs pass.
+    UnmergedAttrs {
+        rec: bool,
+        /// Whether this set was made up by the expand-attrpaths pass, rather than written out. Its definitions still
+        /// merge into an existing set of the same name, as if the attr path had never been expanded.
+        implicit: bool,
+        defs: Vec<AttrPathDef>,
+        /// Where the set (or the whole `let`) was written, as a range of bytes in the source
+        #[serde(skip)]
+        span: Range<usize>,
+    },
+}
+
+impl NormalNixExpr {
+    /// The name of the variant, e.g. `Call`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NormalNixExpr::Int(_) => "Int",
+            NormalNixExpr::Float(_) => "Float",
+            NormalNixExpr::String(_) => "String",
+            NormalNixExpr::Path(_) => "Path",
+            NormalNixExpr::Var(_) => "Var",
+            NormalNixExpr::Select { .. } => "Select",
+            NormalNixExpr::OpHasAttr { .. } => "OpHasAttr",
+            NormalNixExpr::Attrs { .. } => "Attrs",
+            NormalNixExpr::List(_) => "List",
+            NormalNixExpr::Lambda { .. } => "Lambda",
+            NormalNixExpr::Call { .. } => "Call",
+            NormalNixExpr::Let { .. } => "Let",
+            NormalNixExpr::With { .. } => "With",
+            NormalNixExpr::If { .. } => "If",
+            NormalNixExpr::Assert { .. } => "Assert",
+            NormalNixExpr::OpNot(_) => "OpNot",
+            NormalNixExpr::OpEq(..) => "OpEq",
+            NormalNixExpr::OpNEq(..) => "OpNEq",
+            NormalNixExpr::OpAnd(..) => "OpAnd",
+            NormalNixExpr::OpOr(..) => "OpOr",
+            NormalNixExpr::OpImpl(..) => "OpImpl",
+            NormalNixExpr::OpUpdate(..) => "OpUpdate",
+            NormalNixExpr::OpConcatLists(..) => "OpConcatLists",
+            NormalNixExpr::OpConcatStrings { .. } => "OpConcatStrings",
+            NormalNixExpr::Pos { .. } => "Pos",
+            NormalNixExpr::Paren(_) => "Paren",
+            NormalNixExpr::Operator { .. } => "Operator",
+            NormalNixExpr::UnmergedAttrs { .. } => "UnmergedAttrs",
+        }
+    }
+}
+
+/// A variable, along with what it refers to, as worked out by the reference impl's `bindVars`.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Var {
+    pub name: String,
+    /// Where the variable was written, as a range of bytes in the source. `None` for variables which don't appear in
+    /// the source, like the `__sub` that subtraction is desugared into.
+    #[serde(skip)]
+    pub span: Option<Range<usize>>,
+    /// Whether the variable isn't bound lexically, and so comes from the innermost enclosing `with`
+    pub from_with: bool,
+    /// How many scopes out the binder is, counting from the innermost. For variables from a `with`, this is how many
+    /// scopes out that `with` is.
+    pub level: usize,
+    /// Which variable of its scope the binder is, as an index into that scope's names sorted by name. `None` for
+    /// variables from a `with` and for builtins.
+    ///
+    /// Note that this is not what the reference impl calls the displacement, which depends on the order in which
+    /// symbols were first interned, and so isn't something another parser could reproduce.
+    pub displ: Option<usize>,
+}
+
+impl Var {
+    /// A variable which doesn't appear in the source, before it's been resolved.
+    pub fn new(name: impl Into<String>) -> Self {
+        Var {
+            name: name.into(),
+            span: None,
+            from_with: false,
+            level: 0,
+            displ: None,
+        }
+    }
+
+    /// A variable written at `span` in the source, before it's been resolved.
+    pub fn at(name: impl Into<String>, span: Range<usize>) -> Self {
+        Var {
+            span: Some(span),
+            ..Var::new(name)
+        }
+    }
 }
 
-#[derive(Clone, Serialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum AttrName {
     Symbol(String),
     Expr(NormalNixExpr),
 }
 
-#[derive(Clone, Serialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct AttrDef {
     pub name: String,
     pub inherited: bool,
     pub expr: NormalNixExpr,
 }
 
-#[derive(Clone, Serialize, Debug)]
+/// A definition in [`NormalNixExpr::UnmergedAttrs`]. `inherit (x) y` is written as the definition `y = x.y`.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AttrPathDef {
+    pub path: Vec<AttrName>,
+    pub inherited: bool,
+    pub expr: NormalNixExpr,
+    /// Where the attr path (or the whole list of inherited names) was written, as a range of bytes in the source
+    #[serde(skip)]
+    pub span: Range<usize>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct DynamicAttrDef {
     pub name_expr: NormalNixExpr,
     pub value_expr: NormalNixExpr,
 }
 
-#[derive(Clone, Serialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Formal {
     pub default: Option<NormalNixExpr>,
 }
 
-#[derive(Clone, Serialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Formals {
     pub ellipsis: bool,
     pub entries: HashMap<String, Formal>,
@@ -0,0 +1,175 @@
+//! A compact, human-readable rendering of the normal AST as S-expressions, e.g. `(Call :args [(Int 0)] :fun (Var ...))`,
+//! which diffs well line by line.
+//!
+//! It's derived from the JSON, so that it stays lossless as the AST changes: a node is `(Kind ...)`, with a struct
+//! variant's fields as `:field value` pairs and a tuple variant's fields in order, any other object is a list of
+//! `:field value` pairs without a kind, an array is `[...]`, and `null` is `nil`. Fields are sorted by name, so that
+//! maps come out in the same order every time.
+
+use serde_json::Value;
+
+use crate::NormalNixExpr;
+
+/// How wide a line can get before what's on it is broken over several lines
+const WIDTH: usize = 100;
+
+impl NormalNixExpr {
+    /// Render the expression as S-expressions (see the [module docs](self)), ending with a newline. Anything that
+    /// fits on a line is printed on one, and the rest has each of its children on a line of its own, indented by two
+    /// spaces.
+    pub fn to_sexpr(&self) -> String {
+        let value = serde_json::to_value(self).expect("the normal AST always serializes");
+        let mut out = String::new();
+        write_doc(&to_doc(&value), 0, 0, &mut out);
+        out.push('\n');
+        out
+    }
+}
+
+enum Doc {
+    Atom(String),
+    /// `open`, followed by `items` separated by spaces or newlines, and then `close`
+    List {
+        open: String,
+        items: Vec<Doc>,
+        close: char,
+    },
+    /// `:key value`, which is kept together on a line
+    Field {
+        key: String,
+        value: Box<Doc>,
+    },
+}
+
+fn to_doc(value: &Value) -> Doc {
+    match value {
+        Value::Null => Doc::Atom("nil".to_string()),
+        Value::Bool(b) => Doc::Atom(b.to_string()),
+        Value::Number(n) => Doc::Atom(n.to_string()),
+        Value::String(s) => Doc::Atom(quote(s)),
+        Value::Array(items) => Doc::List {
+            open: "[".to_string(),
+            items: items.iter().map(to_doc).collect(),
+            close: ']',
+        },
+        Value::Object(fields) => match node(value) {
+            // A newtype variant holding a node, e.g. `OpNot`, rather than a struct variant
+            Some((kind, payload)) if node(payload).is_some() => Doc::List {
+                open: format!("({kind}"),
+                items: vec![to_doc(payload)],
+                close: ')',
+            },
+            Some((kind, Value::Object(fields))) => Doc::List {
+                open: format!("({kind}"),
+                items: field_docs(fields),
+                close: ')',
+            },
+            Some((kind, Value::Array(items))) => Doc::List {
+                open: format!("({kind}"),
+                items: items.iter().map(to_doc).collect(),
+                close: ')',
+            },
+            Some((kind, payload)) => Doc::List {
+                open: format!("({kind}"),
+                items: vec![to_doc(payload)],
+                close: ')',
+            },
+            None => Doc::List {
+                open: "(".to_string(),
+                items: field_docs(fields),
+                close: ')',
+            },
+        },
+    }
+}
+
+/// The kind of `value` and what's in it, if it's a node, i.e. an object with a single field named after the kind
+fn node(value: &Value) -> Option<(&str, &Value)> {
+    match value {
+        Value::Object(fields) if fields.len() == 1 => {
+            let (kind, payload) = fields.iter().next()?;
+            kind.starts_with(|c: char| c.is_ascii_uppercase())
+                .then_some((kind.as_str(), payload))
+        }
+        _ => None,
+    }
+}
+
+fn field_docs(fields: &serde_json::Map<String, Value>) -> Vec<Doc> {
+    let mut fields: Vec<_> = fields.iter().collect();
+    fields.sort_by_key(|(key, _)| *key);
+    fields
+        .into_iter()
+        .map(|(key, value)| Doc::Field {
+            key: keyword(key),
+            value: Box::new(to_doc(value)),
+        })
+        .collect()
+}
+
+/// `:key`, or the key quoted if it isn't a plain name, like a formal or attr name could be
+fn keyword(key: &str) -> String {
+    let plain = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''));
+    if plain {
+        format!(":{key}")
+    } else {
+        format!(":{}", quote(key))
+    }
+}
+
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).expect("strings always serialize")
+}
+
+fn flat(doc: &Doc, out: &mut String) {
+    match doc {
+        Doc::Atom(atom) => out.push_str(atom),
+        Doc::List { open, items, close } => {
+            out.push_str(open);
+            for (i, item) in items.iter().enumerate() {
+                // A kind is separated from what follows it, but brackets aren't
+                if i > 0 || open.len() > 1 {
+                    out.push(' ');
+                }
+                flat(item, out);
+            }
+            out.push(*close);
+        }
+        Doc::Field { key, value } => {
+            out.push_str(key);
+            out.push(' ');
+            flat(value, out);
+        }
+    }
+}
+
+/// Write `doc`, which starts at `column` of a line indented by `indent`
+fn write_doc(doc: &Doc, indent: usize, column: usize, out: &mut String) {
+    let mut line = String::new();
+    flat(doc, &mut line);
+    if column + line.chars().count() <= WIDTH {
+        out.push_str(&line);
+        return;
+    }
+
+    match doc {
+        Doc::Atom(atom) => out.push_str(atom),
+        Doc::List { open, items, close } => {
+            out.push_str(open);
+            for item in items {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent + 2));
+                write_doc(item, indent + 2, indent + 2, out);
+            }
+            out.push(*close);
+        }
+        Doc::Field { key, value } => {
+            out.push_str(key);
+            out.push(' ');
+            write_doc(value, indent, column + key.chars().count() + 1, out);
+        }
+    }
+}
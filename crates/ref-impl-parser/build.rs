@@ -9,4 +9,5 @@ fn main() {
     println!("cargo:rustc-link-lib=nixexpr");
     println!("cargo:rustc-link-lib=nixstore");
     println!("cargo:rustc-link-lib=nixutil");
+    println!("cargo:rustc-link-lib=gc");
 }
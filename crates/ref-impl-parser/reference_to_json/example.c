@@ -2,9 +2,10 @@
 #include "reference_to_json.h"
 
 int main() {
-    struct Parser *parser = init_parser();
+    struct Parser *parser = init_parser("/tmp");
     const char *nix_expr = "./foo/${\"bar\"}";
-    const char *ast_json = parse_from_str(parser, nix_expr);
+    bool ok;
+    const char *ast_json = parse_from_str(parser, nix_expr, "/tmp", &ok);
     printf("%s", ast_json);
     destroy_parser(parser);
 }
@@ -1,15 +1,54 @@
+#include <algorithm>
 #include <memory>
+#include <mutex>
 #include <iostream>
+#include <optional>
 #include <nlohmann/json.hpp>
 #include <nix/config.h>
+
+#if HAVE_BOEHMGC
+// Declares what's needed to register threads that the garbage collector didn't start itself
+#define GC_THREADS
+#include <gc/gc.h>
+#endif
+
 #include <nix/eval.hh>
+#include <nix/globals.hh>
 #include <nix/store-api.hh>
 
 #include "reference_to_json.h"
 
 using namespace nix;
 
-nlohmann::json nix_expr_to_json(Expr *expr, const SymbolTable &symbols);
+// The static environments that the reference impl's bindVars would have built at some point, innermost last. Each
+// holds its variable names sorted, or is empty for a `with`.
+using Scopes = std::vector<std::optional<std::vector<std::string>>>;
+
+nlohmann::json nix_expr_to_json(Expr *expr, const SymbolTable &symbols, const PosTable &positions, Scopes &scopes);
+
+// Push the scope of a `rec` set, `let`, or lambda binding the given names
+template <typename Names>
+void push_scope(Scopes &scopes, const Names &names, const SymbolTable &symbols)
+{
+    std::vector<std::string> scope{};
+    for (const auto &name : names)
+    {
+        scope.push_back(symbols[name]);
+    }
+    std::sort(scope.begin(), scope.end());
+    scope.erase(std::unique(scope.begin(), scope.end()), scope.end());
+    scopes.push_back(scope);
+}
+
+std::vector<Symbol> attr_names(const ExprAttrs::AttrDefs &attrDefs)
+{
+    std::vector<Symbol> names{};
+    for (const auto &[name, _] : attrDefs)
+    {
+        names.push_back(name);
+    }
+    return names;
+}
 
 class NotImplemented : public std::logic_error
 {
@@ -17,7 +56,8 @@ public:
     NotImplemented() : std::logic_error("Function not yet implemented"){};
 };
 
-nlohmann::json attr_defs_to_json(ExprAttrs::AttrDefs attrDefs, const SymbolTable &symbols)
+// `ownScope` is whether the innermost scope was pushed for these attrs, and so shouldn't be seen by inherited ones
+nlohmann::json attr_defs_to_json(ExprAttrs::AttrDefs attrDefs, const SymbolTable &symbols, const PosTable &positions, Scopes &scopes, bool ownScope)
 {
     std::vector<std::pair<Symbol, ExprAttrs::AttrDef>> attrs{};
     for (const auto &attr : attrDefs)
@@ -32,30 +72,43 @@ nlohmann::json attr_defs_to_json(ExprAttrs::AttrDefs attrDefs, const SymbolTable
     auto res = nlohmann::json::array();
     for (const auto &[key, value] : attrs)
     {
+        nlohmann::json expr;
+        if (value.inherited && ownScope)
+        {
+            auto scope = scopes.back();
+            scopes.pop_back();
+            expr = nix_expr_to_json(value.e, symbols, positions, scopes);
+            scopes.push_back(scope);
+        }
+        else
+        {
+            expr = nix_expr_to_json(value.e, symbols, positions, scopes);
+        }
+
         res.push_back({
             {"name", symbols[key]},
             {"inherited", value.inherited},
-            {"expr", nix_expr_to_json(value.e, symbols)},
+            {"expr", expr},
         });
     }
 
     return res;
 }
 
-nlohmann::json dynamic_attr_defs_to_json(ExprAttrs::DynamicAttrDefs attrDefs, const SymbolTable &symbols)
+nlohmann::json dynamic_attr_defs_to_json(ExprAttrs::DynamicAttrDefs attrDefs, const SymbolTable &symbols, const PosTable &positions, Scopes &scopes)
 {
     auto res = nlohmann::json::array();
     for (const auto &attr : attrDefs)
     {
         res.push_back({
-            {"name_expr", nix_expr_to_json(attr.nameExpr, symbols)},
-            {"value_expr", nix_expr_to_json(attr.valueExpr, symbols)},
+            {"name_expr", nix_expr_to_json(attr.nameExpr, symbols, positions, scopes)},
+            {"value_expr", nix_expr_to_json(attr.valueExpr, symbols, positions, scopes)},
         });
     }
     return res;
 }
 
-nlohmann::json formals_to_json(Formals *formals, const SymbolTable &symbols)
+nlohmann::json formals_to_json(Formals *formals, const SymbolTable &symbols, const PosTable &positions, Scopes &scopes)
 {
     if (formals == nullptr)
     {
@@ -65,7 +118,7 @@ nlohmann::json formals_to_json(Formals *formals, const SymbolTable &symbols)
     auto entries = nlohmann::json::object();
     for (const auto formal : formals->formals)
     {
-        entries.push_back({symbols[formal.name], {{"default", nix_expr_to_json(formal.def, symbols)}}});
+        entries.push_back({symbols[formal.name], {{"default", nix_expr_to_json(formal.def, symbols, positions, scopes)}}});
     }
 
     return {
@@ -74,17 +127,17 @@ nlohmann::json formals_to_json(Formals *formals, const SymbolTable &symbols)
     };
 }
 
-nlohmann::json nix_exprs_to_json(std::vector<Expr *> exprs, const SymbolTable &symbols)
+nlohmann::json nix_exprs_to_json(std::vector<Expr *> exprs, const SymbolTable &symbols, const PosTable &positions, Scopes &scopes)
 {
     auto res = nlohmann::json::array();
     for (const auto expr : exprs)
     {
-        res.push_back(nix_expr_to_json(expr, symbols));
+        res.push_back(nix_expr_to_json(expr, symbols, positions, scopes));
     }
     return res;
 }
 
-nlohmann::json string_concat_exprs_to_json(std::vector<std::pair<PosIdx, Expr *>> *exprs, const SymbolTable &symbols)
+nlohmann::json string_concat_exprs_to_json(std::vector<std::pair<PosIdx, Expr *>> *exprs, const SymbolTable &symbols, const PosTable &positions, Scopes &scopes)
 {
     auto res = std::vector<Expr *>();
     for (const auto &[pos, e] : *exprs)
@@ -92,10 +145,10 @@ nlohmann::json string_concat_exprs_to_json(std::vector<std::pair<PosIdx, Expr *>
         res.push_back(e);
     }
 
-    return nix_exprs_to_json(res, symbols);
+    return nix_exprs_to_json(res, symbols, positions, scopes);
 }
 
-nlohmann::json attr_path_to_json(AttrPath attrPath, const SymbolTable &symbols)
+nlohmann::json attr_path_to_json(AttrPath attrPath, const SymbolTable &symbols, const PosTable &positions, Scopes &scopes)
 {
     auto res = nlohmann::json::array();
     for (const auto attr : attrPath)
@@ -109,14 +162,14 @@ nlohmann::json attr_path_to_json(AttrPath attrPath, const SymbolTable &symbols)
         else
         {
             res.push_back({
-                {"Expr", nix_expr_to_json(attr.expr, symbols)},
+                {"Expr", nix_expr_to_json(attr.expr, symbols, positions, scopes)},
             });
         }
     }
     return res;
 }
 
-nlohmann::json nix_expr_to_json(Expr *expr, const SymbolTable &symbols)
+nlohmann::json nix_expr_to_json(Expr *expr, const SymbolTable &symbols, const PosTable &positions, Scopes &scopes)
 {
     if (expr == nullptr)
     {
@@ -148,40 +201,67 @@ nlohmann::json nix_expr_to_json(Expr *expr, const SymbolTable &symbols)
     }
     else if (auto exprVar = dynamic_cast<ExprVar *>(expr))
     {
+        std::string name = symbols[exprVar->name];
+
+        // The reference impl's displacement depends on the order in which symbols were interned, which is nothing
+        // another parser could reproduce. Report where the binder's name falls in its (sorted) scope instead. Builtins
+        // live in the base env, which we don't track, and variables from a `with` don't have a displacement.
+        nlohmann::json displ(nullptr);
+        if (!exprVar->fromWith && exprVar->level < scopes.size())
+        {
+            const auto &scope = *scopes[scopes.size() - 1 - exprVar->level];
+            displ = std::lower_bound(scope.begin(), scope.end(), name) - scope.begin();
+        }
+
         return {
-            {"Var", symbols[exprVar->name]},
-        };
+            {"Var", {
+                        {"name", name},
+                        {"from_with", exprVar->fromWith},
+                        {"level", exprVar->level},
+                        {"displ", displ},
+                    }}};
     }
     else if (auto exprSelect = dynamic_cast<ExprSelect *>(expr))
     {
         return {
             {"Select", {
-                           {"subject", nix_expr_to_json(exprSelect->e, symbols)},
-                           {"or_default", nix_expr_to_json(exprSelect->def, symbols)},
-                           {"path", attr_path_to_json(exprSelect->attrPath, symbols)},
+                           {"subject", nix_expr_to_json(exprSelect->e, symbols, positions, scopes)},
+                           {"or_default", nix_expr_to_json(exprSelect->def, symbols, positions, scopes)},
+                           {"path", attr_path_to_json(exprSelect->attrPath, symbols, positions, scopes)},
                        }}};
     }
     else if (auto exprOpHasAttr = dynamic_cast<ExprOpHasAttr *>(expr))
     {
         return {
             {"OpHasAttr", {
-                              {"subject", nix_expr_to_json(exprOpHasAttr->e, symbols)},
-                              {"path", attr_path_to_json(exprOpHasAttr->attrPath, symbols)},
+                              {"subject", nix_expr_to_json(exprOpHasAttr->e, symbols, positions, scopes)},
+                              {"path", attr_path_to_json(exprOpHasAttr->attrPath, symbols, positions, scopes)},
                           }}};
     }
     else if (auto exprAttrs = dynamic_cast<ExprAttrs *>(expr))
     {
-        return {
+        if (exprAttrs->recursive)
+        {
+            push_scope(scopes, attr_names(exprAttrs->attrs), symbols);
+        }
+
+        nlohmann::json res = {
             {"Attrs", {
                           {"rec", exprAttrs->recursive},
-                          {"attrs", attr_defs_to_json(exprAttrs->attrs, symbols)},
-                          {"dynamic_attrs", dynamic_attr_defs_to_json(exprAttrs->dynamicAttrs, symbols)},
+                          {"attrs", attr_defs_to_json(exprAttrs->attrs, symbols, positions, scopes, exprAttrs->recursive)},
+                          {"dynamic_attrs", dynamic_attr_defs_to_json(exprAttrs->dynamicAttrs, symbols, positions, scopes)},
                       }}};
+
+        if (exprAttrs->recursive)
+        {
+            scopes.pop_back();
+        }
+        return res;
     }
     else if (auto exprList = dynamic_cast<ExprList *>(expr))
     {
         return {
-            {"List", nix_exprs_to_json(exprList->elems, symbols)},
+            {"List", nix_exprs_to_json(exprList->elems, symbols, positions, scopes)},
         };
     }
     else if (auto exprLambda = dynamic_cast<ExprLambda *>(expr))
@@ -192,103 +272,143 @@ nlohmann::json nix_expr_to_json(Expr *expr, const SymbolTable &symbols)
             arg = (std::string)symbols[exprLambda->arg];
         }
 
-        return {
+        std::vector<Symbol> names{};
+        if (exprLambda->arg)
+        {
+            names.push_back(exprLambda->arg);
+        }
+        if (exprLambda->formals)
+        {
+            for (const auto &formal : exprLambda->formals->formals)
+            {
+                names.push_back(formal.name);
+            }
+        }
+        push_scope(scopes, names, symbols);
+
+        nlohmann::json res = {
             {"Lambda", {
                            {"arg", arg},
-                           {"formals", formals_to_json(exprLambda->formals, symbols)},
-                           {"body", nix_expr_to_json(exprLambda->body, symbols)},
+                           {"formals", formals_to_json(exprLambda->formals, symbols, positions, scopes)},
+                           {"body", nix_expr_to_json(exprLambda->body, symbols, positions, scopes)},
                        }}};
+
+        scopes.pop_back();
+        return res;
     }
     else if (auto exprCall = dynamic_cast<ExprCall *>(expr))
     {
         return {
             {"Call", {
-                         {"fun", nix_expr_to_json(exprCall->fun, symbols)},
-                         {"args", nix_exprs_to_json(exprCall->args, symbols)},
+                         {"fun", nix_expr_to_json(exprCall->fun, symbols, positions, scopes)},
+                         {"args", nix_exprs_to_json(exprCall->args, symbols, positions, scopes)},
                      }}};
     }
     else if (auto exprLet = dynamic_cast<ExprLet *>(expr))
     {
-        return {
+        // The attrs of a let aren't `rec`, but they are all in scope, in the same way
+        push_scope(scopes, attr_names(exprLet->attrs->attrs), symbols);
+
+        nlohmann::json res = {
             {"Let", {
-                        {"attrs", nix_expr_to_json(exprLet->attrs, symbols)},
-                        {"body", nix_expr_to_json(exprLet->body, symbols)},
+                        {"attrs", {
+                                      {"Attrs", {
+                                                    {"rec", exprLet->attrs->recursive},
+                                                    {"attrs", attr_defs_to_json(exprLet->attrs->attrs, symbols, positions, scopes, true)},
+                                                    {"dynamic_attrs", dynamic_attr_defs_to_json(exprLet->attrs->dynamicAttrs, symbols, positions, scopes)},
+                                                }},
+                                  }},
+                        {"body", nix_expr_to_json(exprLet->body, symbols, positions, scopes)},
                     }}};
+
+        scopes.pop_back();
+        return res;
     }
     else if (auto exprWith = dynamic_cast<ExprWith *>(expr))
     {
+        auto attrs = nix_expr_to_json(exprWith->attrs, symbols, positions, scopes);
+
+        scopes.push_back(std::nullopt);
+        auto body = nix_expr_to_json(exprWith->body, symbols, positions, scopes);
+        scopes.pop_back();
+
         return {
             {"With", {
-                         {"attrs", nix_expr_to_json(exprWith->attrs, symbols)},
-                         {"body", nix_expr_to_json(exprWith->body, symbols)},
+                         {"attrs", attrs},
+                         {"body", body},
                      }}};
     }
     else if (auto exprIf = dynamic_cast<ExprIf *>(expr))
     {
         return {
             {"If", {
-                       {"cond", nix_expr_to_json(exprIf->cond, symbols)},
-                       {"then", nix_expr_to_json(exprIf->then, symbols)},
-                       {"else_", nix_expr_to_json(exprIf->else_, symbols)},
+                       {"cond", nix_expr_to_json(exprIf->cond, symbols, positions, scopes)},
+                       {"then", nix_expr_to_json(exprIf->then, symbols, positions, scopes)},
+                       {"else_", nix_expr_to_json(exprIf->else_, symbols, positions, scopes)},
                    }}};
     }
     else if (auto exprAssert = dynamic_cast<ExprAssert *>(expr))
     {
         return {
-            {"Assert", {{"cond", nix_expr_to_json(exprAssert->cond, symbols)}, {"body", nix_expr_to_json(exprAssert->body, symbols)}}},
+            {"Assert", {{"cond", nix_expr_to_json(exprAssert->cond, symbols, positions, scopes)}, {"body", nix_expr_to_json(exprAssert->body, symbols, positions, scopes)}}},
         };
     }
     else if (auto exprOpNot = dynamic_cast<ExprOpNot *>(expr))
     {
-        return {{"OpNot", nix_expr_to_json(exprOpNot->e, symbols)}};
+        return {{"OpNot", nix_expr_to_json(exprOpNot->e, symbols, positions, scopes)}};
     }
     else if (auto exprOpEq = dynamic_cast<ExprOpEq *>(expr))
     {
-        return {{"OpEq", {nix_expr_to_json(exprOpEq->e1, symbols), nix_expr_to_json(exprOpEq->e2, symbols)}}};
+        return {{"OpEq", {nix_expr_to_json(exprOpEq->e1, symbols, positions, scopes), nix_expr_to_json(exprOpEq->e2, symbols, positions, scopes)}}};
     }
     else if (auto exprOpNEq = dynamic_cast<ExprOpNEq *>(expr))
     {
-        return {{"OpNEq", {nix_expr_to_json(exprOpNEq->e1, symbols), nix_expr_to_json(exprOpNEq->e2, symbols)}}};
+        return {{"OpNEq", {nix_expr_to_json(exprOpNEq->e1, symbols, positions, scopes), nix_expr_to_json(exprOpNEq->e2, symbols, positions, scopes)}}};
     }
     else if (auto exprOpAnd = dynamic_cast<ExprOpAnd *>(expr))
     {
-        return {{"OpAnd", {nix_expr_to_json(exprOpAnd->e1, symbols), nix_expr_to_json(exprOpAnd->e2, symbols)}}};
+        return {{"OpAnd", {nix_expr_to_json(exprOpAnd->e1, symbols, positions, scopes), nix_expr_to_json(exprOpAnd->e2, symbols, positions, scopes)}}};
     }
     else if (auto exprOpOr = dynamic_cast<ExprOpOr *>(expr))
     {
-        return {{"OpOr", {nix_expr_to_json(exprOpOr->e1, symbols), nix_expr_to_json(exprOpOr->e2, symbols)}}};
+        return {{"OpOr", {nix_expr_to_json(exprOpOr->e1, symbols, positions, scopes), nix_expr_to_json(exprOpOr->e2, symbols, positions, scopes)}}};
     }
     else if (auto exprOpImpl = dynamic_cast<ExprOpImpl *>(expr))
     {
-        return {{"OpImpl", {nix_expr_to_json(exprOpImpl->e1, symbols), nix_expr_to_json(exprOpImpl->e2, symbols)}}};
+        return {{"OpImpl", {nix_expr_to_json(exprOpImpl->e1, symbols, positions, scopes), nix_expr_to_json(exprOpImpl->e2, symbols, positions, scopes)}}};
     }
     else if (auto exprOpUpdate = dynamic_cast<ExprOpUpdate *>(expr))
     {
-        return {{"OpUpdate", {nix_expr_to_json(exprOpUpdate->e1, symbols), nix_expr_to_json(exprOpUpdate->e2, symbols)}}};
+        return {{"OpUpdate", {nix_expr_to_json(exprOpUpdate->e1, symbols, positions, scopes), nix_expr_to_json(exprOpUpdate->e2, symbols, positions, scopes)}}};
     }
     else if (auto exprOpConcatLists = dynamic_cast<ExprOpConcatLists *>(expr))
     {
-        return {{"OpConcatLists", {nix_expr_to_json(exprOpConcatLists->e1, symbols), nix_expr_to_json(exprOpConcatLists->e2, symbols)}}};
+        return {{"OpConcatLists", {nix_expr_to_json(exprOpConcatLists->e1, symbols, positions, scopes), nix_expr_to_json(exprOpConcatLists->e2, symbols, positions, scopes)}}};
     }
     else if (auto exprConcatStrings = dynamic_cast<ExprConcatStrings *>(expr))
     {
         return {{"OpConcatStrings", {
                                         {"force_string", exprConcatStrings->forceString},
-                                        {"es", string_concat_exprs_to_json(exprConcatStrings->es, symbols)},
+                                        {"es", string_concat_exprs_to_json(exprConcatStrings->es, symbols, positions, scopes)},
                                     }}};
     }
     else if (auto exprPos = dynamic_cast<ExprPos *>(expr))
     {
-        // Just treat this as the var/expression that creates the ExprPos ast node.
-        // It would be difficult to implement the rnix-parser equivalent of this
-        // node if we were to include the actual info from ExprPos. This is because
-        // despite the fact that _every_ rowan syntax node includes position information,
-        // it is represented only be a byte offset, not a line and column. Converting
-        // between these is non-trivial, so we choose not to for now.
-        // See https://github.com/rust-analyzer/rowan/issues/17
+        auto pos = positions[exprPos->pos];
+
+        // Only files have a meaningful name. For anything else, there's nothing for rnix-parser to compare against.
+        nlohmann::json file(nullptr);
+        if (pos.origin == foFile)
+        {
+            file = pos.file;
+        }
+
         return {
-            {"Var", "__curPos"},
-        };
+            {"Pos", {
+                        {"file", file},
+                        {"line", pos.line},
+                        {"column", pos.column},
+                    }}};
     }
 
     throw NotImplemented();
@@ -304,8 +424,17 @@ struct Parser
     }
 };
 
-extern "C" Parser *init_parser()
+// Nix expands `~` to the home directory it looks up in HOME the first time it needs it, and keeps using that one. So
+// setting HOME before then is the only way to choose it, and only the first parser created gets to.
+extern "C" Parser *init_parser(const char *home)
 {
+    static std::once_flag home_chosen;
+    std::call_once(home_chosen, [&]
+                   {
+                       setenv("HOME", home, 1);
+                       getHome();
+                   });
+
     initGC();
 
     auto searchPath = Strings{};
@@ -320,13 +449,45 @@ extern "C" void destroy_parser(Parser *parser)
     delete parser;
 }
 
+extern "C" const char *nix_version()
+{
+    return nixVersion.c_str();
+}
+
+// Creating a parser allocates with the garbage collector, which only knows about the main thread and the threads it
+// started itself. Any other thread has to register with it first, which this allows.
+extern "C" void allow_parser_threads()
+{
+    initGC();
+#if HAVE_BOEHMGC
+    GC_allow_register_threads();
+#endif
+}
+
+extern "C" void register_parser_thread()
+{
+#if HAVE_BOEHMGC
+    struct GC_stack_base stack_base;
+    GC_get_stack_base(&stack_base);
+    GC_register_my_thread(&stack_base);
+#endif
+}
+
+extern "C" void unregister_parser_thread()
+{
+#if HAVE_BOEHMGC
+    GC_unregister_my_thread();
+#endif
+}
+
 const char *nix_expr_to_json_str(Parser *parser, const std::function<nix::Expr *()> &get_expr, bool *ok)
 {
     try
     {
         auto expr = get_expr();
 
-        auto json_str = nix_expr_to_json(expr, parser->state->symbols).dump();
+        auto scopes = Scopes{};
+        auto json_str = nix_expr_to_json(expr, parser->state->symbols, parser->state->positions, scopes).dump();
         auto c_str = json_str.c_str();
 
         if (ok)
@@ -336,6 +497,24 @@ const char *nix_expr_to_json_str(Parser *parser, const std::function<nix::Expr *
 
         return strdup(c_str);
     }
+    catch (BaseError &e)
+    {
+        if (ok)
+        {
+            *ok = false;
+        }
+
+        // Report the bare message and its position separately, so that they can be compared with rnix-parser's errors
+        auto &info = e.info();
+        nlohmann::json err = {{"message", filterANSIEscapes(info.msg.str(), true)}};
+        if (info.errPos)
+        {
+            err["line"] = info.errPos->line;
+            err["column"] = info.errPos->column;
+        }
+
+        return strdup(err.dump().c_str());
+    }
     catch (std::exception &e)
     {
         if (ok)
@@ -343,16 +522,16 @@ const char *nix_expr_to_json_str(Parser *parser, const std::function<nix::Expr *
             *ok = false;
         }
 
-        auto what = e.what();
-        return strdup(what);
+        nlohmann::json err = {{"message", e.what()}};
+        return strdup(err.dump().c_str());
     }
 }
 
-extern "C" const char *parse_from_str(Parser *parser, const char *nix_expr, bool *ok)
+extern "C" const char *parse_from_str(Parser *parser, const char *nix_expr, const char *base_path, bool *ok)
 {
     return nix_expr_to_json_str(
         parser, [&]
-        { return parser->state->parseExprFromString(nix_expr, absPath(".")); },
+        { return parser->state->parseExprFromString(nix_expr, base_path); },
         ok);
 }
 
@@ -7,9 +7,13 @@ extern "C" {
 
 struct Parser;
 
-struct Parser *init_parser();
+struct Parser *init_parser(const char *home);
 void destroy_parser(struct Parser *parser);
-const char *parse_from_str(struct Parser *parser, const char *nix_expr, bool* ok);
+const char *nix_version();
+void allow_parser_threads();
+void register_parser_thread();
+void unregister_parser_thread();
+const char *parse_from_str(struct Parser *parser, const char *nix_expr, const char *base_path, bool* ok);
 const char *parse_from_file(Parser *parser, const char *file_path, bool* ok);
 
 #ifdef __cplusplus
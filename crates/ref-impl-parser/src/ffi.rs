@@ -7,11 +7,16 @@ pub(crate) struct Parser {
 }
 
 extern "C" {
-    pub(crate) fn init_parser() -> *const Parser;
+    pub(crate) fn init_parser(home: *const c_char) -> *const Parser;
     pub(crate) fn destroy_parser(parser: *const Parser);
+    pub(crate) fn nix_version() -> *const c_char;
+    pub(crate) fn allow_parser_threads();
+    pub(crate) fn register_parser_thread();
+    pub(crate) fn unregister_parser_thread();
     pub(crate) fn parse_from_str(
         parser: *const Parser,
         nix_expr: *const c_char,
+        base_path: *const c_char,
         ok: *mut bool,
     ) -> *const c_char;
     pub(crate) fn parse_from_file(
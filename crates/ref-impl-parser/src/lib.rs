@@ -1,30 +1,50 @@
 use std::{
     error::Error,
     ffi::{CStr, CString},
+    marker::PhantomData,
     path::Path,
+    str::Utf8Error,
+    sync::OnceLock,
 };
 
+use serde::Deserialize;
+
 mod ffi;
 
+/// The reference impl's parser. It wraps a raw `EvalState` pointer, so it can't be sent to another thread. Each thread
+/// creates its own instead, which any thread but the main one can only do while it holds a [`ThreadRegistration`].
 pub struct Parser {
     ffi_parser: *const ffi::Parser,
 }
 
+/// The home directory that the first parser was created with. Nix only looks it up once, so it can't be changed after.
+static HOME: OnceLock<String> = OnceLock::new();
+
 impl Parser {
-    pub fn new() -> Self {
-        let ffi_parser = unsafe { ffi::init_parser() };
+    /// Create a parser which expands `~` to `home`. Panics if an earlier parser was given a different one.
+    pub fn new(home: &str) -> Self {
+        let chosen_home = HOME.get_or_init(|| home.to_string());
+        assert_eq!(
+            chosen_home, home,
+            "the reference impl's home directory can't be changed once a parser has been created"
+        );
+        let home = CString::new(home).unwrap();
+        let ffi_parser = unsafe { ffi::init_parser(home.as_ptr()) };
         Parser { ffi_parser }
     }
 
-    pub fn parse_from_str<S>(&self, nix_expr: S) -> Result<String, Box<dyn Error>>
+    /// Parse `nix_expr`, resolving relative paths in it against `base_path`, which must be absolute
+    pub fn parse_from_str<S>(&self, nix_expr: S, base_path: &str) -> Result<String, Box<dyn Error>>
     where
         S: AsRef<str>,
     {
         let nix_expr = CString::new(nix_expr.as_ref())?;
         let nix_expr = nix_expr.as_ptr();
+        let base_path = CString::new(base_path)?;
+        let base_path = base_path.as_ptr();
         unsafe {
             let ok = Box::into_raw(Box::new(false));
-            let res = ffi::parse_from_str(self.ffi_parser, nix_expr, ok);
+            let res = ffi::parse_from_str(self.ffi_parser, nix_expr, base_path, ok);
             self.handle_result(res, *Box::from_raw(ok))
         }
     }
@@ -47,11 +67,15 @@ impl Parser {
         json_str: *const i8,
         ok: bool,
     ) -> Result<String, Box<dyn Error>> {
-        let res = CStr::from_ptr(json_str).to_str()?.to_string();
+        let res = CStr::from_ptr(json_str)
+            .to_str()
+            .map_err(BridgeError::NotUtf8)?
+            .to_string();
         if ok {
             Ok(res)
         } else {
-            Err(ReferenceImplError(res))?
+            Err(serde_json::from_str::<ReferenceImplError>(&res)
+                .map_err(BridgeError::InvalidError)?)?
         }
     }
 }
@@ -62,30 +86,99 @@ impl Drop for Parser {
     }
 }
 
-impl Default for Parser {
+/// The version of Nix that the reference impl is built against, e.g. `2.10.3`
+pub fn nix_version() -> String {
+    unsafe { CStr::from_ptr(ffi::nix_version()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Let threads other than the main one register with the reference impl's garbage collector. Has to be called from the
+/// main thread before any other thread creates a [`ThreadRegistration`].
+pub fn allow_parser_threads() {
+    unsafe { ffi::allow_parser_threads() }
+}
+
+/// Registers the current thread with the reference impl's garbage collector until dropped, so that it can create
+/// [`Parser`]s. See [`allow_parser_threads`]. The main thread doesn't need one.
+pub struct ThreadRegistration {
+    // Registration is for the current thread only
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ThreadRegistration {
+    pub fn new() -> Self {
+        unsafe { ffi::register_parser_thread() };
+        ThreadRegistration {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for ThreadRegistration {
+    fn drop(&mut self) {
+        unsafe { ffi::unregister_parser_thread() }
+    }
+}
+
+impl Default for ThreadRegistration {
     fn default() -> Self {
         Self::new()
     }
 }
 
-#[derive(Debug)]
-struct ReferenceImplError(String);
+/// An error thrown by the reference impl while parsing. `line` and `column` are 1-based, and only known for errors that
+/// Nix reports with a position.
+#[derive(Debug, Deserialize)]
+pub struct ReferenceImplError {
+    pub message: String,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
 
 impl std::fmt::Display for ReferenceImplError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.message)?;
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, " at {line}:{column}")?;
+        }
+        Ok(())
     }
 }
 
 impl std::error::Error for ReferenceImplError {}
 
+/// The reference impl's answer couldn't be read. Unlike a [`ReferenceImplError`], this is a bug in the bridge rather
+/// than the reference impl rejecting the source.
+#[derive(Debug)]
+pub enum BridgeError {
+    NotUtf8(Utf8Error),
+    /// The reference impl failed, but didn't say why in the expected shape
+    InvalidError(serde_json::Error),
+}
+
+impl std::fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BridgeError::NotUtf8(err) => {
+                write!(f, "the reference impl's answer isn't UTF-8: {err}")
+            }
+            BridgeError::InvalidError(err) => {
+                write!(f, "invalid error from the reference impl: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BridgeError {}
+
 #[cfg(test)]
 mod reference_to_json_tests {
     use crate::Parser;
 
     #[test]
     fn test_bad_parse_doesnt_crash() {
-        let parser = Parser::new();
-        let _ = parser.parse_from_str("bad expression");
+        let parser = Parser::new("/homeless-shelter");
+        let _ = parser.parse_from_str("bad expression", "/");
     }
 }
@@ -1,6 +1,7 @@
 pub use rnix::ast::{BinOpKind, UnaryOpKind};
+use serde::Serialize;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum RNixExpr {
     Apply(Apply),
     Assert(Assert),
@@ -24,200 +25,297 @@ pub enum RNixExpr {
     HasAttr(HasAttr),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl RNixExpr {
+    pub fn span(&self) -> Span {
+        match self {
+            RNixExpr::Apply(expr) => expr.span,
+            RNixExpr::Assert(expr) => expr.span,
+            RNixExpr::IfElse(expr) => expr.span,
+            RNixExpr::Select(expr) => expr.span,
+            RNixExpr::Str(expr) => expr.span,
+            RNixExpr::Path(expr) => expr.span,
+            RNixExpr::Literal(expr) => expr.span,
+            RNixExpr::Lambda(expr) => expr.span,
+            RNixExpr::LegacyLet(expr) => expr.span,
+            RNixExpr::LetIn(expr) => expr.span,
+            RNixExpr::List(expr) => expr.span,
+            RNixExpr::BinOp(expr) => expr.span,
+            RNixExpr::Paren(expr) => expr.span,
+            RNixExpr::Root(expr) => expr.span,
+            RNixExpr::AttrSet(expr) => expr.span,
+            RNixExpr::UnaryOp(expr) => expr.span,
+            RNixExpr::Ident(expr) => expr.span,
+            RNixExpr::With(expr) => expr.span,
+            RNixExpr::HasAttr(expr) => expr.span,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Apply {
     pub lambda: Box<RNixExpr>,
     pub argument: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Assert {
     pub condition: Box<RNixExpr>,
     pub body: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct IfElse {
     pub condition: Box<RNixExpr>,
     pub body: Box<RNixExpr>,
     pub else_body: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Select {
     pub expr: Box<RNixExpr>,
     pub attrpath: Attrpath,
     pub default_expr: Option<Box<RNixExpr>>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Str {
     pub parts: Vec<InterpolPart<String>>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Path {
     pub parts: Vec<InterpolPart<String>>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Inherit {
     pub from: Option<InheritFrom>,
-    pub idents: Vec<Ident>,
+    /// Usually idents. Nix also accepts strings and `${...}` here, as long as they're constant.
+    pub attrs: Vec<Attr>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct InheritFrom {
     pub expr: Box<RNixExpr>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Literal {
     pub kind: LiteralKind,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Lambda {
     pub param: Param,
     pub body: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct LegacyLet {
     pub entries: Vec<Entry>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct LetIn {
     pub entries: Vec<Entry>,
     pub body: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct List {
     pub items: Vec<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct BinOp {
     pub lhs: Box<RNixExpr>,
+    #[serde(with = "BinOpKindDef")]
     pub operator: BinOpKind,
     pub rhs: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Paren {
     pub expr: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Root {
     pub expr: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct AttrSet {
     pub entries: Vec<Entry>,
     pub recursive: bool,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct UnaryOp {
+    #[serde(with = "UnaryOpKindDef")]
     pub operator: UnaryOpKind,
     pub expr: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Ident {
     pub inner: String,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct With {
     pub namespace: Box<RNixExpr>,
     pub body: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct HasAttr {
     pub expr: Box<RNixExpr>,
     pub attrpath: Attrpath,
+    pub span: Span,
+}
+
+/// Where a node came from in the source, as byte offsets
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
 }
 
 // == Nodes that don't appear at the top level ==
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Attrpath {
     pub attrs: Vec<Attr>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum Attr {
     Ident(Ident),
     Dynamic(Dynamic),
     Str(Str),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum LiteralKind {
     Float(f64),
     Integer(i64),
+    /// An integer literal which doesn't fit in an i64. Nix rejects these, so rather than failing the conversion, we
+    /// keep the literal text around so that the rejection can be compared.
+    IntegerOutOfRange(String),
+    /// A float literal which overflows to infinity or underflows to zero. Nix rejects these too, and they're kept the
+    /// same way.
+    FloatOutOfRange(String),
     Uri(String),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Dynamic {
     pub expr: Box<RNixExpr>,
+    pub span: Span,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum Param {
     Pattern(Pattern),
     IdentParam(IdentParam),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Pattern {
     pub pat_entries: Vec<PatEntry>,
     pub ellipsis: bool,
     pub pat_bind: Option<PatBind>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct PatEntry {
     pub ident: Ident,
     pub default: Option<Box<RNixExpr>>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct PatBind {
     pub ident: Ident,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct IdentParam {
     pub ident: Ident,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum Entry {
     Inherit(Inherit),
     AttrpathValue(AttrpathValue),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct AttrpathValue {
     pub attrpath: Attrpath,
     pub value: Box<RNixExpr>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum InterpolPart<T> {
     Literal(T),
     Interpolation(StrInterpol),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct StrInterpol {
     pub expr: Box<RNixExpr>,
 }
+
+// == Serialization of the operator kinds, which are defined by rnix and so can't derive Serialize themselves ==
+
+#[derive(Serialize)]
+#[serde(remote = "BinOpKind")]
+enum BinOpKindDef {
+    Concat,
+    Update,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Equal,
+    Implication,
+    Less,
+    LessOrEq,
+    More,
+    MoreOrEq,
+    NotEqual,
+    Or,
+}
+
+#[derive(Serialize)]
+#[serde(remote = "UnaryOpKind")]
+enum UnaryOpKindDef {
+    Invert,
+    Negate,
+}
@@ -1,6 +1,10 @@
-use std::{fmt, num};
+use std::{collections::HashSet, fmt, num};
 
-use rnix::{ast::AstToken, parser::ParseError, Parse, Root};
+use rnix::{
+    ast::{AstNode, AstToken},
+    parser::ParseError,
+    Parse, Root, SyntaxKind, TextRange,
+};
 
 use crate::ast::{self, RNixExpr};
 
@@ -33,25 +37,75 @@ impl fmt::Display for ToAstError {
 
 impl std::error::Error for ToAstError {}
 
+/// Identifies a node of the CST. Nodes are identified by kind and range rather than by [`rnix::SyntaxNode`], because
+/// every call to [`Parse::syntax`] creates a fresh tree whose nodes don't compare equal to those of any other.
+pub type NodeKey = (SyntaxKind, TextRange);
+
+/// Told about every CST node which the conversion looks at. `()` ignores them, which is what a plain conversion uses.
+pub trait Visitor {
+    fn visit(&mut self, node: &rnix::SyntaxNode);
+}
+
+impl Visitor for () {
+    fn visit(&mut self, _node: &rnix::SyntaxNode) {}
+}
+
+impl Visitor for HashSet<NodeKey> {
+    fn visit(&mut self, node: &rnix::SyntaxNode) {
+        self.insert((node.kind(), node.text_range()));
+    }
+}
+
+/// Convert as usual, but also collect every CST node which the conversion looked at. Any node not in the returned
+/// set was ignored by the conversion.
+pub fn convert_recording_visited(
+    parse: Parse<Root>,
+) -> (Result<RNixExpr, ToAstError>, HashSet<NodeKey>) {
+    let mut visited = HashSet::new();
+    let res = convert_parse(parse, &mut visited);
+    (res, visited)
+}
+
+fn visit(node: &impl AstNode, v: &mut impl Visitor) {
+    v.visit(node.syntax());
+}
+
+fn span(node: &impl AstNode) -> ast::Span {
+    let range = node.syntax().text_range();
+    ast::Span {
+        start: range.start().into(),
+        end: range.end().into(),
+    }
+}
+
 impl TryFrom<Parse<Root>> for RNixExpr {
     type Error = ToAstError;
 
     fn try_from(value: Parse<Root>) -> Result<Self, Self::Error> {
-        let value =
-            value
-                .ok()
-                .map_err(ToAstError::ParseError)?
-                .expr()
-                .ok_or(ToAstError::EmptyBranch(
-                    "Root has no inner expression".to_string(),
-                ))?;
-        RNixExpr::try_from(value)
+        convert_parse(value, &mut ())
+    }
+}
+
+impl TryFrom<rnix::ast::Expr> for RNixExpr {
+    type Error = ToAstError;
+
+    fn try_from(value: rnix::ast::Expr) -> Result<Self, Self::Error> {
+        convert_expr(value, &mut ())
     }
 }
 
+fn convert_parse(value: Parse<Root>, v: &mut impl Visitor) -> Result<RNixExpr, ToAstError> {
+    let root = value.ok().map_err(ToAstError::ParseError)?;
+    visit(&root, v);
+    let value = root.expr().ok_or(ToAstError::EmptyBranch(
+        "Root has no inner expression".to_string(),
+    ))?;
+    convert_expr(value, v)
+}
+
 macro_rules! try_convert {
-    ($e:expr) => {
-        try_convert_with!($e, |value| Ok(Box::new(RNixExpr::try_from(value)?)))
+    ($e:expr, $v:expr) => {
+        try_convert_with!($e, |value| Ok(Box::new(convert_expr(value, $v)?)))
     };
 }
 
@@ -72,91 +126,162 @@ macro_rules! try_convert_with {
     };
 }
 
-impl TryFrom<rnix::ast::Expr> for RNixExpr {
-    type Error = ToAstError;
+fn convert_expr(value: rnix::ast::Expr, v: &mut impl Visitor) -> Result<RNixExpr, ToAstError> {
+    visit(&value, v);
+    match value {
+        rnix::ast::Expr::Apply(apply) => convert_apply(apply, v).map(RNixExpr::Apply),
+        rnix::ast::Expr::Assert(assert) => convert_assert(assert, v).map(RNixExpr::Assert),
+        rnix::ast::Expr::Error(_) => unreachable!("This should have been caught by convert_parse"),
+        rnix::ast::Expr::IfElse(if_else) => convert_if_else(if_else, v).map(RNixExpr::IfElse),
+        rnix::ast::Expr::Select(select) => convert_select(select, v).map(RNixExpr::Select),
+        rnix::ast::Expr::Str(str) => convert_str(str, v).map(RNixExpr::Str),
+        rnix::ast::Expr::Path(path) => convert_path(path, v).map(RNixExpr::Path),
+        rnix::ast::Expr::Literal(literal) => convert_literal(literal).map(RNixExpr::Literal),
+        rnix::ast::Expr::Lambda(lambda) => convert_lambda(lambda, v).map(RNixExpr::Lambda),
+        rnix::ast::Expr::LegacyLet(legacy_let) => {
+            convert_legacy_let(legacy_let, v).map(RNixExpr::LegacyLet)
+        }
+        rnix::ast::Expr::LetIn(let_in) => convert_let_in(let_in, v).map(RNixExpr::LetIn),
+        rnix::ast::Expr::List(list) => convert_list(list, v).map(RNixExpr::List),
+        rnix::ast::Expr::BinOp(bin_op) => convert_bin_op(bin_op, v).map(RNixExpr::BinOp),
+        rnix::ast::Expr::Paren(paren) => convert_paren(paren, v).map(RNixExpr::Paren),
+        rnix::ast::Expr::Root(root) => convert_root(root, v).map(RNixExpr::Root),
+        rnix::ast::Expr::AttrSet(attr_set) => convert_attr_set(attr_set, v).map(RNixExpr::AttrSet),
+        rnix::ast::Expr::UnaryOp(unary_op) => convert_unary_op(unary_op, v).map(RNixExpr::UnaryOp),
+        rnix::ast::Expr::Ident(ident) => convert_ident(ident, v).map(RNixExpr::Ident),
+        rnix::ast::Expr::With(with) => convert_with(with, v).map(RNixExpr::With),
+        rnix::ast::Expr::HasAttr(has_attr) => convert_has_attr(has_attr, v).map(RNixExpr::HasAttr),
+    }
+}
 
-    fn try_from(value: rnix::ast::Expr) -> Result<Self, Self::Error> {
-        match value {
-            rnix::ast::Expr::Apply(apply) => convert_apply(apply).map(RNixExpr::Apply),
-            rnix::ast::Expr::Assert(assert) => convert_assert(assert).map(RNixExpr::Assert),
-            rnix::ast::Expr::Error(_) => unreachable!(
-                "This should have been caught by impl TryFrom<Parse<Root>> for RNixExpr"
-            ),
-            rnix::ast::Expr::IfElse(if_else) => convert_if_else(if_else).map(RNixExpr::IfElse),
-            rnix::ast::Expr::Select(select) => convert_select(select).map(RNixExpr::Select),
-            rnix::ast::Expr::Str(str) => convert_str(str).map(RNixExpr::Str),
-            rnix::ast::Expr::Path(path) => convert_path(path).map(RNixExpr::Path),
-            rnix::ast::Expr::Literal(literal) => convert_literal(literal).map(RNixExpr::Literal),
-            rnix::ast::Expr::Lambda(lambda) => convert_lambda(lambda).map(RNixExpr::Lambda),
-            rnix::ast::Expr::LegacyLet(legacy_let) => {
-                convert_legacy_let(legacy_let).map(RNixExpr::LegacyLet)
+/// rnix-parser reads `f a or` as `(f a) or`, but Nix's `expr_simple OR_KW` rule only takes the `or` as an argument of
+/// the expression right before it, giving `f (a or)`. See [`apply_or`].
+fn convert_apply(apply: rnix::ast::Apply, v: &mut impl Visitor) -> Result<ast::Apply, ToAstError> {
+    let lambda = try_convert!(apply.lambda(), v);
+    let argument = try_convert!(apply.argument(), v);
+    Ok(match *lambda {
+        RNixExpr::Apply(inner) if is_or_ident(&argument) && is_simple(&inner.argument) => {
+            ast::Apply {
+                lambda: inner.lambda,
+                argument: Box::new(apply_or(*inner.argument, *argument)),
+                span: span(&apply),
             }
-            rnix::ast::Expr::LetIn(let_in) => convert_let_in(let_in).map(RNixExpr::LetIn),
-            rnix::ast::Expr::List(list) => convert_list(list).map(RNixExpr::List),
-            rnix::ast::Expr::BinOp(bin_op) => convert_bin_op(bin_op).map(RNixExpr::BinOp),
-            rnix::ast::Expr::Paren(paren) => convert_paren(paren).map(RNixExpr::Paren),
-            rnix::ast::Expr::Root(root) => convert_root(root).map(RNixExpr::Root),
-            rnix::ast::Expr::AttrSet(attr_set) => convert_attr_set(attr_set).map(RNixExpr::AttrSet),
-            rnix::ast::Expr::UnaryOp(unary_op) => convert_unary_op(unary_op).map(RNixExpr::UnaryOp),
-            rnix::ast::Expr::Ident(ident) => convert_ident(ident).map(RNixExpr::Ident),
-            rnix::ast::Expr::With(with) => convert_with(with).map(RNixExpr::With),
-            rnix::ast::Expr::HasAttr(has_attr) => convert_has_attr(has_attr).map(RNixExpr::HasAttr),
         }
-    }
+        lambda => ast::Apply {
+            lambda: Box::new(lambda),
+            argument,
+            span: span(&apply),
+        },
+    })
 }
 
-fn convert_apply(apply: rnix::ast::Apply) -> Result<ast::Apply, ToAstError> {
-    Ok(ast::Apply {
-        lambda: try_convert!(apply.lambda()),
-        argument: try_convert!(apply.argument()),
+/// Nix lets `or` stand for a variable called `or`, but only directly after an `expr_simple`, which is then applied to
+/// it: `[ 1 or 2 ]` is a list of `1 or` (i.e., `1` called with `or`) and `2`. rnix-parser gives the `or` an ident node
+/// of its own instead, so this puts it back where Nix would.
+fn apply_or(lambda: RNixExpr, or: RNixExpr) -> RNixExpr {
+    RNixExpr::Apply(ast::Apply {
+        span: ast::Span {
+            start: lambda.span().start,
+            end: or.span().end,
+        },
+        lambda: Box::new(lambda),
+        argument: Box::new(or),
     })
 }
 
-fn convert_assert(assert: rnix::ast::Assert) -> Result<ast::Assert, ToAstError> {
+fn is_or_ident(expr: &RNixExpr) -> bool {
+    matches!(expr, RNixExpr::Ident(ident) if ident.inner == "or")
+}
+
+/// Whether `expr` is an `expr_simple` in Nix's grammar, i.e., something that an `or` can follow.
+fn is_simple(expr: &RNixExpr) -> bool {
+    matches!(
+        expr,
+        RNixExpr::Ident(_)
+            | RNixExpr::Literal(_)
+            | RNixExpr::Str(_)
+            | RNixExpr::Path(_)
+            | RNixExpr::Paren(_)
+            | RNixExpr::LegacyLet(_)
+            | RNixExpr::AttrSet(_)
+            | RNixExpr::List(_)
+    ) && !is_or_ident(expr)
+}
+
+fn convert_assert(
+    assert: rnix::ast::Assert,
+    v: &mut impl Visitor,
+) -> Result<ast::Assert, ToAstError> {
     Ok(ast::Assert {
-        condition: try_convert!(assert.condition()),
-        body: try_convert!(assert.body()),
+        condition: try_convert!(assert.condition(), v),
+        body: try_convert!(assert.body(), v),
+        span: span(&assert),
     })
 }
 
-fn convert_ident(ident: rnix::ast::Ident) -> Result<ast::Ident, ToAstError> {
+fn convert_ident(ident: rnix::ast::Ident, v: &mut impl Visitor) -> Result<ast::Ident, ToAstError> {
+    visit(&ident, v);
     Ok(ast::Ident {
         inner: ident.to_string(),
+        span: span(&ident),
     })
 }
 
-fn convert_if_else(if_else: rnix::ast::IfElse) -> Result<ast::IfElse, ToAstError> {
+fn convert_if_else(
+    if_else: rnix::ast::IfElse,
+    v: &mut impl Visitor,
+) -> Result<ast::IfElse, ToAstError> {
     Ok(ast::IfElse {
-        condition: try_convert!(if_else.condition()),
-        body: try_convert!(if_else.body()),
-        else_body: try_convert!(if_else.else_body()),
+        condition: try_convert!(if_else.condition(), v),
+        body: try_convert!(if_else.body(), v),
+        else_body: try_convert!(if_else.else_body(), v),
+        span: span(&if_else),
     })
 }
 
-fn convert_select(select: rnix::ast::Select) -> Result<ast::Select, ToAstError> {
+fn convert_select(
+    select: rnix::ast::Select,
+    v: &mut impl Visitor,
+) -> Result<ast::Select, ToAstError> {
     Ok(ast::Select {
-        expr: try_convert!(select.expr()),
-        attrpath: try_convert_with!(select.attrpath(), convert_attrpath),
+        expr: try_convert!(select.expr(), v),
+        attrpath: try_convert_with!(select.attrpath(), |x| convert_attrpath(x, v)),
         default_expr: select
             .default_expr()
-            .map(|default| RNixExpr::try_from(default))
+            .map(|default| convert_expr(default, v))
             .transpose()?
             .map(|default| Box::new(default)),
+        span: span(&select),
     })
 }
 
-fn convert_inherit(inherit: rnix::ast::Inherit) -> Result<ast::Inherit, ToAstError> {
+fn convert_inherit(
+    inherit: rnix::ast::Inherit,
+    v: &mut impl Visitor,
+) -> Result<ast::Inherit, ToAstError> {
+    visit(&inherit, v);
     Ok(ast::Inherit {
-        from: inherit.from().map(convert_inherit_from).transpose()?,
-        idents: try_convert_all_with!(inherit.idents(), convert_ident),
+        from: inherit
+            .from()
+            .map(|from| convert_inherit_from(from, v))
+            .transpose()?,
+        attrs: try_convert_all_with!(
+            inherit
+                .syntax()
+                .children()
+                .filter_map(rnix::ast::Attr::cast),
+            |x| convert_attr(x, v)
+        ),
     })
 }
 
 fn convert_inherit_from(
     inherit_from: rnix::ast::InheritFrom,
+    v: &mut impl Visitor,
 ) -> Result<ast::InheritFrom, ToAstError> {
+    visit(&inherit_from, v);
     Ok(ast::InheritFrom {
-        expr: try_convert!(inherit_from.expr()),
+        expr: try_convert!(inherit_from.expr(), v),
     })
 }
 
@@ -164,100 +289,164 @@ fn convert_literal(literal: rnix::ast::Literal) -> Result<ast::Literal, ToAstErr
     Ok(ast::Literal {
         kind: match literal.kind() {
             rnix::ast::LiteralKind::Float(float) => {
-                ast::LiteralKind::Float(float.value().map_err(ToAstError::ParseFloatError)?)
-            }
-            rnix::ast::LiteralKind::Integer(integer) => {
-                ast::LiteralKind::Integer(integer.value().map_err(ToAstError::ParseIntError)?)
+                let value = float.value().map_err(ToAstError::ParseFloatError)?;
+                if float_out_of_range(&float.to_string(), value) {
+                    ast::LiteralKind::FloatOutOfRange(float.to_string())
+                } else {
+                    ast::LiteralKind::Float(value)
+                }
             }
+            rnix::ast::LiteralKind::Integer(integer) => match integer.value() {
+                Ok(n) => ast::LiteralKind::Integer(n),
+                Err(e) if *e.kind() == num::IntErrorKind::PosOverflow => {
+                    ast::LiteralKind::IntegerOutOfRange(integer.to_string())
+                }
+                Err(e) => return Err(ToAstError::ParseIntError(e)),
+            },
             rnix::ast::LiteralKind::Uri(uri) => ast::LiteralKind::Uri(uri.to_string()),
         },
+        span: span(&literal),
     })
 }
 
-fn convert_lambda(lambda: rnix::ast::Lambda) -> Result<ast::Lambda, ToAstError> {
+/// Whether `value`, parsed from the float literal `literal`, is out of range, i.e. it overflowed to infinity, or
+/// underflowed to zero even though the literal isn't zero. Those are where strtod, which Nix parses floats with, sets
+/// `ERANGE`.
+fn float_out_of_range(literal: &str, value: f64) -> bool {
+    let mantissa = literal.split(['e', 'E']).next().unwrap_or_default();
+    value.is_infinite() || (value == 0.0 && mantissa.contains(|c| matches!(c, '1'..='9')))
+}
+
+fn convert_lambda(
+    lambda: rnix::ast::Lambda,
+    v: &mut impl Visitor,
+) -> Result<ast::Lambda, ToAstError> {
     Ok(ast::Lambda {
-        param: try_convert_with!(lambda.param(), convert_param),
-        body: try_convert!(lambda.body()),
+        param: try_convert_with!(lambda.param(), |x| convert_param(x, v)),
+        body: try_convert!(lambda.body(), v),
+        span: span(&lambda),
     })
 }
 
-fn convert_legacy_let(legacy_let: rnix::ast::LegacyLet) -> Result<ast::LegacyLet, ToAstError> {
+fn convert_legacy_let(
+    legacy_let: rnix::ast::LegacyLet,
+    v: &mut impl Visitor,
+) -> Result<ast::LegacyLet, ToAstError> {
     Ok(ast::LegacyLet {
-        entries: entries_from_holder(&legacy_let)?,
+        entries: entries_from_holder(&legacy_let, v)?,
+        span: span(&legacy_let),
     })
 }
 
-fn convert_let_in(let_in: rnix::ast::LetIn) -> Result<ast::LetIn, ToAstError> {
+fn convert_let_in(
+    let_in: rnix::ast::LetIn,
+    v: &mut impl Visitor,
+) -> Result<ast::LetIn, ToAstError> {
     Ok(ast::LetIn {
-        entries: entries_from_holder(&let_in)?,
-        body: try_convert!(let_in.body()),
+        entries: entries_from_holder(&let_in, v)?,
+        body: try_convert!(let_in.body(), v),
+        span: span(&let_in),
     })
 }
 
-fn convert_list(list: rnix::ast::List) -> Result<ast::List, ToAstError> {
+/// An `or` item is folded into the item before it, as described in [`apply_or`].
+fn convert_list(list: rnix::ast::List, v: &mut impl Visitor) -> Result<ast::List, ToAstError> {
+    let mut items: Vec<RNixExpr> = vec![];
+    for item in try_convert_all_with!(list.items(), |x| convert_expr(x, v)) {
+        if is_or_ident(&item) && items.last().map_or(false, is_simple) {
+            let prev = items.pop().unwrap();
+            items.push(apply_or(prev, item));
+        } else {
+            items.push(item);
+        }
+    }
     Ok(ast::List {
-        items: try_convert_all_with!(list.items(), RNixExpr::try_from),
+        items,
+        span: span(&list),
     })
 }
 
-fn convert_bin_op(bin_op: rnix::ast::BinOp) -> Result<ast::BinOp, ToAstError> {
+fn convert_bin_op(
+    bin_op: rnix::ast::BinOp,
+    v: &mut impl Visitor,
+) -> Result<ast::BinOp, ToAstError> {
     Ok(ast::BinOp {
-        lhs: try_convert!(bin_op.lhs()),
+        lhs: try_convert!(bin_op.lhs(), v),
         operator: bin_op
             .operator()
             .ok_or(ToAstError::EmptyBranch("BinOp has no operator".to_string()))?,
-        rhs: try_convert!(bin_op.rhs()),
+        rhs: try_convert!(bin_op.rhs(), v),
+        span: span(&bin_op),
     })
 }
 
-fn convert_paren(paren: rnix::ast::Paren) -> Result<ast::Paren, ToAstError> {
+fn convert_paren(paren: rnix::ast::Paren, v: &mut impl Visitor) -> Result<ast::Paren, ToAstError> {
     Ok(ast::Paren {
-        expr: try_convert!(paren.expr()),
+        expr: try_convert!(paren.expr(), v),
+        span: span(&paren),
     })
 }
 
-fn convert_root(root: rnix::ast::Root) -> Result<ast::Root, ToAstError> {
+fn convert_root(root: rnix::ast::Root, v: &mut impl Visitor) -> Result<ast::Root, ToAstError> {
     Ok(ast::Root {
-        expr: try_convert!(root.expr()),
+        expr: try_convert!(root.expr(), v),
+        span: span(&root),
     })
 }
 
-fn convert_attr_set(attr_set: rnix::ast::AttrSet) -> Result<ast::AttrSet, ToAstError> {
+fn convert_attr_set(
+    attr_set: rnix::ast::AttrSet,
+    v: &mut impl Visitor,
+) -> Result<ast::AttrSet, ToAstError> {
     Ok(ast::AttrSet {
-        entries: entries_from_holder(&attr_set)?,
+        entries: entries_from_holder(&attr_set, v)?,
         recursive: attr_set.rec_token().is_some(),
+        span: span(&attr_set),
     })
 }
 
-fn convert_str(str: rnix::ast::Str) -> Result<ast::Str, ToAstError> {
+fn convert_str(str: rnix::ast::Str, v: &mut impl Visitor) -> Result<ast::Str, ToAstError> {
+    visit(&str, v);
     Ok(ast::Str {
-        parts: try_convert_all_with!(str.normalized_parts().into_iter(), convert_interpol_part),
+        parts: try_convert_all_with!(str.normalized_parts().into_iter(), |x| {
+            convert_interpol_part(x, v)
+        }),
+        span: span(&str),
     })
 }
 
-fn convert_interpol(str_interpol: rnix::ast::Interpol) -> Result<ast::StrInterpol, ToAstError> {
+fn convert_interpol(
+    str_interpol: rnix::ast::Interpol,
+    v: &mut impl Visitor,
+) -> Result<ast::StrInterpol, ToAstError> {
+    visit(&str_interpol, v);
     Ok(ast::StrInterpol {
-        expr: try_convert!(str_interpol.expr()),
+        expr: try_convert!(str_interpol.expr(), v),
     })
 }
 
-fn convert_unary_op(unary_op: rnix::ast::UnaryOp) -> Result<ast::UnaryOp, ToAstError> {
+fn convert_unary_op(
+    unary_op: rnix::ast::UnaryOp,
+    v: &mut impl Visitor,
+) -> Result<ast::UnaryOp, ToAstError> {
     Ok(ast::UnaryOp {
         operator: unary_op.operator().ok_or(ToAstError::EmptyBranch(
             "UnaryOp has no operator".to_string(),
         ))?,
-        expr: try_convert!(unary_op.expr()),
+        expr: try_convert!(unary_op.expr(), v),
+        span: span(&unary_op),
     })
 }
 
-fn convert_with(with: rnix::ast::With) -> Result<ast::With, ToAstError> {
+fn convert_with(with: rnix::ast::With, v: &mut impl Visitor) -> Result<ast::With, ToAstError> {
     Ok(ast::With {
-        namespace: try_convert!(with.namespace()),
-        body: try_convert!(with.body()),
+        namespace: try_convert!(with.namespace(), v),
+        body: try_convert!(with.body(), v),
+        span: span(&with),
     })
 }
 
-fn convert_path(path: rnix::ast::Path) -> Result<ast::Path, ToAstError> {
+fn convert_path(path: rnix::ast::Path, v: &mut impl Visitor) -> Result<ast::Path, ToAstError> {
     Ok(ast::Path {
         parts: try_convert_all_with!(path.parts().into_iter(), |part| {
             Ok(match part {
@@ -265,114 +454,151 @@ fn convert_path(path: rnix::ast::Path) -> Result<ast::Path, ToAstError> {
                     ast::InterpolPart::Literal(literal.syntax().text().to_string())
                 }
                 rnix::ast::InterpolPart::Interpolation(interpol) => {
-                    ast::InterpolPart::Interpolation(convert_interpol(interpol)?)
+                    ast::InterpolPart::Interpolation(convert_interpol(interpol, v)?)
                 }
             })
         }),
+        span: span(&path),
     })
 }
 
-fn convert_has_attr(has_attr: rnix::ast::HasAttr) -> Result<ast::HasAttr, ToAstError> {
+fn convert_has_attr(
+    has_attr: rnix::ast::HasAttr,
+    v: &mut impl Visitor,
+) -> Result<ast::HasAttr, ToAstError> {
     Ok(ast::HasAttr {
-        expr: try_convert!(has_attr.expr()),
-        attrpath: try_convert_with!(has_attr.attrpath(), convert_attrpath),
+        expr: try_convert!(has_attr.expr(), v),
+        attrpath: try_convert_with!(has_attr.attrpath(), |x| convert_attrpath(x, v)),
+        span: span(&has_attr),
     })
 }
 
 fn convert_interpol_part<T>(
     part: rnix::ast::InterpolPart<T>,
+    v: &mut impl Visitor,
 ) -> Result<ast::InterpolPart<T>, ToAstError> {
     Ok(match part {
         rnix::ast::InterpolPart::Literal(lit) => ast::InterpolPart::Literal(lit),
         rnix::ast::InterpolPart::Interpolation(interpol) => {
-            ast::InterpolPart::Interpolation(convert_interpol(interpol)?)
+            ast::InterpolPart::Interpolation(convert_interpol(interpol, v)?)
         }
     })
 }
 
 fn entries_from_holder(
     entry_holder: &impl rnix::ast::HasEntry,
+    v: &mut impl Visitor,
 ) -> Result<Vec<ast::Entry>, ToAstError> {
-    Ok(try_convert_all_with!(entry_holder.entries(), convert_entry))
+    Ok(try_convert_all_with!(entry_holder.entries(), |x| {
+        convert_entry(x, v)
+    }))
 }
 
-fn convert_entry(entry: rnix::ast::Entry) -> Result<ast::Entry, ToAstError> {
+fn convert_entry(entry: rnix::ast::Entry, v: &mut impl Visitor) -> Result<ast::Entry, ToAstError> {
     match entry {
-        rnix::ast::Entry::Inherit(inherit) => Ok(ast::Entry::Inherit(convert_inherit(inherit)?)),
+        rnix::ast::Entry::Inherit(inherit) => Ok(ast::Entry::Inherit(convert_inherit(inherit, v)?)),
         rnix::ast::Entry::AttrpathValue(attrpath_value) => Ok(ast::Entry::AttrpathValue(
-            convert_attrpath_value(attrpath_value)?,
+            convert_attrpath_value(attrpath_value, v)?,
         )),
     }
 }
 
 fn convert_attrpath_value(
     attrpath_value: rnix::ast::AttrpathValue,
+    v: &mut impl Visitor,
 ) -> Result<ast::AttrpathValue, ToAstError> {
+    visit(&attrpath_value, v);
     Ok(ast::AttrpathValue {
-        attrpath: try_convert_with!(attrpath_value.attrpath(), convert_attrpath),
-        value: try_convert!(attrpath_value.value()),
+        attrpath: try_convert_with!(attrpath_value.attrpath(), |x| convert_attrpath(x, v)),
+        value: try_convert!(attrpath_value.value(), v),
     })
 }
 
-fn convert_param(param: rnix::ast::Param) -> Result<ast::Param, ToAstError> {
+fn convert_param(param: rnix::ast::Param, v: &mut impl Visitor) -> Result<ast::Param, ToAstError> {
     match param {
-        rnix::ast::Param::Pattern(pattern) => convert_pattern(pattern).map(ast::Param::Pattern),
+        rnix::ast::Param::Pattern(pattern) => convert_pattern(pattern, v).map(ast::Param::Pattern),
         rnix::ast::Param::IdentParam(ident_param) => {
-            convert_ident_param(ident_param).map(ast::Param::IdentParam)
+            convert_ident_param(ident_param, v).map(ast::Param::IdentParam)
         }
     }
 }
 
-fn convert_pattern(pattern: rnix::ast::Pattern) -> Result<ast::Pattern, ToAstError> {
+fn convert_pattern(
+    pattern: rnix::ast::Pattern,
+    v: &mut impl Visitor,
+) -> Result<ast::Pattern, ToAstError> {
+    visit(&pattern, v);
     Ok(ast::Pattern {
-        pat_entries: try_convert_all_with!(pattern.pat_entries(), convert_pat_entry),
+        pat_entries: try_convert_all_with!(pattern.pat_entries(), |x| convert_pat_entry(x, v)),
         ellipsis: pattern.ellipsis_token().is_some(),
         pat_bind: pattern
             .pat_bind()
-            .map(|pat_bind| convert_pat_bind(pat_bind))
+            .map(|pat_bind| convert_pat_bind(pat_bind, v))
             .transpose()?,
     })
 }
 
-fn convert_pat_bind(pat_bind: rnix::ast::PatBind) -> Result<ast::PatBind, ToAstError> {
+fn convert_pat_bind(
+    pat_bind: rnix::ast::PatBind,
+    v: &mut impl Visitor,
+) -> Result<ast::PatBind, ToAstError> {
+    visit(&pat_bind, v);
     Ok(ast::PatBind {
-        ident: try_convert_with!(pat_bind.ident(), convert_ident),
+        ident: try_convert_with!(pat_bind.ident(), |x| convert_ident(x, v)),
     })
 }
 
-fn convert_pat_entry(pat_entry: rnix::ast::PatEntry) -> Result<ast::PatEntry, ToAstError> {
+fn convert_pat_entry(
+    pat_entry: rnix::ast::PatEntry,
+    v: &mut impl Visitor,
+) -> Result<ast::PatEntry, ToAstError> {
+    visit(&pat_entry, v);
     Ok(ast::PatEntry {
-        ident: try_convert_with!(pat_entry.ident(), convert_ident),
+        ident: try_convert_with!(pat_entry.ident(), |x| convert_ident(x, v)),
         default: pat_entry
             .default()
-            .map(|default| RNixExpr::try_from(default))
+            .map(|default| convert_expr(default, v))
             .transpose()?
             .map(|default| Box::new(default)),
     })
 }
 
-fn convert_ident_param(ident_param: rnix::ast::IdentParam) -> Result<ast::IdentParam, ToAstError> {
+fn convert_ident_param(
+    ident_param: rnix::ast::IdentParam,
+    v: &mut impl Visitor,
+) -> Result<ast::IdentParam, ToAstError> {
+    visit(&ident_param, v);
     Ok(ast::IdentParam {
-        ident: try_convert_with!(ident_param.ident(), convert_ident),
+        ident: try_convert_with!(ident_param.ident(), |x| convert_ident(x, v)),
     })
 }
 
-fn convert_attrpath(attrpath: rnix::ast::Attrpath) -> Result<ast::Attrpath, ToAstError> {
+fn convert_attrpath(
+    attrpath: rnix::ast::Attrpath,
+    v: &mut impl Visitor,
+) -> Result<ast::Attrpath, ToAstError> {
+    visit(&attrpath, v);
     Ok(ast::Attrpath {
-        attrs: try_convert_all_with!(attrpath.attrs(), convert_attr),
+        attrs: try_convert_all_with!(attrpath.attrs(), |x| convert_attr(x, v)),
     })
 }
 
-fn convert_attr(attr: rnix::ast::Attr) -> Result<ast::Attr, ToAstError> {
+fn convert_attr(attr: rnix::ast::Attr, v: &mut impl Visitor) -> Result<ast::Attr, ToAstError> {
+    visit(&attr, v);
     match attr {
-        rnix::ast::Attr::Ident(ident) => Ok(ast::Attr::Ident(convert_ident(ident)?)),
-        rnix::ast::Attr::Dynamic(dynamic) => Ok(ast::Attr::Dynamic(convert_dynamic(dynamic)?)),
-        rnix::ast::Attr::Str(str) => Ok(ast::Attr::Str(convert_str(str)?)),
+        rnix::ast::Attr::Ident(ident) => Ok(ast::Attr::Ident(convert_ident(ident, v)?)),
+        rnix::ast::Attr::Dynamic(dynamic) => Ok(ast::Attr::Dynamic(convert_dynamic(dynamic, v)?)),
+        rnix::ast::Attr::Str(str) => Ok(ast::Attr::Str(convert_str(str, v)?)),
     }
 }
 
-fn convert_dynamic(dynamic: rnix::ast::Dynamic) -> Result<ast::Dynamic, ToAstError> {
+fn convert_dynamic(
+    dynamic: rnix::ast::Dynamic,
+    v: &mut impl Visitor,
+) -> Result<ast::Dynamic, ToAstError> {
+    visit(&dynamic, v);
     Ok(ast::Dynamic {
-        expr: try_convert!(dynamic.expr()),
+        expr: try_convert!(dynamic.expr(), v),
+        span: span(&dynamic),
     })
 }
@@ -0,0 +1,727 @@
+//! [`proptest`] strategies for generating random, grammatically-valid Nix expressions.
+//!
+//! Every generated [`RNixExpr`] comes with source text which rnix-parser should parse back into exactly that tree.
+//! To keep the two in agreement, the strategies insert [`RNixExpr::Paren`] nodes wherever the surrounding syntax
+//! would otherwise bind differently, and [`to_source`] prints the tree verbatim without adding anything of its own.
+
+use std::iter;
+
+use proptest::{
+    collection::vec,
+    option,
+    prelude::*,
+    sample::{select, subsequence},
+};
+
+use crate::ast::{
+    Apply, Assert, Attr, AttrSet, Attrpath, AttrpathValue, BinOp, BinOpKind, Dynamic, Entry,
+    HasAttr, Ident, IdentParam, IfElse, Inherit, InheritFrom, InterpolPart, Lambda, LegacyLet,
+    LetIn, List, Literal, LiteralKind, Param, Paren, PatBind, PatEntry, Path, Pattern, RNixExpr,
+    Select, Span, Str, StrInterpol, UnaryOp, UnaryOpKind, With,
+};
+
+/// The only free variables that generated expressions refer to. See [`GeneratedExpr::closed_source`].
+pub const BOUND_IDENTS: &[&str] = &["a", "b", "f", "x", "foo-bar", "baz'", "_"];
+
+const URIS: &[&str] = &[
+    "http://example.org",
+    "https://nixos.org/manual/nix/stable/",
+    "mirror://gnu/hello",
+];
+
+const BIN_OPS: &[BinOpKind] = &[
+    BinOpKind::Concat,
+    BinOpKind::Update,
+    BinOpKind::Add,
+    BinOpKind::Sub,
+    BinOpKind::Mul,
+    BinOpKind::Div,
+    BinOpKind::And,
+    BinOpKind::Equal,
+    BinOpKind::Implication,
+    BinOpKind::Less,
+    BinOpKind::LessOrEq,
+    BinOpKind::More,
+    BinOpKind::MoreOrEq,
+    BinOpKind::NotEqual,
+    BinOpKind::Or,
+];
+
+const UNARY_OPS: &[UnaryOpKind] = &[UnaryOpKind::Invert, UnaryOpKind::Negate];
+
+/// A generated expression together with the source text that should parse to it.
+#[derive(Clone, Debug)]
+pub struct GeneratedExpr {
+    pub expr: RNixExpr,
+    pub source: String,
+}
+
+impl GeneratedExpr {
+    /// The source text nested in a lambda for each of [`BOUND_IDENTS`]. The reference impl rejects undeclared
+    /// identifiers while parsing, so this is the form that should be given to it.
+    pub fn closed_source(&self) -> String {
+        let mut source: String = BOUND_IDENTS
+            .iter()
+            .map(|ident| format!("{ident}: "))
+            .collect();
+        source.push_str(&self.source);
+        source
+    }
+}
+
+impl From<RNixExpr> for GeneratedExpr {
+    fn from(expr: RNixExpr) -> Self {
+        GeneratedExpr {
+            source: to_source(&expr),
+            expr,
+        }
+    }
+}
+
+impl Arbitrary for GeneratedExpr {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        arb_expr().prop_map(GeneratedExpr::from).boxed()
+    }
+}
+
+impl Arbitrary for RNixExpr {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        arb_expr().boxed()
+    }
+}
+
+/// Generate an arbitrary expression. Parentheses needed to print it unambiguously are already part of the tree.
+pub fn arb_expr() -> impl Strategy<Value = RNixExpr> {
+    arb_expr_with(|_| None)
+}
+
+/// Generate an arbitrary expression which uses `or` as a variable, in every position where Nix allows it to be one:
+/// right after an atom, which is then applied to it (e.g., `[ a or b ]` is a list of `a or` and `b`). Because `or`
+/// can't be a lambda's parameter, the expression is wrapped in `let or = a; in`, which keeps
+/// [`GeneratedExpr::closed_source`] accepted by the reference impl.
+pub fn arb_or_expr() -> impl Strategy<Value = RNixExpr> {
+    arb_expr_with(|inner| {
+        Some(
+            inner
+                .prop_map(|expr| {
+                    RNixExpr::Apply(Apply {
+                        lambda: paren_unless(expr, is_atom),
+                        argument: Box::new(RNixExpr::Ident(ident("or"))),
+                        span: Span::default(),
+                    })
+                })
+                .boxed(),
+        )
+    })
+    .prop_map(|body| {
+        RNixExpr::LetIn(LetIn {
+            entries: vec![Entry::AttrpathValue(AttrpathValue {
+                attrpath: Attrpath {
+                    attrs: vec![Attr::Ident(ident("or"))],
+                },
+                value: Box::new(RNixExpr::Ident(ident("a"))),
+            })],
+            body: Box::new(body),
+            span: Span::default(),
+        })
+    })
+}
+
+/// [`arb_expr`], with `extra` given the chance to add a strategy of its own to each level of recursion
+fn arb_expr_with(
+    extra: fn(BoxedStrategy<RNixExpr>) -> Option<BoxedStrategy<RNixExpr>>,
+) -> impl Strategy<Value = RNixExpr> {
+    let leaf = prop_oneof![
+        arb_ident().prop_map(RNixExpr::Ident),
+        arb_literal().prop_map(RNixExpr::Literal),
+        arb_str_literal().prop_map(|lit| RNixExpr::Str(Str {
+            parts: vec![InterpolPart::Literal(lit)],
+            span: Span::default(),
+        })),
+        Just(RNixExpr::Str(Str {
+            parts: vec![],
+            span: Span::default(),
+        })),
+        arb_path_literal().prop_map(|lit| RNixExpr::Path(Path {
+            parts: vec![InterpolPart::Literal(lit)],
+            span: Span::default(),
+        })),
+    ];
+
+    leaf.prop_recursive(4, 64, 4, move |inner| {
+        let expr = prop_oneof![
+            (inner.clone(), inner.clone()).prop_map(|(lambda, argument)| {
+                RNixExpr::Apply(Apply {
+                    lambda: paren_unless(lambda, |e| is_atom(e) || matches!(e, RNixExpr::Apply(_))),
+                    argument: paren_unless(argument, is_app_arg),
+                    span: Span::default(),
+                })
+            }),
+            (inner.clone(), inner.clone()).prop_map(|(condition, body)| {
+                RNixExpr::Assert(Assert {
+                    condition: Box::new(condition),
+                    body: Box::new(body),
+                    span: Span::default(),
+                })
+            }),
+            (inner.clone(), inner.clone(), inner.clone()).prop_map(
+                |(condition, body, else_body)| RNixExpr::IfElse(IfElse {
+                    condition: Box::new(condition),
+                    body: Box::new(body),
+                    else_body: Box::new(else_body),
+                    span: Span::default(),
+                })
+            ),
+            (
+                inner.clone(),
+                arb_attrpath(inner.clone()),
+                option::of(inner.clone())
+            )
+                .prop_map(|(expr, attrpath, default_expr)| {
+                    RNixExpr::Select(Select {
+                        expr: paren_unless(expr, is_select_atom),
+                        attrpath,
+                        default_expr: default_expr
+                            .map(|default| paren_unless(default, is_select_atom)),
+                        span: Span::default(),
+                    })
+                }),
+            arb_str(inner.clone()).prop_map(RNixExpr::Str),
+            (select(&["./", "./foo/", "/foo/"][..]), inner.clone()).prop_map(|(prefix, expr)| {
+                RNixExpr::Path(Path {
+                    parts: vec![
+                        InterpolPart::Literal(prefix.to_string()),
+                        InterpolPart::Interpolation(StrInterpol {
+                            expr: Box::new(expr),
+                        }),
+                    ],
+                    span: Span::default(),
+                })
+            }),
+            (arb_param(inner.clone()), inner.clone()).prop_map(|(param, body)| {
+                RNixExpr::Lambda(Lambda {
+                    param,
+                    body: Box::new(body),
+                    span: Span::default(),
+                })
+            }),
+            (arb_entries(inner.clone()), inner.clone()).prop_map(|(mut entries, body)| {
+                entries.push(Entry::AttrpathValue(AttrpathValue {
+                    attrpath: Attrpath {
+                        attrs: vec![Attr::Ident(ident("body"))],
+                    },
+                    value: Box::new(body),
+                }));
+                RNixExpr::LegacyLet(LegacyLet {
+                    entries,
+                    span: Span::default(),
+                })
+            }),
+            (arb_entries(inner.clone()), inner.clone()).prop_map(|(entries, body)| {
+                RNixExpr::LetIn(LetIn {
+                    entries,
+                    body: Box::new(body),
+                    span: Span::default(),
+                })
+            }),
+            vec(inner.clone(), 0..4).prop_map(|items| RNixExpr::List(List {
+                items: items
+                    .into_iter()
+                    .map(|item| *paren_unless(item, is_app_arg))
+                    .collect(),
+                span: Span::default(),
+            })),
+            (inner.clone(), select(BIN_OPS), inner.clone()).prop_map(|(lhs, operator, rhs)| {
+                RNixExpr::BinOp(BinOp {
+                    lhs: paren_unless(lhs, is_app_arg),
+                    operator,
+                    rhs: paren_unless(rhs, is_app_arg),
+                    span: Span::default(),
+                })
+            }),
+            inner.clone().prop_map(|expr| RNixExpr::Paren(Paren {
+                expr: Box::new(expr),
+                span: Span::default(),
+            })),
+            (arb_entries(inner.clone()), any::<bool>()).prop_map(|(entries, recursive)| {
+                RNixExpr::AttrSet(AttrSet {
+                    entries,
+                    recursive,
+                    span: Span::default(),
+                })
+            }),
+            (select(UNARY_OPS), inner.clone()).prop_map(|(operator, expr)| {
+                RNixExpr::UnaryOp(UnaryOp {
+                    operator,
+                    expr: paren_unless(expr, |e| {
+                        is_select_atom(e) || matches!(e, RNixExpr::Literal(_))
+                    }),
+                    span: Span::default(),
+                })
+            }),
+            (inner.clone(), inner.clone()).prop_map(|(namespace, body)| {
+                RNixExpr::With(With {
+                    namespace: Box::new(namespace),
+                    body: Box::new(body),
+                    span: Span::default(),
+                })
+            }),
+            (inner.clone(), arb_attrpath(inner.clone())).prop_map(|(expr, attrpath)| {
+                RNixExpr::HasAttr(HasAttr {
+                    expr: paren_unless(expr, is_atom),
+                    attrpath,
+                    span: Span::default(),
+                })
+            }),
+        ];
+        match extra(inner) {
+            Some(extra) => prop_oneof![4 => expr, 1 => extra].boxed(),
+            None => expr.boxed(),
+        }
+    })
+}
+
+/// Print an expression exactly as it is structured. See [`arb_expr`] for why no parentheses are added here.
+pub fn to_source(expr: &RNixExpr) -> String {
+    let mut out = String::new();
+    write_expr(&mut out, expr);
+    out
+}
+
+fn arb_ident() -> impl Strategy<Value = Ident> {
+    select(BOUND_IDENTS).prop_map(ident)
+}
+
+fn arb_literal() -> impl Strategy<Value = Literal> {
+    prop_oneof![
+        (0i64..=1_000_000).prop_map(LiteralKind::Integer),
+        // Built from digits, rather than an arbitrary f64, so that printing it gives something that lexes as a float
+        (0u32..1000, 0u32..1000).prop_map(|(whole, fraction)| LiteralKind::Float(
+            format!("{whole}.{fraction}").parse().unwrap()
+        )),
+        select(URIS).prop_map(|uri| LiteralKind::Uri(uri.to_string())),
+    ]
+    .prop_map(|kind| Literal {
+        kind,
+        span: Span::default(),
+    })
+}
+
+fn arb_str_literal() -> impl Strategy<Value = String> {
+    r#"[a-z é"\\${}\t\n]{1,6}"#
+}
+
+fn arb_path_literal() -> impl Strategy<Value = String> {
+    let segments = vec("[a-z][a-z0-9_-]{0,3}(\\.nix)?", 1..3).prop_map(|s| s.join("/"));
+    prop_oneof![
+        segments.clone().prop_map(|path| format!("./{path}")),
+        segments.clone().prop_map(|path| format!("foo/{path}")),
+        segments.clone().prop_map(|path| format!("/{path}")),
+        segments.clone().prop_map(|path| format!("~/{path}")),
+        segments.prop_map(|path| format!("<{path}>")),
+    ]
+}
+
+fn arb_str(inner: BoxedStrategy<RNixExpr>) -> impl Strategy<Value = Str> {
+    vec(
+        prop_oneof![
+            arb_str_literal().prop_map(InterpolPart::Literal),
+            inner.prop_map(|expr| InterpolPart::Interpolation(StrInterpol {
+                expr: Box::new(expr)
+            })),
+        ],
+        0..4,
+    )
+    .prop_map(|parts| {
+        // rnix-parser never produces two literal parts in a row, so neither do we
+        let mut merged: Vec<InterpolPart<String>> = vec![];
+        for part in parts {
+            match (merged.last_mut(), part) {
+                (Some(InterpolPart::Literal(prev)), InterpolPart::Literal(lit)) => {
+                    prev.push_str(&lit)
+                }
+                (_, part) => merged.push(part),
+            }
+        }
+        Str {
+            parts: merged,
+            span: Span::default(),
+        }
+    })
+}
+
+fn arb_attr(inner: BoxedStrategy<RNixExpr>) -> impl Strategy<Value = Attr> {
+    prop_oneof![
+        arb_ident().prop_map(Attr::Ident),
+        arb_str(inner.clone()).prop_map(Attr::Str),
+        inner.prop_map(|expr| Attr::Dynamic(Dynamic {
+            expr: Box::new(expr),
+            span: Span::default(),
+        })),
+    ]
+}
+
+fn arb_attrpath(inner: BoxedStrategy<RNixExpr>) -> impl Strategy<Value = Attrpath> {
+    vec(arb_attr(inner), 1..3).prop_map(|attrs| Attrpath { attrs })
+}
+
+/// Generate the entries of an attr set or let. The first attr of every path is a distinct `k<n>` and there is at most
+/// one inherit, so that no attribute is ever defined twice.
+fn arb_entries(inner: BoxedStrategy<RNixExpr>) -> impl Strategy<Value = Vec<Entry>> {
+    (
+        vec((vec(arb_attr(inner.clone()), 0..2), inner.clone()), 1..4),
+        option::of((option::of(inner), subsequence(BOUND_IDENTS, 1..3))),
+    )
+        .prop_map(|(values, inherit)| {
+            let mut entries: Vec<Entry> = values
+                .into_iter()
+                .enumerate()
+                .map(|(i, (tail, value))| {
+                    Entry::AttrpathValue(AttrpathValue {
+                        attrpath: Attrpath {
+                            attrs: iter::once(Attr::Ident(ident(&format!("k{i}"))))
+                                .chain(tail)
+                                .collect(),
+                        },
+                        value: Box::new(value),
+                    })
+                })
+                .collect();
+
+            if let Some((from, idents)) = inherit {
+                entries.push(Entry::Inherit(Inherit {
+                    from: from.map(|expr| InheritFrom {
+                        expr: Box::new(expr),
+                    }),
+                    attrs: idents
+                        .into_iter()
+                        .map(|name| Attr::Ident(ident(name)))
+                        .collect(),
+                }));
+            }
+
+            entries
+        })
+}
+
+fn arb_param(inner: BoxedStrategy<RNixExpr>) -> impl Strategy<Value = Param> {
+    prop_oneof![
+        arb_ident().prop_map(|ident| Param::IdentParam(IdentParam { ident })),
+        (
+            subsequence(BOUND_IDENTS, 0..3),
+            vec(option::of(inner), 3),
+            any::<bool>(),
+            any::<bool>()
+        )
+            .prop_map(|(names, defaults, ellipsis, bind)| {
+                Param::Pattern(Pattern {
+                    pat_entries: names
+                        .into_iter()
+                        .zip(defaults)
+                        .map(|(name, default)| PatEntry {
+                            ident: ident(name),
+                            default: default.map(Box::new),
+                        })
+                        .collect(),
+                    ellipsis,
+                    // Not one of BOUND_IDENTS, so it can never clash with a formal
+                    pat_bind: bind.then(|| PatBind {
+                        ident: ident("args"),
+                    }),
+                })
+            }),
+    ]
+}
+
+fn ident(name: &str) -> Ident {
+    Ident {
+        inner: name.to_string(),
+        span: Span::default(),
+    }
+}
+
+/// Expressions which are delimited on their own and can appear anywhere without parentheses.
+fn is_atom(expr: &RNixExpr) -> bool {
+    matches!(
+        expr,
+        RNixExpr::Ident(_)
+            | RNixExpr::Literal(_)
+            | RNixExpr::Str(_)
+            | RNixExpr::Path(_)
+            | RNixExpr::List(_)
+            | RNixExpr::AttrSet(_)
+            | RNixExpr::Paren(_)
+    )
+}
+
+/// Expressions which can be a list item or an argument without parentheses: atoms, and atoms followed by `or`.
+fn is_app_arg(expr: &RNixExpr) -> bool {
+    match expr {
+        RNixExpr::Apply(apply) => {
+            is_atom(&apply.lambda)
+                && matches!(&*apply.argument, RNixExpr::Ident(ident) if ident.inner == "or")
+        }
+        expr => is_atom(expr),
+    }
+}
+
+/// Atoms which can be immediately followed by a `.`. Paths, floats and URIs would swallow it.
+fn is_select_atom(expr: &RNixExpr) -> bool {
+    matches!(
+        expr,
+        RNixExpr::Ident(_)
+            | RNixExpr::Str(_)
+            | RNixExpr::List(_)
+            | RNixExpr::AttrSet(_)
+            | RNixExpr::Paren(_)
+    )
+}
+
+fn paren_unless(expr: RNixExpr, keep: impl Fn(&RNixExpr) -> bool) -> Box<RNixExpr> {
+    if keep(&expr) {
+        Box::new(expr)
+    } else {
+        Box::new(RNixExpr::Paren(Paren {
+            expr: Box::new(expr),
+            span: Span::default(),
+        }))
+    }
+}
+
+fn write_expr(out: &mut String, expr: &RNixExpr) {
+    match expr {
+        RNixExpr::Apply(apply) => {
+            write_expr(out, &apply.lambda);
+            out.push(' ');
+            write_expr(out, &apply.argument);
+        }
+        RNixExpr::Assert(assert) => {
+            out.push_str("assert ");
+            write_expr(out, &assert.condition);
+            out.push_str("; ");
+            write_expr(out, &assert.body);
+        }
+        RNixExpr::IfElse(if_else) => {
+            out.push_str("if ");
+            write_expr(out, &if_else.condition);
+            out.push_str(" then ");
+            write_expr(out, &if_else.body);
+            out.push_str(" else ");
+            write_expr(out, &if_else.else_body);
+        }
+        RNixExpr::Select(select) => {
+            write_expr(out, &select.expr);
+            out.push('.');
+            write_attrpath(out, &select.attrpath);
+            if let Some(default) = &select.default_expr {
+                out.push_str(" or ");
+                write_expr(out, default);
+            }
+        }
+        RNixExpr::Str(str) => write_str(out, str),
+        RNixExpr::Path(path) => {
+            for part in &path.parts {
+                match part {
+                    InterpolPart::Literal(lit) => out.push_str(lit),
+                    InterpolPart::Interpolation(interpol) => write_interpol(out, &interpol.expr),
+                }
+            }
+        }
+        RNixExpr::Literal(literal) => match &literal.kind {
+            // Debug formatting always keeps the decimal point, so the result can't be mistaken for an integer
+            LiteralKind::Float(float) => out.push_str(&format!("{float:?}")),
+            LiteralKind::Integer(integer) => out.push_str(&integer.to_string()),
+            LiteralKind::IntegerOutOfRange(integer) => out.push_str(integer),
+            LiteralKind::FloatOutOfRange(float) => out.push_str(float),
+            LiteralKind::Uri(uri) => out.push_str(uri),
+        },
+        RNixExpr::Lambda(lambda) => {
+            write_param(out, &lambda.param);
+            out.push_str(": ");
+            write_expr(out, &lambda.body);
+        }
+        RNixExpr::LegacyLet(legacy_let) => {
+            out.push_str("let { ");
+            write_entries(out, &legacy_let.entries);
+            out.push('}');
+        }
+        RNixExpr::LetIn(let_in) => {
+            out.push_str("let ");
+            write_entries(out, &let_in.entries);
+            out.push_str("in ");
+            write_expr(out, &let_in.body);
+        }
+        RNixExpr::List(list) => {
+            out.push('[');
+            for item in &list.items {
+                out.push(' ');
+                write_expr(out, item);
+            }
+            out.push_str(" ]");
+        }
+        RNixExpr::BinOp(bin_op) => {
+            write_expr(out, &bin_op.lhs);
+            out.push(' ');
+            out.push_str(bin_op_token(bin_op.operator));
+            out.push(' ');
+            write_expr(out, &bin_op.rhs);
+        }
+        RNixExpr::Paren(paren) => {
+            out.push('(');
+            write_expr(out, &paren.expr);
+            out.push(')');
+        }
+        RNixExpr::Root(root) => write_expr(out, &root.expr),
+        RNixExpr::AttrSet(attr_set) => {
+            if attr_set.recursive {
+                out.push_str("rec ");
+            }
+            out.push_str("{ ");
+            write_entries(out, &attr_set.entries);
+            out.push('}');
+        }
+        RNixExpr::UnaryOp(unary_op) => {
+            out.push(match unary_op.operator {
+                UnaryOpKind::Invert => '!',
+                UnaryOpKind::Negate => '-',
+            });
+            write_expr(out, &unary_op.expr);
+        }
+        RNixExpr::Ident(ident) => out.push_str(&ident.inner),
+        RNixExpr::With(with) => {
+            out.push_str("with ");
+            write_expr(out, &with.namespace);
+            out.push_str("; ");
+            write_expr(out, &with.body);
+        }
+        RNixExpr::HasAttr(has_attr) => {
+            write_expr(out, &has_attr.expr);
+            out.push_str(" ? ");
+            write_attrpath(out, &has_attr.attrpath);
+        }
+    }
+}
+
+fn write_str(out: &mut String, str: &Str) {
+    out.push('"');
+    for part in &str.parts {
+        match part {
+            InterpolPart::Literal(lit) => {
+                for c in lit.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        '$' => out.push_str("\\$"),
+                        '\n' => out.push_str("\\n"),
+                        '\t' => out.push_str("\\t"),
+                        '\r' => out.push_str("\\r"),
+                        c => out.push(c),
+                    }
+                }
+            }
+            InterpolPart::Interpolation(interpol) => write_interpol(out, &interpol.expr),
+        }
+    }
+    out.push('"');
+}
+
+fn write_interpol(out: &mut String, expr: &RNixExpr) {
+    out.push_str("${");
+    write_expr(out, expr);
+    out.push('}');
+}
+
+fn write_attrpath(out: &mut String, attrpath: &Attrpath) {
+    for (i, attr) in attrpath.attrs.iter().enumerate() {
+        if i > 0 {
+            out.push('.');
+        }
+        write_attr(out, attr);
+    }
+}
+
+fn write_attr(out: &mut String, attr: &Attr) {
+    match attr {
+        Attr::Ident(ident) => out.push_str(&ident.inner),
+        Attr::Dynamic(dynamic) => write_interpol(out, &dynamic.expr),
+        Attr::Str(str) => write_str(out, str),
+    }
+}
+
+fn write_entries(out: &mut String, entries: &[Entry]) {
+    for entry in entries {
+        match entry {
+            Entry::AttrpathValue(attrpath_value) => {
+                write_attrpath(out, &attrpath_value.attrpath);
+                out.push_str(" = ");
+                write_expr(out, &attrpath_value.value);
+            }
+            Entry::Inherit(inherit) => {
+                out.push_str("inherit");
+                if let Some(from) = &inherit.from {
+                    out.push_str(" (");
+                    write_expr(out, &from.expr);
+                    out.push(')');
+                }
+                for attr in &inherit.attrs {
+                    out.push(' ');
+                    write_attr(out, attr);
+                }
+            }
+        }
+        out.push_str("; ");
+    }
+}
+
+fn write_param(out: &mut String, param: &Param) {
+    match param {
+        Param::IdentParam(ident_param) => out.push_str(&ident_param.ident.inner),
+        Param::Pattern(pattern) => {
+            if let Some(pat_bind) = &pattern.pat_bind {
+                out.push_str(&pat_bind.ident.inner);
+                out.push('@');
+            }
+            out.push('{');
+            let mut formals: Vec<String> = pattern
+                .pat_entries
+                .iter()
+                .map(|entry| match &entry.default {
+                    Some(default) => format!("{} ? {}", entry.ident.inner, to_source(default)),
+                    None => entry.ident.inner.clone(),
+                })
+                .collect();
+            if pattern.ellipsis {
+                formals.push("...".to_string());
+            }
+            out.push(' ');
+            out.push_str(&formals.join(", "));
+            out.push_str(" }");
+        }
+    }
+}
+
+fn bin_op_token(operator: BinOpKind) -> &'static str {
+    match operator {
+        BinOpKind::Concat => "++",
+        BinOpKind::Update => "//",
+        BinOpKind::Add => "+",
+        BinOpKind::Sub => "-",
+        BinOpKind::Mul => "*",
+        BinOpKind::Div => "/",
+        BinOpKind::And => "&&",
+        BinOpKind::Equal => "==",
+        BinOpKind::Implication => "->",
+        BinOpKind::Less => "<",
+        BinOpKind::LessOrEq => "<=",
+        BinOpKind::More => ">",
+        BinOpKind::MoreOrEq => ">=",
+        BinOpKind::NotEqual => "!=",
+        BinOpKind::Or => "||",
+    }
+}
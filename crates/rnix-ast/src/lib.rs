@@ -1,5 +1,7 @@
 pub mod ast;
 pub mod convert;
+#[cfg(feature = "proptest")]
+pub mod generator;
 
 pub fn parse(nix_expr: &str) -> Result<ast::RNixExpr, convert::ToAstError> {
     ast::RNixExpr::try_from(rnix::Root::parse(nix_expr))
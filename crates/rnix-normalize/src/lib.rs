@@ -1,29 +1,250 @@
+mod line_index;
+mod passes;
+mod path_context;
+mod profile;
+mod scope;
+mod trace;
 mod value;
 
-use itertools::{chain, Either, Itertools};
-use normal_ast::{AttrDef, AttrName, DynamicAttrDef, Formal, Formals, NormalNixExpr};
+use std::{collections::HashSet, fmt};
+
+use itertools::Either;
+use normal_ast::{AttrName, AttrPathDef, Formal, Formals, NormalNixExpr, Var};
 use rnix_ast::ast::{
     Apply, Assert, Attr, AttrSet, Attrpath, AttrpathValue, BinOp, BinOpKind, Dynamic, Entry,
     HasAttr, Ident, IfElse, Inherit, InterpolPart, Lambda, LegacyLet, LetIn, List, Literal,
-    LiteralKind, Param, Paren, Path, RNixExpr, Root, Select, Str, UnaryOp, UnaryOpKind, With,
+    LiteralKind, Param, Paren, Path, RNixExpr, Root, Select, Span, Str, UnaryOp, UnaryOpKind, With,
 };
-use value::{parse_path, Anchor};
+use trace::Tracer;
+
+pub use line_index::LineIndex;
+pub use passes::{merge_string_fragments, Pass, Passes, UnknownPass};
+pub use path_context::{PathContext, PathContextError, SearchPathEntry, SymlinkPolicy};
+pub use profile::{NixVersionProfile, UnknownNixVersion};
+pub use trace::Trace;
+
+/// The version of the lowering and normalization rules. Bump it with any change which can change what the same source
+/// normalizes to, so that results cached by `compare --incremental` before the change aren't used after it.
+pub const NORMALIZATION_VERSION: u32 = 2;
+
+/// An expression which rnix-parser accepts, but which the reference impl rejects while parsing.
+#[derive(Debug)]
+pub enum NormalizeError {
+    IntegerOutOfRange {
+        literal: String,
+        span: Span,
+    },
+    /// A float literal which overflows to infinity or underflows to zero
+    FloatOutOfRange {
+        literal: String,
+        span: Span,
+    },
+    /// The same attr is defined twice in a way that can't be merged, e.g. `{ x = 1; x = 2; }`, `{ x = 1; x.y = 2; }`,
+    /// or `{ inherit x; x.y = 1; }`.
+    DuplicateAttr {
+        path: String,
+        span: Span,
+        previous: Span,
+    },
+    /// The same formal appears twice in a lambda's pattern, or is also the name of its `@` binding.
+    DuplicateFormal {
+        name: String,
+        span: Span,
+    },
+    DynamicAttrInLet {
+        span: Span,
+    },
+    /// An inherited attr which isn't constant, e.g. `inherit ${x};`
+    DynamicAttrInInherit {
+        span: Span,
+    },
+    PathTrailingSlash {
+        path: String,
+        span: Span,
+    },
+    /// A variable which isn't bound by anything, and isn't inside of a `with` which could bind it.
+    UndefinedVariable {
+        name: String,
+        span: Span,
+    },
+}
+
+impl NormalizeError {
+    /// Where in the source the reference impl would report the error.
+    pub fn span(&self) -> Span {
+        match self {
+            NormalizeError::IntegerOutOfRange { span, .. }
+            | NormalizeError::FloatOutOfRange { span, .. }
+            | NormalizeError::DuplicateAttr { span, .. }
+            | NormalizeError::DuplicateFormal { span, .. }
+            | NormalizeError::DynamicAttrInLet { span }
+            | NormalizeError::DynamicAttrInInherit { span }
+            | NormalizeError::PathTrailingSlash { span, .. }
+            | NormalizeError::UndefinedVariable { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for NormalizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NormalizeError::IntegerOutOfRange { literal, span } => {
+                write!(f, "invalid integer '{literal}' at {span}")
+            }
+            NormalizeError::FloatOutOfRange { literal, span } => {
+                write!(f, "invalid float '{literal}' at {span}")
+            }
+            NormalizeError::DuplicateAttr {
+                path,
+                span,
+                previous,
+            } => {
+                write!(
+                    f,
+                    "attribute '{path}' already defined at {previous}, redefined at {span}"
+                )
+            }
+            NormalizeError::DuplicateFormal { name, span } => {
+                write!(f, "duplicate formal function argument '{name}' at {span}")
+            }
+            NormalizeError::DynamicAttrInLet { span } => {
+                write!(f, "dynamic attributes not allowed in let at {span}")
+            }
+            NormalizeError::DynamicAttrInInherit { span } => {
+                write!(f, "dynamic attributes not allowed in inherit at {span}")
+            }
+            NormalizeError::PathTrailingSlash { path, span } => {
+                write!(f, "path '{path}' has a trailing slash at {span}")
+            }
+            NormalizeError::UndefinedVariable { name, span } => {
+                write!(f, "undefined variable '{name}' at {span}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for NormalizeError {}
+
+type Result<T> = std::result::Result<T, NormalizeError>;
+
+/// Normalize `expr`, which was parsed from `source`, into what `profile`'s version of Nix would produce. `file` is
+/// where `source` came from, if it came from a file, as given by [`PathContext::resolve_file`]. Only the given
+/// `passes` are run.
+pub fn normalize_nix_expr(
+    expr: RNixExpr,
+    source: &str,
+    file: Option<&str>,
+    paths: &PathContext,
+    passes: &Passes,
+    profile: NixVersionProfile,
+) -> Result<NormalNixExpr> {
+    Ok(normalize(
+        expr,
+        source,
+        file,
+        paths,
+        passes,
+        profile,
+        None,
+        &mut |_, _| {},
+    )?
+    .0)
+}
+
+/// Like [`normalize_nix_expr`], but also trace which rule produced each node when lowering rnix-parser's AST.
+pub fn normalize_nix_expr_traced(
+    expr: RNixExpr,
+    source: &str,
+    file: Option<&str>,
+    paths: &PathContext,
+    passes: &Passes,
+    profile: NixVersionProfile,
+) -> Result<(NormalNixExpr, Trace)> {
+    let (expr, trace) = normalize(
+        expr,
+        source,
+        file,
+        paths,
+        passes,
+        profile,
+        Some(Tracer::new()),
+        &mut |_, _| {},
+    )?;
+    Ok((expr, trace.expect("Normalizing always produces a node")))
+}
 
-pub fn normalize_nix_expr(expr: RNixExpr, base_path: String, home_path: String) -> NormalNixExpr {
-    Normalizer {
-        base_path,
-        home_path,
+/// Like [`normalize_nix_expr`], but also hand each intermediate stage to `on_stage` as soon as it's done, along with
+/// its name. The first stage is `lower`, i.e., rnix-parser's AST with all of the sugar still in it, and the rest are
+/// named after the pass which produced them. Variables are only resolved once every pass has run.
+pub fn normalize_nix_expr_staged(
+    expr: RNixExpr,
+    source: &str,
+    file: Option<&str>,
+    paths: &PathContext,
+    passes: &Passes,
+    profile: NixVersionProfile,
+    on_stage: &mut dyn FnMut(&str, &NormalNixExpr),
+) -> Result<NormalNixExpr> {
+    Ok(normalize(expr, source, file, paths, passes, profile, None, on_stage)?.0)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn normalize(
+    expr: RNixExpr,
+    source: &str,
+    file: Option<&str>,
+    paths: &PathContext,
+    passes: &Passes,
+    profile: NixVersionProfile,
+    tracer: Option<Tracer>,
+    on_stage: &mut dyn FnMut(&str, &NormalNixExpr),
+) -> Result<(NormalNixExpr, Option<Trace>)> {
+    let normalizer = Normalizer {
+        file: file.map(str::to_string),
+        line_index: LineIndex::new(source),
+        tracer,
+    };
+    let mut expr = normalizer.normalize(expr)?;
+    on_stage("lower", &expr);
+
+    let base_path = paths.base_path(file);
+    for pass in Pass::ALL {
+        if passes.is_enabled(pass) {
+            expr = passes::run(pass, expr, &base_path, &paths.home, profile)?;
+            on_stage(pass.name(), &expr);
+        }
     }
-    .normalize(expr)
+
+    // The reference impl only resolves variables once the whole expression has been parsed, so any other error comes
+    // first
+    scope::bind_vars(&mut expr, profile)?;
+
+    Ok((expr, normalizer.tracer.and_then(Tracer::finish)))
 }
 
+/// Lowers rnix-parser's AST into a [`NormalNixExpr`] which still has some sugar in it, like parens. The passes in
+/// [`passes`] take care of that.
 struct Normalizer {
-    base_path: String,
-    home_path: String,
+    file: Option<String>,
+    line_index: LineIndex,
+    tracer: Option<Tracer>,
 }
 
 impl Normalizer {
-    fn normalize(&self, expr: RNixExpr) -> NormalNixExpr {
+    fn normalize(&self, expr: RNixExpr) -> Result<NormalNixExpr> {
+        match &self.tracer {
+            None => self.dispatch(expr),
+            Some(tracer) => {
+                let (rule, span) = (trace::rule(&expr), expr.span());
+                tracer.enter();
+                let expr = self.dispatch(expr)?;
+                tracer.exit(rule, span, expr.kind());
+                Ok(expr)
+            }
+        }
+    }
+
+    fn dispatch(&self, expr: RNixExpr) -> Result<NormalNixExpr> {
         match expr {
             RNixExpr::Apply(apply) => self.normalize_apply(apply),
             RNixExpr::Assert(assert) => self.normalize_assert(assert),
@@ -47,74 +268,91 @@ impl Normalizer {
         }
     }
 
-    fn boxed_normalize(&self, expr: RNixExpr) -> Box<NormalNixExpr> {
-        Box::new(self.normalize(expr))
+    fn boxed_normalize(&self, expr: RNixExpr) -> Result<Box<NormalNixExpr>> {
+        self.normalize(expr).map(Box::new)
     }
 
-    /// Normalize by squashing nested Apply nodes to a single [`NormalNixExpr::Call`] node,
-    /// collecting function arguments into a list.
-    fn normalize_apply(&self, apply: Apply) -> NormalNixExpr {
-        let mut fun: NormalNixExpr = self.normalize(*apply.lambda);
-        let last_arg = self.normalize(*apply.argument);
-
-        let mut args: Vec<NormalNixExpr> = vec![];
-
-        while let NormalNixExpr::Call {
-            fun: inner_fun,
-            args: inner_args,
-        } = fun
-        {
-            args.extend(inner_args);
-            fun = *inner_fun;
-        }
-
-        args.push(last_arg);
-
-        NormalNixExpr::Call {
-            fun: Box::new(fun),
-            args,
-        }
+    /// Normalize into a [`NormalNixExpr::Call`] with a single argument. The flatten-calls pass squashes nested calls.
+    fn normalize_apply(&self, apply: Apply) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::Call {
+            fun: self.boxed_normalize(*apply.lambda)?,
+            args: vec![self.normalize(*apply.argument)?],
+        })
     }
 
     /// Normalize trivially by normalizing child expressions and repacking into [`NormalNixExpr::Assert`].
-    fn normalize_assert(&self, assert: Assert) -> NormalNixExpr {
-        NormalNixExpr::Assert {
-            cond: self.boxed_normalize(*assert.condition),
-            body: self.boxed_normalize(*assert.body),
-        }
+    fn normalize_assert(&self, assert: Assert) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::Assert {
+            cond: self.boxed_normalize(*assert.condition)?,
+            body: self.boxed_normalize(*assert.body)?,
+        })
     }
 
-    /// Normalize trivially by repacking the inner string into [`NormalNixExpr::Var`].
-    fn normalize_ident(&self, ident: Ident) -> NormalNixExpr {
-        NormalNixExpr::Var(ident.inner)
+    /// Normalize trivially by repacking the inner string into [`NormalNixExpr::Var`], unless it's `__curPos`, which the
+    /// reference impl turns into the position it appears at, no matter what's in scope.
+    fn normalize_ident(&self, ident: Ident) -> Result<NormalNixExpr> {
+        if ident.inner == "__curPos" {
+            let (line, column) = self.line_index.line_col(ident.span.start);
+            return Ok(NormalNixExpr::Pos {
+                file: self.file.clone(),
+                line,
+                column,
+            });
+        }
+
+        Ok(NormalNixExpr::Var(Var::at(
+            ident.inner,
+            ident.span.start..ident.span.end,
+        )))
     }
 
     /// Normalize trivially by normalizing child expressions and repacking into [`NormalNixExpr::If`].
-    fn normalize_if_else(&self, if_else: IfElse) -> NormalNixExpr {
-        NormalNixExpr::If {
-            cond: self.boxed_normalize(*if_else.condition),
-            then: self.boxed_normalize(*if_else.body),
-            else_: self.boxed_normalize(*if_else.else_body),
-        }
+    fn normalize_if_else(&self, if_else: IfElse) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::If {
+            cond: self.boxed_normalize(*if_else.condition)?,
+            then: self.boxed_normalize(*if_else.body)?,
+            else_: self.boxed_normalize(*if_else.else_body)?,
+        })
     }
 
     /// Normalize most of it trivially by normalizing child expressions and repacking into [`NormalNixExpr::Select`].
     /// The interesting part here is normalizing the key path which is described in `normalize_as_attr_path`.
-    fn normalize_select(&self, select: Select) -> NormalNixExpr {
-        NormalNixExpr::Select {
-            subject: self.boxed_normalize(*select.expr),
+    fn normalize_select(&self, select: Select) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::Select {
+            subject: self.boxed_normalize(*select.expr)?,
             or_default: select
                 .default_expr
-                .map(|default| self.boxed_normalize(*default)),
-            path: self.normalize_attr_path(select.attrpath),
-        }
+                .map(|default| self.boxed_normalize(*default))
+                .transpose()?,
+            path: self.normalize_attr_path(select.attrpath)?,
+        })
     }
 
     /// TODO
-    fn normalize_lambda(&self, lambda: Lambda) -> NormalNixExpr {
+    fn normalize_lambda(&self, lambda: Lambda) -> Result<NormalNixExpr> {
         let (arg, formals) = match lambda.param {
             Param::IdentParam(ident_param) => (Some(ident_param.ident.inner), None),
             Param::Pattern(pattern) => {
+                // The reference impl rejects a formal appearing twice, which would otherwise silently collapse here
+                let mut seen = HashSet::new();
+                for entry in &pattern.pat_entries {
+                    if !seen.insert(&entry.ident.inner) {
+                        return Err(NormalizeError::DuplicateFormal {
+                            name: entry.ident.inner.clone(),
+                            span: entry.ident.span,
+                        });
+                    }
+                }
+                if let Some(pat_bind) = &pattern.pat_bind {
+                    if seen.contains(&pat_bind.ident.inner) {
+                        // The reference impl reports this one at the whole lambda
+                        return Err(NormalizeError::DuplicateFormal {
+                            name: pat_bind.ident.inner.clone(),
+                            span: lambda.span,
+                        });
+                    }
+                }
+
                 let at = pattern.pat_bind.map(|pat_bind| pat_bind.ident.inner);
                 let formals = Formals {
                     ellipsis: pattern.ellipsis,
@@ -122,225 +360,207 @@ impl Normalizer {
                         .pat_entries
                         .into_iter()
                         .map(|entry| {
-                            (
+                            Ok((
                                 entry.ident.inner,
                                 Formal {
-                                    default: entry.default.map(|default| self.normalize(*default)),
+                                    default: entry
+                                        .default
+                                        .map(|default| self.normalize(*default))
+                                        .transpose()?,
                                 },
-                            )
+                            ))
                         })
-                        .collect(),
+                        .collect::<Result<_>>()?,
                 };
 
                 (at, Some(formals))
             }
         };
 
-        NormalNixExpr::Lambda {
+        Ok(NormalNixExpr::Lambda {
             arg,
             formals,
-            body: self.boxed_normalize(*lambda.body),
-        }
+            body: self.boxed_normalize(*lambda.body)?,
+        })
     }
 
     /// TODO
-    fn normalize_legacy_let(&self, legacy_let: LegacyLet) -> NormalNixExpr {
-        NormalNixExpr::Select {
+    fn normalize_legacy_let(&self, legacy_let: LegacyLet) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::Select {
             subject: Box::new(self.normalize_attr_set(AttrSet {
                 entries: legacy_let.entries,
                 recursive: true, // The attr set of a legacy let is implicitly recursive
-            })),
+                span: legacy_let.span,
+            })?),
             or_default: None,
             path: vec![AttrName::Symbol("body".to_string())],
-        }
+        })
     }
 
-    /// TODO
-    fn normalize_let_in(&self, let_in: LetIn) -> NormalNixExpr {
-        NormalNixExpr::Let {
+    /// Normalize the bindings like an attr set. The merge-attrs pass rejects dynamic attrs in them.
+    fn normalize_let_in(&self, let_in: LetIn) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::Let {
             attrs: Box::new(self.normalize_attr_set(AttrSet {
                 entries: let_in.entries,
                 recursive: false,
-            })),
-            body: self.boxed_normalize(*let_in.body),
-        }
+                span: let_in.span,
+            })?),
+            body: self.boxed_normalize(*let_in.body)?,
+        })
     }
 
     /// TODO
-    fn normalize_list(&self, list: List) -> NormalNixExpr {
-        NormalNixExpr::List(list.items.into_iter().map(|e| self.normalize(e)).collect())
+    fn normalize_list(&self, list: List) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::List(
+            list.items
+                .into_iter()
+                .map(|e| self.normalize(e))
+                .collect::<Result<_>>()?,
+        ))
     }
 
     /// TODO
-    fn normalize_bin_op(&self, bin_op: BinOp) -> NormalNixExpr {
+    fn normalize_bin_op(&self, bin_op: BinOp) -> Result<NormalNixExpr> {
         let lhs = *bin_op.lhs;
         let rhs = *bin_op.rhs;
-        match bin_op.operator {
+        Ok(match bin_op.operator {
             BinOpKind::Concat => {
-                NormalNixExpr::OpConcatLists(self.boxed_normalize(lhs), self.boxed_normalize(rhs))
+                NormalNixExpr::OpConcatLists(self.boxed_normalize(lhs)?, self.boxed_normalize(rhs)?)
             }
             BinOpKind::Update => {
-                NormalNixExpr::OpUpdate(self.boxed_normalize(lhs), self.boxed_normalize(rhs))
+                NormalNixExpr::OpUpdate(self.boxed_normalize(lhs)?, self.boxed_normalize(rhs)?)
             }
             // The reference parser calls all addition "concat strings"
             BinOpKind::Add => NormalNixExpr::OpConcatStrings {
                 force_string: false, // FIXME: I don't know what this is
-                es: vec![self.normalize(lhs), self.normalize(rhs)],
-            },
-            // The reference parser treats subtraction as a call to __sub
-            BinOpKind::Sub => NormalNixExpr::Call {
-                fun: Box::new(NormalNixExpr::Var("__sub".to_string())),
-                args: vec![self.normalize(lhs), self.normalize(rhs)],
-            },
-            // The reference parser treats multiplication as a call to __mul
-            BinOpKind::Mul => NormalNixExpr::Call {
-                fun: Box::new(NormalNixExpr::Var("__mul".to_string())),
-                args: vec![self.normalize(lhs), self.normalize(rhs)],
-            },
-            // The reference parser treats division as a call to __div
-            BinOpKind::Div => NormalNixExpr::Call {
-                fun: Box::new(NormalNixExpr::Var("__div".to_string())),
-                args: vec![self.normalize(lhs), self.normalize(rhs)],
+                es: vec![self.normalize(lhs)?, self.normalize(rhs)?],
             },
+            // The reference parser treats these as calls to builtins. The desugar-operators pass takes care of that.
+            BinOpKind::Sub => self.normalize_operator("-", vec![lhs, rhs])?,
+            BinOpKind::Mul => self.normalize_operator("*", vec![lhs, rhs])?,
+            BinOpKind::Div => self.normalize_operator("/", vec![lhs, rhs])?,
+            BinOpKind::Less => self.normalize_operator("<", vec![lhs, rhs])?,
+            BinOpKind::LessOrEq => self.normalize_operator("<=", vec![lhs, rhs])?,
+            BinOpKind::More => self.normalize_operator(">", vec![lhs, rhs])?,
+            BinOpKind::MoreOrEq => self.normalize_operator(">=", vec![lhs, rhs])?,
             BinOpKind::And => {
-                NormalNixExpr::OpAnd(self.boxed_normalize(lhs), self.boxed_normalize(rhs))
+                NormalNixExpr::OpAnd(self.boxed_normalize(lhs)?, self.boxed_normalize(rhs)?)
             }
             BinOpKind::Equal => {
-                NormalNixExpr::OpEq(self.boxed_normalize(lhs), self.boxed_normalize(rhs))
+                NormalNixExpr::OpEq(self.boxed_normalize(lhs)?, self.boxed_normalize(rhs)?)
             }
             BinOpKind::Implication => {
-                NormalNixExpr::OpImpl(self.boxed_normalize(lhs), self.boxed_normalize(rhs))
+                NormalNixExpr::OpImpl(self.boxed_normalize(lhs)?, self.boxed_normalize(rhs)?)
             }
-            // The reference parser treats less than as a call to __lessThan
-            BinOpKind::Less => NormalNixExpr::Call {
-                fun: Box::new(NormalNixExpr::Var("__lessThan".to_string())),
-                args: vec![self.normalize(lhs), self.normalize(rhs)],
-            },
-            // The reference parser treats leq as negating a call to __lessThan with the args flipped
-            BinOpKind::LessOrEq => NormalNixExpr::OpNot(Box::new(NormalNixExpr::Call {
-                fun: Box::new(NormalNixExpr::Var("__lessThan".to_string())),
-                // Note the argument order!
-                args: vec![self.normalize(rhs), self.normalize(lhs)],
-            })),
-            // The reference parser treats greater than as a call to __lessThan with the args flipped
-            BinOpKind::More => NormalNixExpr::Call {
-                fun: Box::new(NormalNixExpr::Var("__lessThan".to_string())),
-                // Note the argument order!
-                args: vec![self.normalize(rhs), self.normalize(lhs)],
-            },
-            // The reference parser treats gte as negating a call to __lessThan
-            BinOpKind::MoreOrEq => NormalNixExpr::OpNot(Box::new(NormalNixExpr::Call {
-                fun: Box::new(NormalNixExpr::Var("__lessThan".to_string())),
-                args: vec![self.normalize(lhs), self.normalize(rhs)],
-            })),
             BinOpKind::NotEqual => {
-                NormalNixExpr::OpNEq(self.boxed_normalize(lhs), self.boxed_normalize(rhs))
+                NormalNixExpr::OpNEq(self.boxed_normalize(lhs)?, self.boxed_normalize(rhs)?)
             }
             BinOpKind::Or => {
-                NormalNixExpr::OpOr(self.boxed_normalize(lhs), self.boxed_normalize(rhs))
+                NormalNixExpr::OpOr(self.boxed_normalize(lhs)?, self.boxed_normalize(rhs)?)
             }
-        }
+        })
     }
 
-    /// TODO
-    fn normalize_paren(&self, paren: Paren) -> NormalNixExpr {
-        // The ref impl has no concept of parens, so simply discard it
-        self.normalize(*paren.expr)
+    fn normalize_operator(&self, op: &str, operands: Vec<RNixExpr>) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::Operator {
+            op: op.to_string(),
+            operands: operands
+                .into_iter()
+                .map(|operand| self.normalize(operand))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Keep the parens, for the strip-parens pass to discard
+    fn normalize_paren(&self, paren: Paren) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::Paren(self.boxed_normalize(*paren.expr)?))
     }
 
     /// TODO
-    fn normalize_root(&self, root: Root) -> NormalNixExpr {
+    fn normalize_root(&self, root: Root) -> Result<NormalNixExpr> {
         // The ref impl has no concept of a root, so simply discard it
         self.normalize(*root.expr)
     }
 
-    /// TODO
-    fn normalize_attr_set(&self, attr_set: AttrSet) -> NormalNixExpr {
-        // For each entry, we generate some number of either dynamic or non-dynamic attrs
-        let (attrs, dynamic_attrs): (Vec<Vec<AttrDef>>, Vec<DynamicAttrDef>) =
-            attr_set.entries.into_iter().partition_map(|entry| {
-                match entry {
-                    // If the entry is of the form `foo = bar`
-                    Entry::AttrpathValue(AttrpathValue {
-                        mut attrpath,
-                        value,
-                    }) => {
-                        let key_head = attrpath.attrs.remove(0);
-                        let key_tail = attrpath.attrs;
-
-                        let value = if !key_tail.is_empty() {
-                            // If the entry is of the form `x.y.z = bar`, then we expand into `x = { y.z = bar }` and recurse
-                            self.normalize_attr_set(AttrSet {
-                                entries: vec![Entry::AttrpathValue(AttrpathValue {
-                                    attrpath: Attrpath { attrs: key_tail },
-                                    value,
-                                })],
-                                recursive: false,
-                            })
-                        } else {
-                            // Otherwise, the value of the attr is simply the rhs of the equals as-is
-                            self.normalize(*value)
+    /// Normalize each entry into an [`AttrPathDef`], as written. The expand-attrpaths and merge-attrs passes take care
+    /// of the rest.
+    fn normalize_attr_set(&self, attr_set: AttrSet) -> Result<NormalNixExpr> {
+        let mut defs = vec![];
+
+        for entry in attr_set.entries {
+            match entry {
+                // If the entry is of the form `x.y.z = bar`
+                Entry::AttrpathValue(AttrpathValue { attrpath, value }) => {
+                    // The reference impl locates every def made by the entry at the whole attr path
+                    let span = match (attrpath.attrs.first(), attrpath.attrs.last()) {
+                        (Some(first), Some(last)) => attr_span(first).start..attr_span(last).end,
+                        _ => 0..0,
+                    };
+                    defs.push(AttrPathDef {
+                        path: self.normalize_attr_path(attrpath)?,
+                        inherited: false,
+                        expr: self.normalize(*value)?,
+                        span,
+                    });
+                }
+                // If the entry is of the form `inherit foo` or `inherit (x) foo`
+                Entry::Inherit(Inherit { from, attrs }) => {
+                    let subject = from
+                        .map(|from| self.boxed_normalize(*from.expr))
+                        .transpose()?;
+                    // The reference impl locates every inherited attr at the whole list of names
+                    let span = match (attrs.first(), attrs.last()) {
+                        (Some(first), Some(last)) => attr_span(first).start..attr_span(last).end,
+                        _ => 0..0,
+                    };
+
+                    for attr in attrs {
+                        let attr_span = attr_span(&attr);
+                        // Strings and `${...}` can be inherited too, but only if they're constant, like in a let
+                        let name = match self.normalize_key_part_as(attr, |name| name, |_| ())? {
+                            Either::Left(name) => name,
+                            Either::Right(()) => {
+                                return Err(NormalizeError::DynamicAttrInInherit {
+                                    span: attr_span,
+                                })
+                            }
                         };
-
-                        self.normalize_key_part_as(
-                            key_head,
-                            |name| {
-                                vec![AttrDef {
-                                    name,
-                                    inherited: false,
-                                    expr: value.clone(),
-                                }]
+                        defs.push(match &subject {
+                            Some(subject) => AttrPathDef {
+                                path: vec![AttrName::Symbol(name.clone())],
+                                inherited: false,
+                                expr: NormalNixExpr::Select {
+                                    subject: subject.clone(),
+                                    or_default: None,
+                                    path: vec![AttrName::Symbol(name)],
+                                },
+                                span: span.clone(),
                             },
-                            |name_expr| DynamicAttrDef {
-                                name_expr,
-                                value_expr: value.clone(),
+                            None => AttrPathDef {
+                                path: vec![AttrName::Symbol(name.clone())],
+                                inherited: true,
+                                expr: NormalNixExpr::Var(Var::at(
+                                    name,
+                                    attr_span.start..attr_span.end,
+                                )),
+                                span: span.clone(),
                             },
-                        )
-                    }
-                    // If the entry is of the form `inherit foo`
-                    Entry::Inherit(Inherit { from, idents }) => {
-                        let subject = from.map(|from| self.boxed_normalize(*from.expr));
-
-                        let attrs: Vec<AttrDef> = idents
-                            .into_iter()
-                            .map(|ident| match &subject {
-                                Some(subject) => AttrDef {
-                                    name: ident.inner.clone(),
-                                    inherited: false,
-                                    expr: NormalNixExpr::Select {
-                                        subject: subject.clone(),
-                                        or_default: None,
-                                        path: vec![AttrName::Symbol(ident.inner)],
-                                    },
-                                },
-                                None => AttrDef {
-                                    name: ident.inner.clone(),
-                                    inherited: true,
-                                    expr: NormalNixExpr::Var(ident.inner),
-                                },
-                            })
-                            .collect();
-
-                        Either::Left(attrs)
+                        });
                     }
                 }
-            });
-
-        // Sort attrs by key names. See attr_set_key_sorting test for explanation.
-        let attrs: Vec<AttrDef> = attrs.into_iter().flatten().collect();
-
-        let attrs = merge_attrs(attrs, vec![]);
-        let dynamic_attrs = merge_dynamic_attrs(dynamic_attrs, vec![]);
+            }
+        }
 
-        NormalNixExpr::Attrs {
+        Ok(NormalNixExpr::UnmergedAttrs {
             rec: attr_set.recursive,
-            attrs,
-            dynamic_attrs,
-        }
+            implicit: false,
+            defs,
+            span: attr_set.span.start..attr_set.span.end,
+        })
     }
 
     /// TODO
-    fn normalize_str(&self, str: Str) -> NormalNixExpr {
+    fn normalize_str(&self, str: Str) -> Result<NormalNixExpr> {
         // If any of the parts are Ast, then this string has interoplations in it
         if str
             .parts
@@ -348,24 +568,24 @@ impl Normalizer {
             .any(|part| matches!(part, InterpolPart::Interpolation(_)))
         {
             // The reference impl treats string interpolation as string concatenation with force_string: true
-            NormalNixExpr::OpConcatStrings {
+            Ok(NormalNixExpr::OpConcatStrings {
                 force_string: true,
                 es: str
                     .parts
                     .into_iter()
                     .map(|part| match part {
-                        InterpolPart::Literal(lit) => NormalNixExpr::String(lit),
+                        InterpolPart::Literal(lit) => Ok(NormalNixExpr::String(lit)),
                         InterpolPart::Interpolation(str_interpol) => {
                             self.normalize(*str_interpol.expr)
                         }
                     })
-                    .collect(),
-            }
+                    .collect::<Result<_>>()?,
+            })
         } else {
             // otherwise, there should either be only be one part which is a literal or nothing which indicates an empty string
             match &*str.parts {
-                [InterpolPart::Literal(lit)] => NormalNixExpr::String(lit.to_string()),
-                [] => NormalNixExpr::String("".to_string()),
+                [InterpolPart::Literal(lit)] => Ok(NormalNixExpr::String(lit.to_string())),
+                [] => Ok(NormalNixExpr::String("".to_string())),
                 other => unreachable!(
                     "String parts contained only multiple separate literals: {other:?}"
                 ),
@@ -374,36 +594,53 @@ impl Normalizer {
     }
 
     /// TODO
-    fn normalize_unary_op(&self, unary_op: UnaryOp) -> NormalNixExpr {
-        match unary_op.operator {
-            UnaryOpKind::Invert => NormalNixExpr::OpNot(self.boxed_normalize(*unary_op.expr)),
-            // The reference parser treats negation as subtraction from 0
-            UnaryOpKind::Negate => NormalNixExpr::Call {
-                fun: Box::new(NormalNixExpr::Var("__sub".to_string())),
-                args: vec![NormalNixExpr::Int(0), self.normalize(*unary_op.expr)],
-            },
-        }
+    fn normalize_unary_op(&self, unary_op: UnaryOp) -> Result<NormalNixExpr> {
+        Ok(match unary_op.operator {
+            UnaryOpKind::Invert => NormalNixExpr::OpNot(self.boxed_normalize(*unary_op.expr)?),
+            // The reference parser treats negation as subtraction from 0. The desugar-operators pass takes care of that.
+            UnaryOpKind::Negate => self.normalize_operator("-", vec![*unary_op.expr])?,
+        })
     }
 
     /// TODO
-    fn normalize_literal(&self, literal: Literal) -> NormalNixExpr {
+    fn normalize_literal(&self, literal: Literal) -> Result<NormalNixExpr> {
         match literal.kind {
-            LiteralKind::Float(nf) => NormalNixExpr::Float(nf),
-            LiteralKind::Integer(n) => NormalNixExpr::Int(n),
-            LiteralKind::Uri(path) => NormalNixExpr::String(path),
+            LiteralKind::Float(nf) => Ok(NormalNixExpr::Float(nf)),
+            LiteralKind::Integer(n) => Ok(NormalNixExpr::Int(n)),
+            // The reference impl fails to parse integers that don't fit in 64 bits
+            LiteralKind::IntegerOutOfRange(int) => Err(NormalizeError::IntegerOutOfRange {
+                literal: int,
+                span: literal.span,
+            }),
+            // Likewise for floats which are out of range
+            LiteralKind::FloatOutOfRange(float) => Err(NormalizeError::FloatOutOfRange {
+                literal: float,
+                span: literal.span,
+            }),
+            LiteralKind::Uri(path) => Ok(NormalNixExpr::String(path)),
         }
     }
 
     /// TODO
-    fn normalize_with(&self, with: With) -> NormalNixExpr {
-        NormalNixExpr::With {
-            attrs: self.boxed_normalize(*with.namespace),
-            body: self.boxed_normalize(*with.body),
-        }
+    fn normalize_with(&self, with: With) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::With {
+            attrs: self.boxed_normalize(*with.namespace)?,
+            body: self.boxed_normalize(*with.body)?,
+        })
     }
 
     /// TODO
-    fn normalize_path(&self, mut path: Path) -> NormalNixExpr {
+    fn normalize_path(&self, mut path: Path) -> Result<NormalNixExpr> {
+        // The reference impl's lexer rejects paths ending in a slash, including after an interpolation
+        if let Some(InterpolPart::Literal(last)) = path.parts.last() {
+            if last.ends_with('/') && !last.starts_with('<') {
+                return Err(NormalizeError::PathTrailingSlash {
+                    path: last.clone(),
+                    span: path.span,
+                });
+            }
+        }
+
         // If any of the parts are Interpolations, then the expression is normalized as a string concatenation with force_string: false
         if path
             .parts
@@ -415,27 +652,27 @@ impl Normalizer {
             let parts_tail = path.parts;
 
             let base_path = match parts_head {
-                InterpolPart::Literal(literal) => self.normalize_path_literal(literal),
+                InterpolPart::Literal(literal) => NormalNixExpr::Path(literal),
                 InterpolPart::Interpolation(_) => {
                     unreachable!("The first part of a Path should always be a literal")
                 }
             };
 
             let parts = parts_tail.into_iter().map(|part| match part {
-                InterpolPart::Literal(lit) => NormalNixExpr::String(lit),
+                InterpolPart::Literal(lit) => Ok(NormalNixExpr::String(lit)),
                 InterpolPart::Interpolation(str_interpol) => self.normalize(*str_interpol.expr),
             });
 
-            NormalNixExpr::OpConcatStrings {
+            Ok(NormalNixExpr::OpConcatStrings {
                 force_string: false,
-                es: std::iter::once(base_path)
-                    .chain(parts.into_iter())
-                    .collect(),
-            }
+                es: std::iter::once(Ok(base_path))
+                    .chain(parts)
+                    .collect::<Result<_>>()?,
+            })
         } else {
-            // otherwise, there should either be only be one part which is a literal. Expand it
+            // otherwise, there should either be only be one part which is a literal. The resolve-paths pass expands it.
             match &*path.parts {
-                [InterpolPart::Literal(lit)] => self.normalize_path_literal(lit.to_string()),
+                [InterpolPart::Literal(lit)] => Ok(NormalNixExpr::Path(lit.to_string())),
                 other => unreachable!(
                     "Path parts contained only multiple separate literals or was empty: {other:?}"
                 ),
@@ -444,58 +681,40 @@ impl Normalizer {
     }
 
     /// TODO
-    fn normalize_has_attr(&self, has_attr: HasAttr) -> NormalNixExpr {
-        NormalNixExpr::OpHasAttr {
-            subject: self.boxed_normalize(*has_attr.expr),
-            path: self.normalize_attr_path(has_attr.attrpath),
-        }
+    fn normalize_has_attr(&self, has_attr: HasAttr) -> Result<NormalNixExpr> {
+        Ok(NormalNixExpr::OpHasAttr {
+            subject: self.boxed_normalize(*has_attr.expr)?,
+            path: self.normalize_attr_path(has_attr.attrpath)?,
+        })
     }
 
-    fn normalize_attr_path(&self, attrpath: Attrpath) -> Vec<AttrName> {
+    fn normalize_attr_path(&self, attrpath: Attrpath) -> Result<Vec<AttrName>> {
         attrpath
             .attrs
             .into_iter()
             .map(|attr| {
-                self.normalize_key_part_as(attr, AttrName::Symbol, AttrName::Expr)
-                    .into_inner()
+                Ok(self
+                    .normalize_key_part_as(attr, AttrName::Symbol, AttrName::Expr)?
+                    .into_inner())
             })
             .collect()
     }
 
-    fn normalize_path_literal(&self, literal: String) -> NormalNixExpr {
-        let (anchor, path) = parse_path(literal);
-        match anchor {
-            Anchor::Absolute => NormalNixExpr::Path(canonicalize(path)),
-            Anchor::Relative => {
-                NormalNixExpr::Path(canonicalize(format!("{}/{}", self.base_path, path)))
-            }
-            Anchor::Home => NormalNixExpr::Path(format!("{}/{}", self.home_path, path)),
-            // The reference impl treats store paths as a call to __findFile with the args __nixPath and the path
-            Anchor::Store => NormalNixExpr::Call {
-                fun: Box::new(NormalNixExpr::Var("__findFile".to_string())),
-                args: vec![
-                    NormalNixExpr::Var("__nixPath".to_string()),
-                    NormalNixExpr::String(path),
-                ],
-            },
-        }
-    }
-
     fn normalize_key_part_as<ND, D, FND, FD>(
         &self,
         attr: Attr,
         non_dynamic: FND,
         dynamic: FD,
-    ) -> Either<ND, D>
+    ) -> Result<Either<ND, D>>
     where
         FND: Fn(String) -> ND,
         FD: Fn(NormalNixExpr) -> D,
     {
-        match attr {
+        Ok(match attr {
             // If the expression is a plain identifier, it's definitely not dynamic
-            Attr::Ident(Ident { inner }) => Either::Left(non_dynamic(inner)),
+            Attr::Ident(Ident { inner, .. }) => Either::Left(non_dynamic(inner)),
             // If the expression is a string, it's...
-            Attr::Str(str) => match self.normalize_str(str) {
+            Attr::Str(str) => match self.normalize_str(str)? {
                 // not dynamic if it's just a plain string
                 NormalNixExpr::String(s) => Either::Left(non_dynamic(s)),
                 // dynamic if it has string interpolations in it
@@ -503,97 +722,24 @@ impl Normalizer {
                 other => unreachable!("It shouldn't be possible for normalize_str to return anything else, but it did: {other:?}"),
             },
             // If the expression is of the form `${x}`, it's...
-            Attr::Dynamic(Dynamic { expr }) => match self.normalize(*expr) {
-                // _not_ dynamic if x is just a plain string (e.g., `${"foo"}`)
+            Attr::Dynamic(Dynamic { expr, .. }) => match passes::without_parens(
+                self.normalize(*expr)?,
+                |inner| matches!(inner, NormalNixExpr::String(_)),
+            ) {
+                // _not_ dynamic if x is just a plain string (e.g., `${"foo"}`, or `${("foo")}`, since the reference impl
+                // has no concept of parens)
                 NormalNixExpr::String(s) => Either::Left(non_dynamic(s)),
                 // dynamic otherwise
                 inner => Either::Right(dynamic(inner)),
             },
-        }
+        })
     }
 }
 
-fn canonicalize(path: String) -> String {
-    // Note that trailing slashes can't occur in user-written nix code, but they can appear in arguments to this function when normalizing interpolated paths.
-    // For example, when normalizing the path `/foo/${"bar"}`, normalize_path_with_interpol will call us with `"/foo/"`.
-    let has_trailing_slash = path.ends_with('/');
-
-    let mut res = vec![];
-
-    for comp in std::path::Path::new(&path).components() {
-        match comp {
-            std::path::Component::RootDir => {
-                res.push("");
-            }
-            std::path::Component::ParentDir => {
-                res.pop();
-            }
-            std::path::Component::Normal(s) => {
-                res.push(s.to_str().unwrap());
-            }
-            _ => {}
-        }
-    }
-
-    if has_trailing_slash {
-        res.push("");
-    }
-
-    let res = res.join("/");
-    if res.is_empty() {
-        "/".to_string()
-    } else {
-        res
-    }
-}
-
-fn merge_attrs(attrs1: Vec<AttrDef>, attrs2: Vec<AttrDef>) -> Vec<AttrDef> {
-    attrs1
-        .into_iter()
-        .chain(attrs2)
-        .into_grouping_map_by(|def| def.name.clone())
-        .fold_first(merge_attr_def)
-        .into_values()
-        .sorted_by(|a, b| a.name.cmp(&b.name))
-        .collect()
-}
-
-fn merge_dynamic_attrs(
-    dynamic_attrs1: Vec<DynamicAttrDef>,
-    dynamic_attrs2: Vec<DynamicAttrDef>,
-) -> Vec<DynamicAttrDef> {
-    // Nix disallows overlapping dynamic attrs. For example, `let x = "x"; in { ${x} = {}; ${x} = {}; }` is not legal.
-    // However, it doesn't check this until evaluation (because the key must be evaluated). During parsing, it just
-    // lumps all dynamic attributes together, like we do here.
-    chain!(dynamic_attrs1, dynamic_attrs2).collect()
-}
-
-fn merge_attr_def(def1: AttrDef, name: &String, def2: AttrDef) -> AttrDef {
-    if def1.inherited || def2.inherited {
-        panic!("{name} is inherited, but inherited defs cannot be merged.");
-    }
-
-    match (def1.expr, def2.expr) {
-        (
-            NormalNixExpr::Attrs {
-                rec: rec1,
-                attrs: attrs1,
-                dynamic_attrs: dynamic_attrs1,
-            },
-            NormalNixExpr::Attrs {
-                rec: rec2,
-                attrs: attrs2,
-                dynamic_attrs: dynamic_attrs2,
-            },
-        ) => AttrDef {
-            name: def1.name, // def1.name == def2.name == name
-            inherited: false,
-            expr: NormalNixExpr::Attrs {
-                rec: rec1 || rec2,
-                attrs: merge_attrs(attrs1, attrs2),
-                dynamic_attrs: merge_dynamic_attrs(dynamic_attrs1, dynamic_attrs2),
-            },
-        },
-        _ => panic!("Cannot merge {name}, because one of the values is not an attrset"),
+fn attr_span(attr: &Attr) -> Span {
+    match attr {
+        Attr::Ident(ident) => ident.span,
+        Attr::Dynamic(dynamic) => dynamic.span,
+        Attr::Str(str) => str.span,
     }
 }
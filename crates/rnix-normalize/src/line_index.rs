@@ -0,0 +1,37 @@
+/// Maps byte offsets into a source to 1-based lines and columns, counted the way the reference impl's lexer does it:
+/// columns are in bytes (so a tab is one column, and a multibyte character is several), and `\n`, `\r\n`, and a lone
+/// `\r` each end a line.
+pub struct LineIndex {
+    /// The offset of the start of each line
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> Self {
+        let bytes = source.as_bytes();
+        let mut line_starts = vec![0];
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\r' if bytes.get(i + 1) == Some(&b'\n') => {
+                    i += 1;
+                    line_starts.push(i + 1);
+                }
+                b'\r' | b'\n' => line_starts.push(i + 1),
+                _ => {}
+            }
+            i += 1;
+        }
+
+        LineIndex { line_starts }
+    }
+
+    /// The line and column of `offset`
+    pub fn line_col(&self, offset: usize) -> (u32, u32) {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        (
+            (line + 1) as u32,
+            (offset - self.line_starts[line] + 1) as u32,
+        )
+    }
+}
@@ -0,0 +1,869 @@
+//! The passes which remove the sugar that [`crate::Normalizer`] leaves in when lowering rnix-parser's AST, so that
+//! what's left is what the reference impl produces. Each pass can be switched off, which leaves its sugar in place, and
+//! what each pass produced can be inspected, for telling which of them a mismatch comes from.
+
+use std::{
+    collections::{btree_map, BTreeMap, BTreeSet, VecDeque},
+    fmt,
+    ops::Range,
+    str::FromStr,
+};
+
+use itertools::Itertools;
+use normal_ast::{
+    AttrDef, AttrName, AttrPathDef, DynamicAttrDef, Formal, Formals, NormalNixExpr, Var,
+};
+use rnix_ast::ast::Span;
+
+use crate::{
+    value::{parse_path, Anchor},
+    NixVersionProfile, NormalizeError, Result,
+};
+
+/// A normalization pass. Passes run in the order they're declared in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Pass {
+    /// Turn operators like `-` and `<` into calls to builtins
+    DesugarOperators,
+    /// Make paths absolute, and turn `<...>` into a call to `__findFile`
+    ResolvePaths,
+    /// Squash a call whose function is a call into a single call, like the reference impl does while parsing. This
+    /// applies to calls made up by earlier passes too, e.g. `(a - b) c` is a call to `__sub` with three arguments. Does
+    /// nothing for versions of Nix where a call only takes one argument.
+    FlattenCalls,
+    /// Turn `x.y = 1` into `x = { y = 1; }`. merge-attrs expands whatever attr paths are left itself, so switching this
+    /// off only shows in the intermediate stages (see [`Pass::can_be_disabled`]).
+    ExpandAttrpaths,
+    /// Merge definitions of the same attr, rejecting those which can't be merged
+    MergeAttrs,
+    /// Merge adjacent strings in string literals, and unwrap literals which end up as a single string. The reference
+    /// impl's lexer may split an indented string into several strings where rnix-parser has one (e.g., `''foo \$''` is
+    /// `foo ` and `\$`), so the reference impl's side goes through this too (see [`merge_string_fragments`]). As a
+    /// consequence, `"a${"b"}"` can't be told apart from `"ab"`. Only string literals are touched, never the
+    /// concatenations that `+` produces.
+    MergeStringFragments,
+    /// Discard parens. This comes last, so that switching it off shows every paren, while the other passes still see
+    /// through them wherever the reference impl would (it has no concept of them).
+    StripParens,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 7] = [
+        Pass::DesugarOperators,
+        Pass::ResolvePaths,
+        Pass::FlattenCalls,
+        Pass::ExpandAttrpaths,
+        Pass::MergeAttrs,
+        Pass::MergeStringFragments,
+        Pass::StripParens,
+    ];
+
+    /// The name of the pass, e.g. `desugar-operators`
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::DesugarOperators => "desugar-operators",
+            Pass::ResolvePaths => "resolve-paths",
+            Pass::FlattenCalls => "flatten-calls",
+            Pass::ExpandAttrpaths => "expand-attrpaths",
+            Pass::MergeAttrs => "merge-attrs",
+            Pass::MergeStringFragments => "merge-string-fragments",
+            Pass::StripParens => "strip-parens",
+        }
+    }
+
+    /// Whether switching the pass off shows in the final AST. Only expand-attrpaths doesn't, since merge-attrs expands
+    /// attr paths itself. Switching off merge-attrs instead shows them expanded, but not merged.
+    pub fn can_be_disabled(self) -> bool {
+        self != Pass::ExpandAttrpaths
+    }
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Pass {
+    type Err = UnknownPass;
+
+    fn from_str(name: &str) -> std::result::Result<Self, Self::Err> {
+        Pass::ALL
+            .into_iter()
+            .find(|pass| pass.name() == name)
+            .ok_or_else(|| UnknownPass(name.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownPass(pub String);
+
+impl fmt::Display for UnknownPass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown normalization pass '{}' (expected one of: {})",
+            self.0,
+            Pass::ALL.iter().join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownPass {}
+
+/// Which passes to run. By default, that's all of them, which is what it takes to produce what the reference impl
+/// does.
+#[derive(Clone, Debug, Default)]
+pub struct Passes {
+    disabled: BTreeSet<Pass>,
+}
+
+impl Passes {
+    pub fn disable(&mut self, pass: Pass) {
+        self.disabled.insert(pass);
+    }
+
+    pub fn is_enabled(&self, pass: Pass) -> bool {
+        !self.disabled.contains(&pass)
+    }
+}
+
+/// Run `pass` over `expr`, following the rules of `profile`. `base_path` and `home_path` are what relative paths and
+/// `~` are resolved against.
+pub(crate) fn run(
+    pass: Pass,
+    expr: NormalNixExpr,
+    base_path: &str,
+    home_path: &str,
+    profile: NixVersionProfile,
+) -> Result<NormalNixExpr> {
+    match pass {
+        Pass::DesugarOperators => desugar_operators(expr, profile),
+        Pass::ResolvePaths => resolve_paths(expr, base_path, home_path, profile),
+        Pass::FlattenCalls if profile.has_multi_arg_calls() => flatten_calls(expr),
+        Pass::FlattenCalls => Ok(expr),
+        Pass::ExpandAttrpaths => expand_attrpaths(expr),
+        Pass::MergeAttrs => merge_attrs(expr),
+        Pass::MergeStringFragments => merge_fragments(expr),
+        Pass::StripParens => strip_parens(expr),
+    }
+}
+
+/// A call to the builtin `name` with `args`, as `profile`'s version of Nix makes it
+fn call_builtin(name: &str, args: Vec<NormalNixExpr>, profile: NixVersionProfile) -> NormalNixExpr {
+    let fun = NormalNixExpr::Var(Var::new(name));
+    if profile.has_multi_arg_calls() {
+        NormalNixExpr::Call {
+            fun: Box::new(fun),
+            args,
+        }
+    } else {
+        // Applied to one argument at a time
+        args.into_iter().fold(fun, |fun, arg| NormalNixExpr::Call {
+            fun: Box::new(fun),
+            args: vec![arg],
+        })
+    }
+}
+
+fn desugar_operators(expr: NormalNixExpr, profile: NixVersionProfile) -> Result<NormalNixExpr> {
+    let call = |name: &str, args| call_builtin(name, args, profile);
+
+    let (op, operands) = match map_children(expr, &mut |expr| desugar_operators(expr, profile))? {
+        NormalNixExpr::Operator { op, operands } => (op, operands),
+        other => return Ok(other),
+    };
+
+    Ok(
+        match (op.as_str(), <[NormalNixExpr; 2]>::try_from(operands)) {
+            // The reference impl treats negation as subtraction from 0
+            ("-", Err(mut operands)) if operands.len() == 1 => {
+                call("__sub", vec![NormalNixExpr::Int(0), operands.remove(0)])
+            }
+            ("-", Ok([lhs, rhs])) => call("__sub", vec![lhs, rhs]),
+            ("*", Ok([lhs, rhs])) => call("__mul", vec![lhs, rhs]),
+            ("/", Ok([lhs, rhs])) => call("__div", vec![lhs, rhs]),
+            ("<", Ok([lhs, rhs])) => call("__lessThan", vec![lhs, rhs]),
+            // The rest are __lessThan with the args flipped and/or negated. Note the argument order!
+            ("<=", Ok([lhs, rhs])) => {
+                NormalNixExpr::OpNot(Box::new(call("__lessThan", vec![rhs, lhs])))
+            }
+            (">", Ok([lhs, rhs])) => call("__lessThan", vec![rhs, lhs]),
+            (">=", Ok([lhs, rhs])) => {
+                NormalNixExpr::OpNot(Box::new(call("__lessThan", vec![lhs, rhs])))
+            }
+            (op, operands) => {
+                unreachable!("Lowering never produces the operator `{op}` with {operands:?}")
+            }
+        },
+    )
+}
+
+fn resolve_paths(
+    expr: NormalNixExpr,
+    base_path: &str,
+    home_path: &str,
+    profile: NixVersionProfile,
+) -> Result<NormalNixExpr> {
+    Ok(
+        match map_children(expr, &mut |expr| {
+            resolve_paths(expr, base_path, home_path, profile)
+        })? {
+            NormalNixExpr::Path(literal) => resolve_path(literal, base_path, home_path, profile),
+            other => other,
+        },
+    )
+}
+
+fn resolve_path(
+    literal: String,
+    base_path: &str,
+    home_path: &str,
+    profile: NixVersionProfile,
+) -> NormalNixExpr {
+    let (anchor, path) = parse_path(literal);
+    match anchor {
+        Anchor::Absolute => NormalNixExpr::Path(canonicalize(path)),
+        Anchor::Relative => NormalNixExpr::Path(canonicalize(format!("{base_path}/{path}"))),
+        Anchor::Home => NormalNixExpr::Path(format!("{home_path}/{path}")),
+        // The reference impl treats store paths as a call to __findFile with the args __nixPath and the path
+        Anchor::Store => call_builtin(
+            "__findFile",
+            vec![
+                NormalNixExpr::Var(Var::new("__nixPath")),
+                NormalNixExpr::String(path),
+            ],
+            profile,
+        ),
+    }
+}
+
+fn canonicalize(path: String) -> String {
+    // Note that trailing slashes can't occur in user-written nix code, but they can appear in arguments to this function when normalizing interpolated paths.
+    // For example, when normalizing the path `/foo/${"bar"}`, the first part is `"/foo/"`.
+    let has_trailing_slash = path.ends_with('/');
+
+    let mut res = vec![];
+
+    for comp in std::path::Path::new(&path).components() {
+        match comp {
+            std::path::Component::RootDir => {
+                res.push("");
+            }
+            std::path::Component::ParentDir => {
+                res.pop();
+            }
+            std::path::Component::Normal(s) => {
+                res.push(s.to_str().unwrap());
+            }
+            _ => {}
+        }
+    }
+
+    if has_trailing_slash {
+        res.push("");
+    }
+
+    let res = res.join("/");
+    if res.is_empty() {
+        "/".to_string()
+    } else {
+        res
+    }
+}
+
+fn flatten_calls(expr: NormalNixExpr) -> Result<NormalNixExpr> {
+    Ok(match map_children(expr, &mut flatten_calls)? {
+        // The function has been flattened already, so one level is enough
+        NormalNixExpr::Call { fun, args } => {
+            match without_parens(*fun, |fun| matches!(fun, NormalNixExpr::Call { .. })) {
+                NormalNixExpr::Call {
+                    fun,
+                    args: mut inner_args,
+                } => {
+                    inner_args.extend(args);
+                    NormalNixExpr::Call {
+                        fun,
+                        args: inner_args,
+                    }
+                }
+                fun => NormalNixExpr::Call {
+                    fun: Box::new(fun),
+                    args,
+                },
+            }
+        }
+        other => other,
+    })
+}
+
+fn expand_attrpaths(expr: NormalNixExpr) -> Result<NormalNixExpr> {
+    Ok(match map_children(expr, &mut expand_attrpaths)? {
+        NormalNixExpr::UnmergedAttrs {
+            rec,
+            implicit,
+            defs,
+            span,
+        } => NormalNixExpr::UnmergedAttrs {
+            rec,
+            implicit,
+            defs: defs.into_iter().map(expand_def).collect(),
+            span,
+        },
+        other => other,
+    })
+}
+
+/// Turn `x.y.z = e` into `x = { y = { z = e; }; }`, where the made up sets are implicit, and located at the whole
+/// attr path like every def the original one made.
+fn expand_def(def: AttrPathDef) -> AttrPathDef {
+    let AttrPathDef {
+        mut path,
+        inherited,
+        mut expr,
+        span,
+    } = def;
+
+    while path.len() > 1 {
+        let key = path.pop().expect("The attr path is longer than one");
+        expr = NormalNixExpr::UnmergedAttrs {
+            rec: false,
+            implicit: true,
+            defs: vec![AttrPathDef {
+                path: vec![key],
+                inherited: false,
+                expr,
+                span: span.clone(),
+            }],
+            span: span.clone(),
+        };
+    }
+
+    AttrPathDef {
+        path,
+        inherited,
+        expr,
+        span,
+    }
+}
+
+/// Unlike the other passes, this works from the top down, since how a set is merged into another depends on where the
+/// set was written, which is lost once it's merged itself.
+fn merge_attrs(expr: NormalNixExpr) -> Result<NormalNixExpr> {
+    match expr {
+        NormalNixExpr::UnmergedAttrs { rec, defs, .. } => Ok(build_attrs(rec, defs)?.finish()),
+        NormalNixExpr::Let { attrs, body } => match *attrs {
+            NormalNixExpr::UnmergedAttrs { defs, span, .. } => {
+                let attrs = build_attrs(false, defs)?;
+
+                // Unlike in attr sets, a dynamic attr in a let is rejected while parsing. Note that `${"foo"}` isn't
+                // dynamic.
+                if !attrs.dynamic_attrs.is_empty() {
+                    return Err(NormalizeError::DynamicAttrInLet {
+                        span: to_span(&span),
+                    });
+                }
+
+                Ok(NormalNixExpr::Let {
+                    attrs: Box::new(attrs.finish()),
+                    body: Box::new(merge_attrs(*body)?),
+                })
+            }
+            attrs => map_children(
+                NormalNixExpr::Let {
+                    attrs: Box::new(attrs),
+                    body,
+                },
+                &mut merge_attrs,
+            ),
+        },
+        other => map_children(other, &mut merge_attrs),
+    }
+}
+
+fn build_attrs(rec: bool, defs: Vec<AttrPathDef>) -> Result<AttrsBuilder> {
+    let mut builder = AttrsBuilder::new(rec);
+    for def in defs {
+        builder.add_def(&[], def)?;
+    }
+    Ok(builder)
+}
+
+/// Run the merge-string-fragments pass over a tree from any parser, e.g. the reference impl's. See
+/// [`Pass::MergeStringFragments`].
+pub fn merge_string_fragments(expr: NormalNixExpr) -> NormalNixExpr {
+    merge_fragments(expr).expect("Merging string fragments never fails")
+}
+
+fn merge_fragments(expr: NormalNixExpr) -> Result<NormalNixExpr> {
+    let es = match map_children(expr, &mut merge_fragments)? {
+        NormalNixExpr::OpConcatStrings {
+            force_string: true,
+            es,
+        } => es,
+        other => return Ok(other),
+    };
+
+    let mut merged: Vec<NormalNixExpr> = Vec::with_capacity(es.len());
+    for e in es {
+        // Like the reference impl, see through parens, e.g. in `"a${("b")}"`
+        match (
+            merged.last_mut(),
+            without_parens(e, |e| matches!(e, NormalNixExpr::String(_))),
+        ) {
+            (Some(NormalNixExpr::String(last)), NormalNixExpr::String(s)) => last.push_str(&s),
+            (_, e) => merged.push(e),
+        }
+    }
+
+    if let [NormalNixExpr::String(_)] = &merged[..] {
+        return Ok(merged.remove(0));
+    }
+    Ok(NormalNixExpr::OpConcatStrings {
+        force_string: true,
+        es: merged,
+    })
+}
+
+fn strip_parens(expr: NormalNixExpr) -> Result<NormalNixExpr> {
+    Ok(match map_children(expr, &mut strip_parens)? {
+        // The reference impl has no concept of parens, so simply discard them
+        NormalNixExpr::Paren(expr) => *expr,
+        other => other,
+    })
+}
+
+/// `expr` without any parens around it, if what's inside them is `wanted`. Otherwise, `expr` as it is.
+pub(crate) fn without_parens(
+    expr: NormalNixExpr,
+    wanted: impl Fn(&NormalNixExpr) -> bool,
+) -> NormalNixExpr {
+    let mut inner = &expr;
+    while let NormalNixExpr::Paren(expr) = inner {
+        inner = expr;
+    }
+    if !wanted(inner) {
+        return expr;
+    }
+
+    let mut expr = expr;
+    while let NormalNixExpr::Paren(inner) = expr {
+        expr = *inner;
+    }
+    expr
+}
+
+fn to_span(range: &Range<usize>) -> Span {
+    Span {
+        start: range.start,
+        end: range.end,
+    }
+}
+
+type Rewrite<'a> = &'a mut dyn FnMut(NormalNixExpr) -> Result<NormalNixExpr>;
+
+/// Rewrite each of `expr`'s children with `f`, in the order they were written, keeping `expr` itself as it is.
+fn map_children(expr: NormalNixExpr, f: Rewrite) -> Result<NormalNixExpr> {
+    fn map_vec(exprs: Vec<NormalNixExpr>, f: Rewrite) -> Result<Vec<NormalNixExpr>> {
+        exprs.into_iter().map(&mut *f).collect()
+    }
+    fn map_path(path: Vec<AttrName>, f: Rewrite) -> Result<Vec<AttrName>> {
+        path.into_iter()
+            .map(|name| match name {
+                AttrName::Symbol(name) => Ok(AttrName::Symbol(name)),
+                AttrName::Expr(expr) => Ok(AttrName::Expr(f(expr)?)),
+            })
+            .collect()
+    }
+
+    Ok(match expr {
+        leaf @ (NormalNixExpr::Int(_)
+        | NormalNixExpr::Float(_)
+        | NormalNixExpr::String(_)
+        | NormalNixExpr::Path(_)
+        | NormalNixExpr::Var(_)
+        | NormalNixExpr::Pos { .. }) => leaf,
+        NormalNixExpr::Select {
+            subject,
+            or_default,
+            path,
+        } => NormalNixExpr::Select {
+            subject: Box::new(f(*subject)?),
+            path: map_path(path, f)?,
+            or_default: or_default.map(|e| f(*e).map(Box::new)).transpose()?,
+        },
+        NormalNixExpr::OpHasAttr { subject, path } => NormalNixExpr::OpHasAttr {
+            subject: Box::new(f(*subject)?),
+            path: map_path(path, f)?,
+        },
+        NormalNixExpr::Attrs {
+            rec,
+            attrs,
+            dynamic_attrs,
+        } => NormalNixExpr::Attrs {
+            rec,
+            attrs: attrs
+                .into_iter()
+                .map(|def| {
+                    Ok(AttrDef {
+                        expr: f(def.expr)?,
+                        ..def
+                    })
+                })
+                .collect::<Result<_>>()?,
+            dynamic_attrs: dynamic_attrs
+                .into_iter()
+                .map(|def| {
+                    Ok(DynamicAttrDef {
+                        name_expr: f(def.name_expr)?,
+                        value_expr: f(def.value_expr)?,
+                    })
+                })
+                .collect::<Result<_>>()?,
+        },
+        NormalNixExpr::List(items) => NormalNixExpr::List(map_vec(items, f)?),
+        NormalNixExpr::Lambda { arg, formals, body } => NormalNixExpr::Lambda {
+            arg,
+            formals: formals
+                .map(|formals| {
+                    Ok(Formals {
+                        ellipsis: formals.ellipsis,
+                        entries: formals
+                            .entries
+                            .into_iter()
+                            .map(|(name, formal)| {
+                                Ok((
+                                    name,
+                                    Formal {
+                                        default: formal.default.map(&mut *f).transpose()?,
+                                    },
+                                ))
+                            })
+                            .collect::<Result<_>>()?,
+                    })
+                })
+                .transpose()?,
+            body: Box::new(f(*body)?),
+        },
+        NormalNixExpr::Call { fun, args } => NormalNixExpr::Call {
+            fun: Box::new(f(*fun)?),
+            args: map_vec(args, f)?,
+        },
+        NormalNixExpr::Let { attrs, body } => NormalNixExpr::Let {
+            attrs: Box::new(f(*attrs)?),
+            body: Box::new(f(*body)?),
+        },
+        NormalNixExpr::With { attrs, body } => NormalNixExpr::With {
+            attrs: Box::new(f(*attrs)?),
+            body: Box::new(f(*body)?),
+        },
+        NormalNixExpr::If { cond, then, else_ } => NormalNixExpr::If {
+            cond: Box::new(f(*cond)?),
+            then: Box::new(f(*then)?),
+            else_: Box::new(f(*else_)?),
+        },
+        NormalNixExpr::Assert { cond, body } => NormalNixExpr::Assert {
+            cond: Box::new(f(*cond)?),
+            body: Box::new(f(*body)?),
+        },
+        NormalNixExpr::OpNot(expr) => NormalNixExpr::OpNot(Box::new(f(*expr)?)),
+        NormalNixExpr::OpEq(lhs, rhs) => {
+            NormalNixExpr::OpEq(Box::new(f(*lhs)?), Box::new(f(*rhs)?))
+        }
+        NormalNixExpr::OpNEq(lhs, rhs) => {
+            NormalNixExpr::OpNEq(Box::new(f(*lhs)?), Box::new(f(*rhs)?))
+        }
+        NormalNixExpr::OpAnd(lhs, rhs) => {
+            NormalNixExpr::OpAnd(Box::new(f(*lhs)?), Box::new(f(*rhs)?))
+        }
+        NormalNixExpr::OpOr(lhs, rhs) => {
+            NormalNixExpr::OpOr(Box::new(f(*lhs)?), Box::new(f(*rhs)?))
+        }
+        NormalNixExpr::OpImpl(lhs, rhs) => {
+            NormalNixExpr::OpImpl(Box::new(f(*lhs)?), Box::new(f(*rhs)?))
+        }
+        NormalNixExpr::OpUpdate(lhs, rhs) => {
+            NormalNixExpr::OpUpdate(Box::new(f(*lhs)?), Box::new(f(*rhs)?))
+        }
+        NormalNixExpr::OpConcatLists(lhs, rhs) => {
+            NormalNixExpr::OpConcatLists(Box::new(f(*lhs)?), Box::new(f(*rhs)?))
+        }
+        NormalNixExpr::OpConcatStrings { force_string, es } => NormalNixExpr::OpConcatStrings {
+            force_string,
+            es: map_vec(es, f)?,
+        },
+        NormalNixExpr::Paren(expr) => NormalNixExpr::Paren(Box::new(f(*expr)?)),
+        NormalNixExpr::Operator { op, operands } => NormalNixExpr::Operator {
+            op,
+            operands: map_vec(operands, f)?,
+        },
+        NormalNixExpr::UnmergedAttrs {
+            rec,
+            implicit,
+            defs,
+            span,
+        } => NormalNixExpr::UnmergedAttrs {
+            rec,
+            implicit,
+            defs: defs
+                .into_iter()
+                .map(|def| {
+                    Ok(AttrPathDef {
+                        path: map_path(def.path, f)?,
+                        expr: f(def.expr)?,
+                        ..def
+                    })
+                })
+                .collect::<Result<_>>()?,
+            span,
+        },
+    })
+}
+
+/// Show an attr path the way the reference impl does in its errors.
+fn show_attr_path(keys: &[AttrName]) -> String {
+    keys.iter()
+        .map(|key| match key {
+            AttrName::Symbol(name) => name.clone(),
+            AttrName::Expr(_) => "\"${...}\"".to_string(),
+        })
+        .join(".")
+}
+
+/// An attr set which is being built up one definition at a time, the same way the reference impl's parser does it
+/// (see `addAttr` in its parser.y), so that definitions of the same attr are merged or rejected just like it would.
+struct AttrsBuilder {
+    rec: bool,
+    attrs: BTreeMap<String, BuilderDef>,
+    dynamic_attrs: Vec<(NormalNixExpr, BuilderValue)>,
+}
+
+struct BuilderDef {
+    value: BuilderValue,
+    inherited: bool,
+    span: Span,
+}
+
+enum BuilderValue {
+    Attrs(AttrsBuilder),
+    Expr(NormalNixExpr),
+}
+
+impl AttrsBuilder {
+    fn new(rec: bool) -> Self {
+        AttrsBuilder {
+            rec,
+            attrs: BTreeMap::new(),
+            dynamic_attrs: vec![],
+        }
+    }
+
+    /// Add `def`, whose attr path is relative to `prefix`. The defs of an implicit set are added with its attr path as
+    /// the prefix, just as if that path had never been expanded.
+    fn add_def(&mut self, prefix: &[AttrName], def: AttrPathDef) -> Result<()> {
+        let mut keys = prefix.to_vec();
+        for name in def.path {
+            keys.push(match name {
+                AttrName::Symbol(name) => AttrName::Symbol(name),
+                AttrName::Expr(expr) => AttrName::Expr(merge_attrs(expr)?),
+            });
+        }
+        let span = to_span(&def.span);
+
+        // Attr set values are kept unfinished, since later defs may still add attrs to them. For example,
+        // `{ x = { y = 1; }; x.z = 2; }` is the same as `{ x = { y = 1; z = 2; }; }`.
+        match without_parens(def.expr, |expr| {
+            matches!(expr, NormalNixExpr::UnmergedAttrs { .. })
+        }) {
+            NormalNixExpr::UnmergedAttrs {
+                implicit: true,
+                defs,
+                ..
+            } => defs
+                .into_iter()
+                .try_for_each(|def| self.add_def(&keys, def)),
+            value if def.inherited => match keys.pop() {
+                Some(AttrName::Symbol(name)) if keys.is_empty() => self.define(
+                    name,
+                    BuilderDef {
+                        value: BuilderValue::Expr(value),
+                        inherited: true,
+                        span,
+                    },
+                ),
+                _ => unreachable!("Only single names are inherited"),
+            },
+            value => {
+                let value = match value {
+                    NormalNixExpr::UnmergedAttrs { rec, defs, .. } => {
+                        BuilderValue::Attrs(build_attrs(rec, defs)?)
+                    }
+                    other => BuilderValue::Expr(merge_attrs(other)?),
+                };
+                let path = show_attr_path(&keys);
+                self.add(keys.into(), value, &path, span)
+            }
+        }
+    }
+
+    /// Define `keys = value`. `path` is the whole attr path, for error messages.
+    fn add(
+        &mut self,
+        mut keys: VecDeque<AttrName>,
+        value: BuilderValue,
+        path: &str,
+        span: Span,
+    ) -> Result<()> {
+        let key = keys.pop_front().expect("An attr path is never empty");
+
+        if keys.is_empty() {
+            return self.add_last(key, value, path, span);
+        }
+
+        match key {
+            // `x.y = ...` adds `y` to `x` if `x` is already defined as an attr set, or defines it as one otherwise
+            AttrName::Symbol(name) => match self.attrs.entry(name) {
+                btree_map::Entry::Occupied(entry) => match entry.into_mut() {
+                    BuilderDef {
+                        value: BuilderValue::Attrs(nested),
+                        inherited: false,
+                        ..
+                    } => nested.add(keys, value, path, span),
+                    previous => Err(NormalizeError::DuplicateAttr {
+                        path: path.to_string(),
+                        span,
+                        previous: previous.span,
+                    }),
+                },
+                btree_map::Entry::Vacant(entry) => {
+                    let mut nested = AttrsBuilder::new(false);
+                    nested.add(keys, value, path, span)?;
+                    entry.insert(BuilderDef {
+                        value: BuilderValue::Attrs(nested),
+                        inherited: false,
+                        span,
+                    });
+                    Ok(())
+                }
+            },
+            // Dynamic attrs can't be merged during parsing, because the name isn't known yet
+            AttrName::Expr(name_expr) => {
+                let mut nested = AttrsBuilder::new(false);
+                nested.add(keys, value, path, span)?;
+                self.dynamic_attrs
+                    .push((name_expr, BuilderValue::Attrs(nested)));
+                Ok(())
+            }
+        }
+    }
+
+    fn add_last(
+        &mut self,
+        key: AttrName,
+        value: BuilderValue,
+        path: &str,
+        span: Span,
+    ) -> Result<()> {
+        match key {
+            AttrName::Symbol(name) => match self.attrs.entry(name) {
+                btree_map::Entry::Occupied(entry) => match (entry.into_mut(), value) {
+                    // Two attr set literals for the same attr are merged, but only one level deep
+                    (
+                        BuilderDef {
+                            value: BuilderValue::Attrs(existing),
+                            ..
+                        },
+                        BuilderValue::Attrs(new),
+                    ) => {
+                        for (name, def) in new.attrs {
+                            // Note that the reference impl reports these the other way around: the error is at the
+                            // existing def, and the new one is the "previous" one.
+                            existing.define(name, def).map_err(|err| match err {
+                                NormalizeError::DuplicateAttr {
+                                    path,
+                                    span,
+                                    previous,
+                                } => NormalizeError::DuplicateAttr {
+                                    path,
+                                    span: previous,
+                                    previous: span,
+                                },
+                                err => err,
+                            })?;
+                        }
+                        // The reference impl drops the dynamic attrs of the second set (and its `rec`) here.
+                        Ok(())
+                    }
+                    (previous, _) => Err(NormalizeError::DuplicateAttr {
+                        path: path.to_string(),
+                        span,
+                        previous: previous.span,
+                    }),
+                },
+                btree_map::Entry::Vacant(entry) => {
+                    entry.insert(BuilderDef {
+                        value,
+                        inherited: false,
+                        span,
+                    });
+                    Ok(())
+                }
+            },
+            // Nix disallows overlapping dynamic attrs. For example, `let x = "x"; in { ${x} = {}; ${x} = {}; }` is not
+            // legal. However, it doesn't check this until evaluation (because the key must be evaluated). During
+            // parsing, it just lumps all dynamic attributes together, like we do here.
+            AttrName::Expr(name_expr) => {
+                self.dynamic_attrs.push((name_expr, value));
+                Ok(())
+            }
+        }
+    }
+
+    /// Define a single attr, which must not be defined yet. Unlike [`AttrsBuilder::add`], nothing is merged.
+    fn define(&mut self, name: String, def: BuilderDef) -> Result<()> {
+        match self.attrs.entry(name) {
+            btree_map::Entry::Occupied(entry) => Err(NormalizeError::DuplicateAttr {
+                path: entry.key().clone(),
+                span: def.span,
+                previous: entry.get().span,
+            }),
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(def);
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(self) -> NormalNixExpr {
+        NormalNixExpr::Attrs {
+            rec: self.rec,
+            // Sorted by key names, since this is a BTreeMap. See attr_set_key_sorting test for explanation.
+            attrs: self
+                .attrs
+                .into_iter()
+                .map(|(name, def)| AttrDef {
+                    name,
+                    inherited: def.inherited,
+                    expr: def.value.finish(),
+                })
+                .collect(),
+            dynamic_attrs: self
+                .dynamic_attrs
+                .into_iter()
+                .map(|(name_expr, value)| DynamicAttrDef {
+                    name_expr,
+                    value_expr: value.finish(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl BuilderValue {
+    fn finish(self) -> NormalNixExpr {
+        match self {
+            BuilderValue::Attrs(attrs) => attrs.finish(),
+            BuilderValue::Expr(expr) => expr,
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Everything outside of an expression that affects how its paths are normalized. Making these explicit (rather than
+/// reading them from the environment while normalizing) keeps comparisons reproducible across machines.
+#[derive(Clone, Debug)]
+pub struct PathContext {
+    /// What relative paths are relative to in expressions which weren't read from a file, and what relative file names
+    /// are relative to
+    pub cwd: String,
+    /// What `~` expands to
+    pub home: String,
+    pub symlinks: SymlinkPolicy,
+    /// Where `<...>` paths are looked up, like `NIX_PATH`. The reference impl doesn't look these up while parsing, so
+    /// this is only used to find files to parse.
+    pub search_path: Vec<SearchPathEntry>,
+}
+
+/// What to do with symlinks in the names of files to parse
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Use file names as given, like the reference impl does when asked to parse a file
+    Keep,
+    /// Resolve symlinks first, like the reference impl does for `import`
+    Resolve,
+}
+
+/// An entry of the search path, either `prefix=path` or just `path`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchPathEntry {
+    pub prefix: Option<String>,
+    pub path: String,
+}
+
+impl SearchPathEntry {
+    pub fn parse(entry: &str) -> Self {
+        match entry.split_once('=') {
+            Some((prefix, path)) => SearchPathEntry {
+                prefix: Some(prefix.to_string()),
+                path: path.to_string(),
+            },
+            None => SearchPathEntry {
+                prefix: None,
+                path: entry.to_string(),
+            },
+        }
+    }
+}
+
+impl PathContext {
+    /// The context of this process, i.e., its working directory, `HOME`, and `NIX_PATH`, without resolving symlinks
+    pub fn from_env() -> Result<Self, PathContextError> {
+        Ok(PathContext {
+            cwd: Self::current_dir()?,
+            home: env::var("HOME").map_err(|_| PathContextError::NoHome)?,
+            symlinks: SymlinkPolicy::Keep,
+            search_path: Self::search_path_from_env(),
+        })
+    }
+
+    pub fn current_dir() -> Result<String, PathContextError> {
+        let cwd =
+            env::current_dir().map_err(|err| PathContextError::Io(PathBuf::from("."), err))?;
+        cwd.into_os_string()
+            .into_string()
+            .map_err(|cwd| PathContextError::NotUtf8(cwd.into()))
+    }
+
+    /// The search path in `NIX_PATH`, which is empty if it isn't set. Entries which are URLs aren't supported.
+    pub fn search_path_from_env() -> Vec<SearchPathEntry> {
+        env::var("NIX_PATH")
+            .map(|nix_path| {
+                nix_path
+                    .split(':')
+                    .filter(|entry| !entry.is_empty())
+                    .map(SearchPathEntry::parse)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Look up `<name>` in the search path, the same way the reference impl's `findFile` does: the first entry which
+    /// matches `name` and exists wins.
+    pub fn find_file(&self, name: &str) -> Option<PathBuf> {
+        self.search_path.iter().find_map(|entry| {
+            let candidate = match &entry.prefix {
+                None => Path::new(&entry.path).join(name),
+                Some(prefix) if name == prefix => PathBuf::from(&entry.path),
+                Some(prefix) => {
+                    let rest = name.strip_prefix(prefix.as_str())?.strip_prefix('/')?;
+                    Path::new(&entry.path).join(rest)
+                }
+            };
+            candidate.exists().then_some(candidate)
+        })
+    }
+
+    /// The name that the file at `file` should be parsed as, by both parsers. It's made absolute, since the reference
+    /// impl can't resolve paths relative to a relative directory, and symlinks are resolved according to
+    /// [`PathContext::symlinks`].
+    pub fn resolve_file(&self, file: &Path) -> Result<String, PathContextError> {
+        let file = Path::new(&self.cwd).join(file);
+        let file = match self.symlinks {
+            SymlinkPolicy::Keep => file,
+            SymlinkPolicy::Resolve => {
+                fs::canonicalize(&file).map_err(|err| PathContextError::Io(file, err))?
+            }
+        };
+
+        file.into_os_string()
+            .into_string()
+            .map_err(|file| PathContextError::NotUtf8(file.into()))
+    }
+
+    /// What relative paths are relative to in an expression read from `file` (which should come from
+    /// [`PathContext::resolve_file`]), or in an expression not read from a file at all.
+    pub fn base_path(&self, file: Option<&str>) -> String {
+        match file {
+            // The same as the reference impl's `dirOf`
+            Some(file) => match file.rfind('/') {
+                Some(0) => "/".to_string(),
+                Some(slash) => file[..slash].to_string(),
+                None => ".".to_string(),
+            },
+            None => self.cwd.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PathContextError {
+    /// `HOME` isn't set, so it has to be given explicitly
+    NoHome,
+    /// The reference impl's JSON can't represent paths which aren't valid UTF-8
+    NotUtf8(PathBuf),
+    Io(PathBuf, io::Error),
+}
+
+impl fmt::Display for PathContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathContextError::NoHome => write!(f, "HOME isn't set"),
+            PathContextError::NotUtf8(path) => {
+                write!(f, "'{}' isn't valid UTF-8", path.display())
+            }
+            PathContextError::Io(path, err) => write!(f, "{}: {err}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for PathContextError {}
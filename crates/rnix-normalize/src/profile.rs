@@ -0,0 +1,77 @@
+//! The versions of Nix which normalization can match. Versions desugar some syntax differently, so the passes ask the
+//! profile which rules to follow.
+//!
+//! Syntax which only newer versions have isn't covered, since the pinned rnix-parser doesn't parse it. In particular,
+//! there's no profile for the experimental pipe operators (`a |> f` and `f <| a`) of Nix 2.24 and later.
+
+use std::{fmt, str::FromStr};
+
+use itertools::Itertools;
+
+/// A version of Nix, or range of versions which all desugar the same way
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum NixVersionProfile {
+    /// Nix 2.3. A call only ever takes one argument, so `f a b` is a call of a call, and `a - b` is `__sub` called with
+    /// `a`, then called with `b`. Builtins which later versions added, like `fetchTree`, aren't in scope.
+    Nix2_3,
+    /// Nix 2.4 and later, as far as rnix-parser parses them, with 2.10 being what the reference impl is built against. A
+    /// call takes all of its arguments at once. The pipe operators aren't supported (see the [module docs](self)).
+    Nix2_10,
+}
+
+impl NixVersionProfile {
+    pub const ALL: [NixVersionProfile; 2] = [NixVersionProfile::Nix2_3, NixVersionProfile::Nix2_10];
+
+    /// The name of the profile, e.g. `2.10`
+    pub fn name(self) -> &'static str {
+        match self {
+            NixVersionProfile::Nix2_3 => "2.3",
+            NixVersionProfile::Nix2_10 => "2.10",
+        }
+    }
+
+    /// Whether a call takes all of its arguments at once, rather than being one call per argument
+    pub fn has_multi_arg_calls(self) -> bool {
+        self >= NixVersionProfile::Nix2_10
+    }
+}
+
+/// The version the reference impl is built against, so that the two can be compared as they are
+impl Default for NixVersionProfile {
+    fn default() -> Self {
+        NixVersionProfile::Nix2_10
+    }
+}
+
+impl fmt::Display for NixVersionProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for NixVersionProfile {
+    type Err = UnknownNixVersion;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        NixVersionProfile::ALL
+            .into_iter()
+            .find(|profile| profile.name() == name)
+            .ok_or_else(|| UnknownNixVersion(name.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownNixVersion(pub String);
+
+impl fmt::Display for UnknownNixVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown Nix version profile '{}' (expected one of: {})",
+            self.0,
+            NixVersionProfile::ALL.iter().join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownNixVersion {}
@@ -0,0 +1,369 @@
+//! Variable resolution, modeled on the reference impl's `bindVars`, which rejects free variables while parsing.
+
+use normal_ast::{AttrName, AttrPathDef, NormalNixExpr, Var};
+use rnix_ast::ast::Span;
+
+use crate::{NixVersionProfile, NormalizeError, Result};
+
+/// The names in the reference impl's base environment, i.e., the builtins which can be referred to without going
+/// through `builtins`. This is the set for Nix 2.10, without any experimental features or unsafe settings enabled.
+/// Earlier versions lack some of them, see [`SINCE_NIX_2_4`].
+const BASE_ENV: &[&str] = &[
+    "abort",
+    "baseNameOf",
+    "break",
+    "builtins",
+    "derivation",
+    "derivationStrict",
+    "dirOf",
+    "false",
+    "fetchGit",
+    "fetchMercurial",
+    "fetchTarball",
+    "fetchTree",
+    "fromTOML",
+    "import",
+    "isNull",
+    "map",
+    "null",
+    "placeholder",
+    "removeAttrs",
+    "scopedImport",
+    "throw",
+    "toString",
+    "true",
+    "__add",
+    "__addErrorContext",
+    "__all",
+    "__any",
+    "__appendContext",
+    "__attrNames",
+    "__attrValues",
+    "__bitAnd",
+    "__bitOr",
+    "__bitXor",
+    "__catAttrs",
+    "__ceil",
+    "__compareVersions",
+    "__concatLists",
+    "__concatMap",
+    "__concatStringsSep",
+    "__currentSystem",
+    "__currentTime",
+    "__deepSeq",
+    "__div",
+    "__elem",
+    "__elemAt",
+    "__fetchurl",
+    "__filter",
+    "__filterSource",
+    "__findFile",
+    "__floor",
+    "__foldl'",
+    "__fromJSON",
+    "__functionArgs",
+    "__genList",
+    "__genericClosure",
+    "__getAttr",
+    "__getContext",
+    "__getEnv",
+    "__groupBy",
+    "__hasAttr",
+    "__hasContext",
+    "__hashFile",
+    "__hashString",
+    "__head",
+    "__intersectAttrs",
+    "__isAttrs",
+    "__isBool",
+    "__isFloat",
+    "__isFunction",
+    "__isInt",
+    "__isList",
+    "__isPath",
+    "__isString",
+    "__langVersion",
+    "__length",
+    "__lessThan",
+    "__listToAttrs",
+    "__mapAttrs",
+    "__match",
+    "__mul",
+    "__nixPath",
+    "__nixVersion",
+    "__parseDrvName",
+    "__partition",
+    "__path",
+    "__pathExists",
+    "__readDir",
+    "__readFile",
+    "__replaceStrings",
+    "__seq",
+    "__sort",
+    "__split",
+    "__splitVersion",
+    "__storeDir",
+    "__storePath",
+    "__stringLength",
+    "__sub",
+    "__substring",
+    "__tail",
+    "__toFile",
+    "__toJSON",
+    "__toPath",
+    "__toXML",
+    "__trace",
+    "__traceVerbose",
+    "__tryEval",
+    "__typeOf",
+    "__unsafeDiscardOutputDependency",
+    "__unsafeDiscardStringContext",
+    "__unsafeGetAttrPos",
+    "__zipAttrsWith",
+];
+
+/// The names in [`BASE_ENV`] which Nix 2.3 doesn't have yet
+const SINCE_NIX_2_4: &[&str] = &[
+    "break",
+    "fetchTree",
+    "__ceil",
+    "__floor",
+    "__groupBy",
+    "__traceVerbose",
+    "__zipAttrsWith",
+];
+
+/// Whether `name` is in the base environment of `profile`'s version of Nix
+fn in_base_env(name: &str, profile: NixVersionProfile) -> bool {
+    BASE_ENV.contains(&name)
+        && (profile >= NixVersionProfile::Nix2_10 || !SINCE_NIX_2_4.contains(&name))
+}
+
+/// A static environment, i.e., the variables in scope at some point, innermost first.
+enum Env<'a> {
+    Base(NixVersionProfile),
+    Vars {
+        /// Sorted, so that a variable's index here is its [`Var::displ`]
+        names: Vec<String>,
+        up: &'a Env<'a>,
+    },
+    With {
+        up: &'a Env<'a>,
+    },
+}
+
+impl<'a> Env<'a> {
+    fn vars(names: impl IntoIterator<Item = String>, up: &'a Env<'a>) -> Self {
+        let mut names: Vec<String> = names.into_iter().collect();
+        names.sort();
+        names.dedup();
+        Env::Vars { names, up }
+    }
+
+    /// Fill in what `var` refers to, returning whether it refers to anything at all. Like the reference impl, a
+    /// variable only comes from a `with` if no scope binds it lexically, no matter how the two are nested.
+    fn resolve(&self, var: &mut Var) -> bool {
+        let mut with_level = None;
+        let mut env = self;
+        let mut level = 0;
+        loop {
+            match env {
+                Env::Base(profile) => {
+                    if in_base_env(&var.name, *profile) {
+                        (var.from_with, var.level, var.displ) = (false, level, None);
+                    } else if let Some(with_level) = with_level {
+                        (var.from_with, var.level, var.displ) = (true, with_level, None);
+                    } else {
+                        return false;
+                    }
+                    return true;
+                }
+                Env::Vars { names, up } => {
+                    if let Ok(displ) = names.binary_search(&var.name) {
+                        (var.from_with, var.level, var.displ) = (false, level, Some(displ));
+                        return true;
+                    }
+                    env = up;
+                }
+                Env::With { up } => {
+                    with_level.get_or_insert(level);
+                    env = up;
+                }
+            }
+            level += 1;
+        }
+    }
+}
+
+/// Resolve every variable in `expr`, like `profile`'s version of Nix does right after parsing, rejecting any which
+/// aren't bound.
+pub(crate) fn bind_vars(expr: &mut NormalNixExpr, profile: NixVersionProfile) -> Result<()> {
+    bind_vars_in(expr, &Env::Base(profile))
+}
+
+fn bind_vars_in(expr: &mut NormalNixExpr, env: &Env) -> Result<()> {
+    match expr {
+        NormalNixExpr::Int(_)
+        | NormalNixExpr::Float(_)
+        | NormalNixExpr::String(_)
+        | NormalNixExpr::Path(_)
+        | NormalNixExpr::Pos { .. } => Ok(()),
+        NormalNixExpr::Var(var) => {
+            if env.resolve(var) {
+                Ok(())
+            } else {
+                Err(NormalizeError::UndefinedVariable {
+                    name: var.name.clone(),
+                    span: var
+                        .span
+                        .as_ref()
+                        .map(|span| Span {
+                            start: span.start,
+                            end: span.end,
+                        })
+                        .unwrap_or_default(),
+                })
+            }
+        }
+        NormalNixExpr::Select {
+            subject,
+            or_default,
+            path,
+        } => {
+            bind_vars_in(subject, env)?;
+            if let Some(or_default) = or_default {
+                bind_vars_in(or_default, env)?;
+            }
+            bind_attr_path(path, env)
+        }
+        NormalNixExpr::OpHasAttr { subject, path } => {
+            bind_vars_in(subject, env)?;
+            bind_attr_path(path, env)
+        }
+        NormalNixExpr::Attrs {
+            rec,
+            attrs,
+            dynamic_attrs,
+        } => {
+            // In a `rec` set, every attr is in scope, except in inherited attrs which refer to the outside by
+            // definition
+            let rec_env = Env::vars(attrs.iter().map(|def| def.name.clone()), env);
+            let inner_env = if *rec { &rec_env } else { env };
+
+            for def in attrs {
+                bind_vars_in(&mut def.expr, if def.inherited { env } else { inner_env })?;
+            }
+            for def in dynamic_attrs {
+                bind_vars_in(&mut def.name_expr, inner_env)?;
+                bind_vars_in(&mut def.value_expr, inner_env)?;
+            }
+            Ok(())
+        }
+        NormalNixExpr::List(items) => items
+            .iter_mut()
+            .try_for_each(|item| bind_vars_in(item, env)),
+        NormalNixExpr::Lambda { arg, formals, body } => {
+            let mut names: Vec<String> = arg.iter().cloned().collect();
+            if let Some(formals) = formals {
+                names.extend(formals.entries.keys().cloned());
+            }
+            let env = Env::vars(names, env);
+
+            if let Some(formals) = formals {
+                for formal in formals.entries.values_mut() {
+                    if let Some(default) = &mut formal.default {
+                        bind_vars_in(default, &env)?;
+                    }
+                }
+            }
+            bind_vars_in(body, &env)
+        }
+        NormalNixExpr::Call { fun, args } => {
+            bind_vars_in(fun, env)?;
+            args.iter_mut().try_for_each(|arg| bind_vars_in(arg, env))
+        }
+        NormalNixExpr::Let { attrs, body } => match &mut **attrs {
+            NormalNixExpr::Attrs { attrs, .. } => {
+                let let_env = Env::vars(attrs.iter().map(|def| def.name.clone()), env);
+
+                for def in attrs {
+                    bind_vars_in(&mut def.expr, if def.inherited { env } else { &let_env })?;
+                }
+                bind_vars_in(body, &let_env)
+            }
+            NormalNixExpr::UnmergedAttrs { defs, .. } => {
+                let let_env = Env::vars(first_keys(defs), env);
+                bind_unmerged_defs(defs, env, &let_env)?;
+                bind_vars_in(body, &let_env)
+            }
+            other => {
+                unreachable!("The attrs of a let should always be Attrs, but got: {other:?}")
+            }
+        },
+        NormalNixExpr::With { attrs, body } => {
+            bind_vars_in(attrs, env)?;
+            bind_vars_in(body, &Env::With { up: env })
+        }
+        NormalNixExpr::If { cond, then, else_ } => {
+            bind_vars_in(cond, env)?;
+            bind_vars_in(then, env)?;
+            bind_vars_in(else_, env)
+        }
+        NormalNixExpr::Assert { cond, body } => {
+            bind_vars_in(cond, env)?;
+            bind_vars_in(body, env)
+        }
+        NormalNixExpr::OpNot(expr) => bind_vars_in(expr, env),
+        NormalNixExpr::OpEq(lhs, rhs)
+        | NormalNixExpr::OpNEq(lhs, rhs)
+        | NormalNixExpr::OpAnd(lhs, rhs)
+        | NormalNixExpr::OpOr(lhs, rhs)
+        | NormalNixExpr::OpImpl(lhs, rhs)
+        | NormalNixExpr::OpUpdate(lhs, rhs)
+        | NormalNixExpr::OpConcatLists(lhs, rhs) => {
+            bind_vars_in(lhs, env)?;
+            bind_vars_in(rhs, env)
+        }
+        NormalNixExpr::OpConcatStrings { es, .. } => {
+            es.iter_mut().try_for_each(|e| bind_vars_in(e, env))
+        }
+        NormalNixExpr::Paren(expr) => bind_vars_in(expr, env),
+        NormalNixExpr::Operator { operands, .. } => operands
+            .iter_mut()
+            .try_for_each(|operand| bind_vars_in(operand, env)),
+        NormalNixExpr::UnmergedAttrs { rec, defs, .. } => {
+            let rec_env = Env::vars(first_keys(defs), env);
+            let inner_env = if *rec { &rec_env } else { env };
+            bind_unmerged_defs(defs, env, inner_env)
+        }
+    }
+}
+
+/// The names which unmerged `defs` define, i.e., the first key of each static attr path
+fn first_keys(defs: &[AttrPathDef]) -> impl Iterator<Item = String> + '_ {
+    defs.iter().filter_map(|def| match def.path.first() {
+        Some(AttrName::Symbol(name)) => Some(name.clone()),
+        _ => None,
+    })
+}
+
+/// Bind `defs` like the attrs of [`NormalNixExpr::Attrs`]: inherited ones refer to `outer_env`, and everything else is
+/// bound in `inner_env`. The implicit sets which expand-attrpaths makes up aren't `rec`, so their defs end up in the
+/// same environment as the def they came from.
+fn bind_unmerged_defs(defs: &mut [AttrPathDef], outer_env: &Env, inner_env: &Env) -> Result<()> {
+    for def in defs {
+        bind_attr_path(&mut def.path, inner_env)?;
+        bind_vars_in(
+            &mut def.expr,
+            if def.inherited { outer_env } else { inner_env },
+        )?;
+    }
+    Ok(())
+}
+
+fn bind_attr_path(path: &mut [AttrName], env: &Env) -> Result<()> {
+    path.iter_mut().try_for_each(|name| match name {
+        AttrName::Symbol(_) => Ok(()),
+        AttrName::Expr(expr) => bind_vars_in(expr, env),
+    })
+}
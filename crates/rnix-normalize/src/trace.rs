@@ -0,0 +1,141 @@
+//! An opt-in record of which lowering rule produced which node, for telling where a mismatch came from. What the passes
+//! do afterwards can be seen with [`crate::normalize_nix_expr_staged`].
+
+use std::{cell::RefCell, fmt};
+
+use rnix_ast::ast::{BinOpKind, InterpolPart, RNixExpr, Span, UnaryOpKind};
+
+/// A node of the lowered AST, along with the rule that produced it and the rnix-parser node it was produced from.
+/// Its children are the nodes produced from normalizing that node's children.
+///
+/// Nodes which a rule makes up on its own (like the `__sub` that subtraction turns into) aren't traced separately.
+#[derive(Debug)]
+pub struct Trace {
+    pub rule: &'static str,
+    pub span: Span,
+    /// The kind of node produced, e.g. `Call`
+    pub output: &'static str,
+    pub children: Vec<Trace>,
+}
+
+impl Trace {
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        writeln!(
+            f,
+            "{:indent$}{} <- {} at {}",
+            "",
+            self.output,
+            self.rule,
+            self.span,
+            indent = depth * 2
+        )?;
+        self.children
+            .iter()
+            .try_for_each(|child| child.fmt_indented(f, depth + 1))
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+/// Builds up a [`Trace`] as [`crate::Normalizer`] recurses
+pub(crate) struct Tracer {
+    /// The children traced so far at each level of recursion, innermost last
+    stack: RefCell<Vec<Vec<Trace>>>,
+}
+
+impl Tracer {
+    pub(crate) fn new() -> Self {
+        Tracer {
+            stack: RefCell::new(vec![vec![]]),
+        }
+    }
+
+    pub(crate) fn enter(&self) {
+        self.stack.borrow_mut().push(vec![]);
+    }
+
+    pub(crate) fn exit(&self, rule: &'static str, span: Span, output: &'static str) {
+        let mut stack = self.stack.borrow_mut();
+        let children = stack.pop().expect("exit without a matching enter");
+        stack
+            .last_mut()
+            .expect("exit without a matching enter")
+            .push(Trace {
+                rule,
+                span,
+                output,
+                children,
+            });
+    }
+
+    pub(crate) fn finish(self) -> Option<Trace> {
+        self.stack.into_inner().into_iter().next()?.pop()
+    }
+}
+
+/// The rule in [`crate::Normalizer`] which will lower `expr`
+pub(crate) fn rule(expr: &RNixExpr) -> &'static str {
+    match expr {
+        RNixExpr::Apply(apply) if matches!(&*apply.argument, RNixExpr::Ident(ident) if ident.inner == "or") => {
+            "apply: `or` after an atom is a variable"
+        }
+        RNixExpr::Apply(_) => "apply",
+        RNixExpr::Assert(_) => "assert",
+        RNixExpr::IfElse(_) => "if",
+        RNixExpr::Select(_) => "select",
+        RNixExpr::Str(str) => {
+            if str
+                .parts
+                .iter()
+                .any(|part| matches!(part, InterpolPart::Interpolation(_)))
+            {
+                "string: interpolation becomes a string concatenation"
+            } else {
+                "string"
+            }
+        }
+        RNixExpr::Path(path) if path.parts.len() > 1 => {
+            "path: interpolation becomes a concatenation"
+        }
+        RNixExpr::Path(_) => "path",
+        RNixExpr::Literal(_) => "literal",
+        RNixExpr::Lambda(_) => "lambda",
+        RNixExpr::LegacyLet(_) => "legacy let: select `body` from a rec attrset",
+        RNixExpr::LetIn(_) => "let",
+        RNixExpr::List(_) => "list",
+        RNixExpr::BinOp(bin_op) => match bin_op.operator {
+            BinOpKind::Concat => "binop: `++`",
+            BinOpKind::Update => "binop: `//`",
+            BinOpKind::Add => "binop: `+` becomes a string concatenation",
+            BinOpKind::Sub => "binop: `-`",
+            BinOpKind::Mul => "binop: `*`",
+            BinOpKind::Div => "binop: `/`",
+            BinOpKind::And => "binop: `&&`",
+            BinOpKind::Equal => "binop: `==`",
+            BinOpKind::Implication => "binop: `->`",
+            BinOpKind::Less => "binop: `<`",
+            BinOpKind::LessOrEq => "binop: `<=`",
+            BinOpKind::More => "binop: `>`",
+            BinOpKind::MoreOrEq => "binop: `>=`",
+            BinOpKind::NotEqual => "binop: `!=`",
+            BinOpKind::Or => "binop: `||`",
+        },
+        RNixExpr::Paren(_) => "paren",
+        RNixExpr::Root(_) => "root: discarded",
+        RNixExpr::AttrSet(_) => "attrset",
+        RNixExpr::UnaryOp(unary_op) => match unary_op.operator {
+            UnaryOpKind::Invert => "unary op: `!`",
+            UnaryOpKind::Negate => "unary op: `-`",
+        },
+        RNixExpr::Ident(ident) if ident.inner == "__curPos" => {
+            "ident: `__curPos` becomes a position"
+        }
+        RNixExpr::Ident(_) => "ident",
+        RNixExpr::With(_) => "with",
+        RNixExpr::HasAttr(_) => "has attr",
+    }
+}
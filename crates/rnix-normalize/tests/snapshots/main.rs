@@ -0,0 +1,95 @@
+//! Golden snapshots of what each of the test expressions normalizes to, as S-expressions in `golden/<name>.sexpr`.
+//! These don't need the reference impl, so they're checked even where it can't be built, e.g. with
+//! `cargo test -p rnix-normalize`.
+//!
+//! A snapshot which doesn't match, or is missing, is written next to where it belongs as `<name>.sexpr.new` for
+//! review, and the test fails. Running the tests with `SNAPSHOTS=accept` moves the reviewed `.new` files into place,
+//! and with `SNAPSHOTS=update`, every snapshot is rewritten without review.
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use normal_ast::NormalNixExpr;
+use rnix_normalize::{normalize_nix_expr, NixVersionProfile, Passes, PathContext, SymlinkPolicy};
+
+/// What the test expressions' paths are resolved against, fixed so that the snapshots are the same on every machine
+fn paths() -> PathContext {
+    PathContext {
+        cwd: "/snapshots/cwd".to_string(),
+        home: "/snapshots/home".to_string(),
+        symlinks: SymlinkPolicy::Keep,
+        search_path: vec![],
+    }
+}
+
+fn normalize(nix_expr: &str) -> NormalNixExpr {
+    let expr = rnix_ast::parse(nix_expr).unwrap_or_else(|err| panic!("{err}"));
+    normalize_nix_expr(
+        expr,
+        nix_expr,
+        None,
+        &paths(),
+        &Passes::default(),
+        NixVersionProfile::default(),
+    )
+    .unwrap_or_else(|err| panic!("{err}"))
+}
+
+fn assert_snapshot(name: &str, nix_expr: &str) {
+    let actual = normalize(nix_expr).to_sexpr();
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots/golden");
+    fs::create_dir_all(&dir).unwrap();
+    let golden = dir.join(format!("{name}.sexpr"));
+    let pending = golden.with_extension("sexpr.new");
+
+    match env::var("SNAPSHOTS").as_deref() {
+        Ok("update") => {
+            fs::write(&golden, &actual).unwrap();
+            remove_if_exists(&pending);
+            return;
+        }
+        Ok("accept") if pending.exists() => fs::rename(&pending, &golden).unwrap(),
+        _ => {}
+    }
+
+    match fs::read_to_string(&golden) {
+        Ok(expected) if expected == actual => remove_if_exists(&pending),
+        Ok(expected) => {
+            fs::write(&pending, &actual).unwrap();
+            panic!(
+                "{name} doesn't match its snapshot, see {}\n--- expected\n{expected}+++ actual\n{actual}",
+                pending.display()
+            );
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            fs::write(&pending, &actual).unwrap();
+            panic!("{name} has no snapshot, see {}", pending.display());
+        }
+        Err(err) => panic!("{}: {err}", golden.display()),
+    }
+}
+
+fn remove_if_exists(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            panic!("{}: {err}", path.display());
+        }
+    }
+}
+
+macro_rules! gen_tests {
+    ($($name:ident : $nix:expr),* $(,)?) => {
+        $(
+            paste::item! {
+                #[test]
+                fn [< snapshot_ $name >]() {
+                    assert_snapshot(stringify!($name), $nix);
+                }
+            }
+        )*
+    };
+}
+
+test_cases::with_test_cases!(gen_tests);
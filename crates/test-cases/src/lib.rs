@@ -0,0 +1,215 @@
+//! The Nix expressions which the tests are generated from. The cli's tests check that both parsers agree on each of
+//! them, and rnix-normalize's snapshot tests check what rnix-parser's side normalizes each of them to, so that a
+//! change which breaks both sides the same way still shows up.
+
+#[doc(hidden)]
+pub use indoc::indoc;
+
+/// Invoke the macro `$gen_tests` with every test case, as `name: expression` pairs
+///
+/// Many expressions are nested in a lambda to introduce dummy identifiers.
+/// This is necessary because the reference nix parser couples parsing and
+/// evaluation and will complain about undeclared identifiers at the
+/// parsing phase. As long as lambdas parse equally, then this shouldn't
+/// affect the outcome of the test.
+#[macro_export]
+macro_rules! with_test_cases {
+    ($gen_tests:ident) => {
+        $gen_tests! {
+            int: "1",
+            int_leading_zeros: "001",
+            float: "3.14",
+            float_no_whole_part: ".14",
+            float_e: "2.5e01",
+            float_e_no_whole_part: ".5e01",
+            string: r#" "hello world" "#,
+            string_interpolated: r#" "hello ${"world"} ${123}" "#,
+            string_escaped_quote: r#" "hello \"world\"" "#,
+            string_multiline: $crate::indoc!{r#"
+                ''
+                Hello world
+                ''
+            "#},
+            // FIXME: I don't think this test is working correctly. Shouldn't it fail because of https://github.com/nix-community/rnix-parser/issues/71 ?
+            string_multiline_indented: $crate::indoc!{r#"
+                ''
+                    foo
+                    bar
+                ''
+            "#},
+            // https://github.com/nix-community/rnix-parser/issues/69
+            string_multiline_nested_quotes: r#" ''"foo"'' "#,
+            // The reference impl lexes each of these as several fragments
+            string_multiline_split_tokens: r#" ''foo \$'' "#,
+            string_multiline_escaped_dollar: r#" ''foo ''$ bar'' "#,
+            string_multiline_escaped_quotes: r#" ''foo ''' bar'' "#,
+            string_multiline_escaped_newline: r#" ''foo ''\n bar'' "#,
+            string_multiline_dollars: r#" ''$$ foo'' "#,
+            string_multiline_split_tokens_interpol: r#"x: ''foo \$ ${x} ''$ bar'' "#,
+            // `+` isn't a string literal, so its operands are never merged
+            string_concat_literals: r#" "foo" + "bar" "#,
+            // https://github.com/kamadorueda/alejandra/issues/194
+            string_escaped_interpol: $crate::indoc!{r#"
+                ''
+                    foo ''\${ bar
+                ''
+            "#},
+            path_relative: "foo/bar",
+            path_relative_prefixed: "./foo/bar",
+            path_relative_parent: "./foo/..",
+            path_relative_cur: "./.",
+            path_absolute: "/foo/bar",
+            path_absolute_parent: "/foo/bar/..",
+            path_absolute_cur: "/foo/bar/.",
+            path_home: "~/foo/bar",
+            path_home_parent: "~/foo/bar/..",
+            path_home_cur: "~/foo/bar/.",
+            path_store: "<foo/bar>",
+            path_store_parent: "<foo/bar/..>",
+            path_store_cur: "<foo/bar/.>",
+            path_interpolated: r#"./${"foo"}"#,
+            select: "x: x.y",
+            select_nested: "x: x.y.z",
+            select_with_default: "x: x.y.z or 37",
+            select_string: r#" {}."foo" "#,
+            select_string_interp: r#" {}."${"foo"}" "#,
+            select_dynamic: "x: {}.${x}",
+            select_dynamic_constant_string: r#" {}.${"foo"} "#,
+            has_attr: "x: x ? y",
+            has_attr_compound: "x: x ? y.z",
+            has_attr_string: r#" {} ? "foo" "#,
+            has_attr_string_interpol: r#" {} ? "${"foo"}" "#,
+            has_attr_dynamic: "x: {} ? ${x}",
+            has_attr_dynamic_constant_string: r#" {} ? ${"foo"} "#,
+            has_attr_select_first_part_not_var: r#" {} ? ${"foo"}.y "#,
+            attrs: "{ x = 5; }",
+            attrs_multiple: "{ x = 5; y = 3.14; }",
+            attrs_nested: "{ x = { y = { z = 5; }; }; }",
+            attrs_compound_key: "{ x.y.z = 5; }",
+            attrs_rec: "rec { x = 5; y = x; }",
+            attrs_dynamic: "x: { ${x} = 5; }",
+            attrs_dynamic_constant_string: r#"{ ${"foo"} = "bar"; }"#,
+            attrs_dynamic_plain_compound: "x: { ${x}.y = 5; }",
+            attrs_dynamic_dynamic_compound: "x: { ${x}.${x} = 5; }",
+            attrs_string_key: r#"{ "hello" = "world"; }"#,
+            attrs_string_key_interpol: r#"x: { "${x}.y" = 5; }"#,
+            attrs_overlapping: r#"{ x.y = "foo"; x.z = "bar"; }"#,
+            attrs_overlapping_literal: "{ x = { y = 1; }; x.z = 2; }",
+            attrs_overlapping_literals: "{ x = { y = 1; }; x = { z = 2; }; }",
+            attrs_overlapping_rec_literal: "{ x = rec { y = 1; }; x.z = 2; }",
+            attrs_overlapping_paren_literal: "{ x = ({ y = 1; }); x.z = 2; }",
+            attrs_inherit: "x: { inherit x; }",
+            attrs_inherit_from: "x: { inherit (x) y z; }",
+            attrs_inherit_constant_strings: r#"x: y: { inherit "x" ${"y"}; }"#,
+            list: r#"[1 "2" (x: 3) 4.5]"#,
+            list_empty: "[]",
+            lambda: "x: x",
+            lambda_underscore_arg: "_:null",
+            lambda_nested: "x: y: x",
+            lambda_formals: "{ x }: x",
+            lambda_formals_default: "{ x ? null } : x",
+            lambda_formals_ellipsis: "{ x, ... }: x",
+            lambda_formals_at_left: "inp@{ x }: x",
+            lambda_formals_at_right: "{ x }@inp: x",
+            call: "f: f 0",
+            call_multiple_args: "f: f 0 1 2",
+            call_multiple_args_grouped: "f: (f 0) 1",
+            call_nested: "f: g: f 0 (g 0 1) 2",
+            let: "let x = 5; in x",
+            let_multiple: "let x = 5; y = 3.14; in x",
+            let_compound_key: "let x.y.z = 5; in x",
+            let_legacy: "let { x = 5; body = x; }",
+            with: "x: with x; y",
+            if: "if true then 0 else 1",
+            assert: "assert true; 0",
+            not: "!true",
+            eq: "0 == 1",
+            neq: "0 != 1",
+            and: "false && true",
+            and_assoc: "false && true && false",
+            or: "false || true",
+            or_assoc: "false || true || false",
+            impl: "false -> true",
+            impl_assoc: "false -> true -> false",
+            update: "{ x = 0; } // { x = 1; }",
+            update_assoc: "{ x = 0; } // { x = 1; } // { x = 2; }",
+            concat_lists: "[0] ++ [1]",
+            concat_lists_assoc: "[0] ++ [1] ++ [2]",
+            concat_strings: r#" "hello" + "world" "#,
+            concat_strings_assoc: r#" "hello" + "world" + "foo" "#,
+            plus: "0 + 1",
+            plus_assoc: "0 + 1 + 2",
+            minus: "0 - 1",
+            minus_assoc: "0 - 1 - 2",
+            times: "0 * 1",
+            times_assoc: "0 * 1 * 2",
+            divide: "0 / 1",
+            divide_assoc: "0 / 1 / 2",
+            less: "0 < 1",
+            less_eq: "0 <= 1",
+            greater: "0 > 1",
+            greater_eq: "0 >= 1",
+            negate: "-5",
+            math_prec: "(0 + 1 + -2 - 3) * -(4 / 5)",
+            import: "import ./foo.nix",
+            // `or` is a variable when it directly follows an atom, which is applied to it. Like any other variable, the
+            // reference impl wants it declared, but it can't be a lambda's parameter.
+            or_special_handling: "let or = 1; in [1 or 2]",
+            or_special_handling_apply: "let or = 1; in f: f 1 or",
+            or_special_handling_apply_more: "let or = 1; in f: f 1 or 2 or",
+            or_special_handling_operator: "let or = 1; in 1 or + 2",
+            or_as_attr_name: "let or = 1; in { inherit or; }.or",
+
+            // This is a kind of sanity check relating to how the reference impl sorts attr set keys.
+            // In particular, it maintains a global set of symbols, and attributes are sorted by when
+            // their corresponding symbols were created. However, there are a bunch of built-in symbols
+            // which are created earlier than all the others: https://github.com/NixOS/nix/blob/7e23039b7f491f8517309e0c20653d6d80c37dd7/src/libexpr/eval.cc#L426-L462
+            // So, without doing anything, `outputs` would appear _before_ `description` in the below set.
+            // However, we don't actually care about attribute order in Nix, so to make things easier, we
+            // sort lexicographically on key name in both the ref impl and rnix normalization phases.
+            // So, this test verifies that both are sorting correctly despite the ref impl's default behavior.
+            attr_set_key_sorting: r#"{ description = "foo"; outputs = "bar"; a = "a"; }"#,
+            cur_pos: "__curPos",
+            cur_pos_later_line: "[\n  1\n  __curPos\n]",
+            cur_pos_after_tab: "[\t__curPos ]",
+            cur_pos_after_multibyte: r#"[ "λ→" __curPos ]"#,
+            cur_pos_after_crlf: "[\r\n__curPos\r\n]",
+            cur_pos_after_cr: "[\r__curPos\r]",
+            cur_pos_after_multiline_string: "[ ''\n  foo\n'' __curPos ]",
+            cur_pos_after_comment: "/* a\n b */ __curPos",
+            cur_pos_in_interpolation: r#""foo ${__curPos}""#,
+            // `__curPos` is a position no matter what's in scope
+            cur_pos_shadowed: "__curPos: __curPos",
+            builtin: "builtins",
+            builtin_not_in_builtins: "toString",
+            builtin_underscored: "__typeOf",
+            with_free_var: "with {}; x",
+            with_nested_free_var: "with {}; y: x",
+            lambda_formals_refer_to_each_other: "{ x ? y, y ? x }: x",
+            lambda_formals_refer_to_arg: "{ x ? args }@args: x",
+            attrs_rec_refer_to_self: "rec { x = 1; y = x; }",
+            attrs_rec_refer_via_compound_key: "rec { x.y = z; z = 1; }",
+            attrs_rec_dynamic_refer_to_self: r#"rec { x = "y"; ${x} = x; }"#,
+            // `b` ends up in the rec set, so it can refer to `a`
+            attrs_merged_into_rec_scope: "{ x = rec { a = 1; }; x.b = a; }",
+            let_refer_to_self: "let x = 1; y = x; in y",
+            let_inherit_from_own_scope: "let x = { y = 1; }; inherit (x) y; in y",
+            let_shadowed: "let x = 1; in let x = 2; in x",
+            let_outer_scope: "let x = 1; in let y = 2; in x + y",
+            let_displacement: "let a = 1; b = 2; c = 3; in [ c a b ]",
+            let_inherit_refers_outside: "x: let inherit x; y = x; in y",
+            lambda_shadows_let: "let x = 1; in x: x",
+            lambda_formals_displacement: "{ b, a, c }@args: [ a b c args ]",
+            rec_attrs_outer_scope: "x: rec { y = x; z = y; }",
+            rec_attrs_inherit_refers_outside: "x: rec { inherit x; y = x; }",
+            non_rec_attrs_no_scope: "x: { x = 1; y = x; }",
+            // Lexical scoping wins over `with`, no matter how they're nested
+            with_loses_to_lexical: "x: with { x = 1; }; x",
+            with_nested: "with {}; with {}; x",
+            with_inside_lambda: "with {}; y: x + y",
+            with_builtin: "with { true = 1; }; true",
+            shadowed_builtin: "let true = false; in true",
+            shadowed_desugared_builtin: "let __sub = x: y: x; in 1 - 2",
+        }
+    };
+}
@@ -4,15 +4,6 @@
     let
       pkgs = import nixpkgs {
         system = "x86_64-linux";
-        overlays = [
-          (final: prev: {
-            nix = prev.nix.overrideAttrs (old: {
-              patches = (old.patches or [ ]) ++ [
-                ./crates/ref-impl-parser/reference_to_json/patch/combine-string-token.patch
-              ];
-            });
-          })
-        ];
       };
     in
     {